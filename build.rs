@@ -2,7 +2,7 @@ use std::fs::File;
 use std::io::Write;
 use std::process::Command;
 
-fn main() {
+fn main() -> Result<(), String> {
     // Get rustc version
     let output = Command::new("rustc")
         .args(&["--version"])
@@ -15,12 +15,28 @@ fn main() {
     let package: toml::value::Table = toml::from_str(cargo_toml).unwrap();
     let version = package["package"]["version"].as_str().unwrap();
 
+    // The plugins are checked against the core version, it must be a released one
+    let core_dependency = package
+        .get("dependencies")
+        .and_then(|dependencies| dependencies.get("panduza-platform-core"))
+        .ok_or("panduza-platform-core is not a dependency in Cargo.toml")?;
+    let core_version = core_dependency
+        .as_str()
+        .or(core_dependency.get("tag").and_then(|tag| tag.as_str()))
+        .or(core_dependency.get("version").and_then(|version| version.as_str()))
+        .ok_or(format!(
+            "panduza-platform-core must be given by a 'tag' or a 'version' to check the plugins, found {}",
+            core_dependency
+        ))?;
+
     // Format information for writing
     let info = format!(
         "pub static RUSTC_VERSION: &str  = \"{}\";\n
-pub static PLATFORM_VERSION: &str  =  \"{}\";\n",
+pub static PLATFORM_VERSION: &str  =  \"{}\";\n
+pub static PLATFORM_CORE_VERSION: &str  =  \"{}\";\n",
         rustc_version.trim_end_matches("\n"),
-        version
+        version,
+        core_version
     );
 
     // Write information to file
@@ -29,4 +45,5 @@ pub static PLATFORM_VERSION: &str  =  \"{}\";\n",
         .expect("Failed to write to sys_info.rs");
 
     println!("Information written to sys_info.rs");
+    Ok(())
 }
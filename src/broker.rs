@@ -0,0 +1,231 @@
+use config::{Map, Value};
+use panduza_platform_core::Error;
+use rumqttd::{Broker, Config};
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+///
+/// Address on which the broker listens, only the platform and its command gate reach it
+///
+pub static INTERNAL_BROKER_ADDR: &str = "127.0.0.1";
+
+///
+/// Maximum size of the payload of a message accepted by the broker
+///
+pub static MAX_PAYLOAD_SIZE: usize = 20480;
+
+///
+/// Maximum time for the broker to accept connections after its start
+///
+static BROKER_START_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// Period of the checks while the broker starts or stops
+///
+static BROKER_POLL_PERIOD: Duration = Duration::from_millis(50);
+
+///
+/// Number of ports tried, an other process can take the chosen port before the broker
+///
+static BROKER_START_ATTEMPTS: usize = 3;
+
+/// Embedded broker, run in a child process of the platform
+///
+/// rumqttd gives no way to stop a broker started in a thread. The broker runs in the
+/// platform executable started in broker mode, the platform stops it by killing
+/// this process.
+///
+pub struct BrokerProcess {
+    ///
+    /// Broker process
+    child: Child,
+    ///
+    /// Local port of the broker
+    port: u16,
+}
+
+impl BrokerProcess {
+    ///
+    /// Start the broker on a free local port and wait for it to accept connections
+    ///
+    /// The port is free when it is chosen but an other process can take it before the
+    /// broker, the broker then fails to listen and an other port is tried.
+    ///
+    pub async fn start(
+        enable_stdout: bool,
+        broker_log: bool,
+        debug: bool,
+        trace: bool,
+    ) -> Result<BrokerProcess, Error> {
+        let mut last_error = Error::Generic("Broker not started".to_string());
+        for _ in 0..BROKER_START_ATTEMPTS {
+            let port = std::net::TcpListener::bind((INTERNAL_BROKER_ADDR, 0))
+                .and_then(|listener| listener.local_addr())
+                .map_err(|e| Error::Generic(format!("No local port for the broker ({:?})", e)))?
+                .port();
+
+            let exe = std::env::current_exe()
+                .map_err(|e| Error::Generic(format!("Unable to find platform exe ({:?})", e)))?;
+            let mut command = Command::new(exe);
+            command
+                .arg("--broker-listen")
+                .arg(format!("{}:{}", INTERNAL_BROKER_ADDR, port))
+                .stdin(Stdio::null());
+            if !enable_stdout {
+                command.arg("--quiet-log");
+            }
+            if broker_log {
+                command.arg("--broker-log-enable");
+            }
+            if debug {
+                command.arg("--debug-log");
+            }
+            if trace {
+                command.arg("--trace-log");
+            }
+            let child = command
+                .spawn()
+                .map_err(|e| Error::Generic(format!("Unable to start the broker ({:?})", e)))?;
+
+            let mut broker = BrokerProcess {
+                child: child,
+                port: port,
+            };
+            match broker.wait_ready().await {
+                Ok(_) => return Ok(broker),
+                Err(e) => {
+                    broker.kill();
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    ///
+    /// Local port of the broker
+    ///
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    ///
+    /// Stop the broker and wait for the end of its process, at most the timeout
+    ///
+    /// Return false if the process is still running after the timeout.
+    ///
+    pub async fn stop(mut self, timeout: Duration) -> bool {
+        let _ = self.child.kill();
+        let start = Instant::now();
+        loop {
+            if !matches!(self.child.try_wait(), Ok(None)) {
+                return true;
+            }
+            if start.elapsed() > timeout {
+                return false;
+            }
+            tokio::time::sleep(BROKER_POLL_PERIOD).await;
+        }
+    }
+
+    ///
+    /// Wait for the broker to accept connections, fail if its process ends before
+    ///
+    async fn wait_ready(&mut self) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Err(Error::Generic(format!(
+                    "Broker exited during its start ({})",
+                    status
+                )));
+            }
+            if tokio::net::TcpStream::connect((INTERNAL_BROKER_ADDR, self.port))
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+            if start.elapsed() > BROKER_START_TIMEOUT {
+                return Err(Error::Generic("Broker start timeout".to_string()));
+            }
+            tokio::time::sleep(BROKER_POLL_PERIOD).await;
+        }
+    }
+
+    ///
+    ///
+    ///
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for BrokerProcess {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Broker mode entry point, executed in the child process
+///
+/// Run the broker until the process is killed, an error if it cannot listen.
+///
+pub fn run(listen_addr: String) -> Result<(), Error> {
+    //
+    // The broker only reports a listen failure in its logs, check the address first
+    drop(std::net::TcpListener::bind(&listen_addr).map_err(|e| {
+        Error::Generic(format!(
+            "Broker unable to listen on {} ({:?})",
+            listen_addr, e
+        ))
+    })?);
+
+    let mut router: HashMap<String, Value> = Map::new();
+    router.insert("id".to_string(), Value::new(None, 0));
+    router.insert("max_connections".to_string(), Value::new(None, 10010));
+    router.insert(
+        "max_outgoing_packet_count".to_string(),
+        Value::new(None, 200),
+    );
+    router.insert("max_segment_size".to_string(), Value::new(None, 104857600));
+    router.insert("max_segment_count".to_string(), Value::new(None, 10));
+
+    let mut server_connections: HashMap<String, Value> = Map::new();
+    server_connections.insert("connection_timeout_ms".to_string(), Value::new(None, 60000));
+    server_connections.insert(
+        "max_payload_size".to_string(),
+        Value::new(None, MAX_PAYLOAD_SIZE as i64),
+    );
+    server_connections.insert("max_inflight_count".to_string(), Value::new(None, 10000));
+    server_connections.insert("dynamic_filters".to_string(), Value::new(None, true));
+
+    let mut server: HashMap<String, Value> = Map::new();
+    server.insert("name".to_string(), Value::new(None, "v4-1"));
+    server.insert("listen".to_string(), Value::new(None, listen_addr.clone()));
+    server.insert("next_connection_delay_ms".to_string(), Value::new(None, 1));
+    server.insert(
+        "connections".to_string(),
+        Value::new(None, server_connections),
+    );
+
+    // see docs of config crate to know more
+    let config = config::Config::builder()
+        .set_default("id", 0)
+        .and_then(|builder| builder.set_default("router", router))
+        .and_then(|builder| builder.set_default("v4.1", server))
+        .and_then(|builder| builder.build())
+        .map_err(|e| Error::Generic(format!("Invalid broker config ({:?})", e)))?;
+
+    //
+    // this is where we deserialize it into Config
+    let rumqttd_config: Config = config
+        .try_deserialize()
+        .map_err(|e| Error::Generic(format!("Invalid broker config ({:?})", e)))?;
+    let mut broker = Broker::new(rumqttd_config);
+    broker
+        .start()
+        .map_err(|e| Error::Generic(format!("Broker stopped ({:?})", e)))
+}
@@ -0,0 +1,478 @@
+use crate::broker::MAX_PAYLOAD_SIZE;
+use crate::guardrails::{split_topic, Guardrails};
+use crate::underscore_device::pack::InfoPack;
+use crate::underscore_device::structure::instance::Alert;
+use panduza_platform_core::{log_debug, log_info, log_warn, Error, Logger, TaskResult};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+///
+/// MQTT packet types used by the gate (first 4 bits of the fixed header)
+///
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+
+/// Gate between the MQTT clients and the embedded broker
+///
+/// The broker gives no way to hold or refuse a message, so it only listens on a local
+/// port and the clients connect to the gate, on the port of the platform. The gate reads
+/// the packets of the clients and refuses the commands ('pza/<instance>/<attribute>/cmd')
+/// that break the limits of their attribute, they never reach the driver. All the commands
+/// of the instances are refused while the gate is held (emergency stop, shutdown).
+/// The other packets go through unchanged. The clients of the platform itself connect
+/// to the broker directly.
+///
+#[derive(Clone)]
+pub struct CommandGate {
+    ///
+    /// Limits of the attributes declared in the tree
+    ///
+    guardrails: Guardrails,
+
+    ///
+    /// Refused commands are raised as alerts on their instance
+    ///
+    info_pack: Arc<std::sync::Mutex<Option<InfoPack>>>,
+
+    ///
+    /// Reason to refuse all the commands of the instances, None while they are accepted
+    ///
+    hold: Arc<std::sync::Mutex<Option<String>>>,
+
+    ///
+    ///
+    ///
+    logger: Logger,
+}
+
+impl CommandGate {
+    ///
+    ///
+    ///
+    pub fn new(guardrails: Guardrails, logger: Logger) -> Self {
+        Self {
+            guardrails: guardrails,
+            info_pack: Arc::new(std::sync::Mutex::new(None)),
+            hold: Arc::new(std::sync::Mutex::new(None)),
+            logger: logger,
+        }
+    }
+
+    ///
+    /// Report the refused commands on the underscore device
+    ///
+    pub fn set_info_pack(&self, info_pack: InfoPack) {
+        *self.info_pack.lock().unwrap() = Some(info_pack);
+    }
+
+    ///
+    /// Refuse all the commands of the instances, the underscore device stays reachable
+    ///
+    pub fn hold<R: Into<String>>(&self, reason: R) {
+        *self.hold.lock().unwrap() = Some(reason.into());
+    }
+
+    ///
+    /// Accept again the commands of the instances
+    ///
+    pub fn release(&self) {
+        *self.hold.lock().unwrap() = None;
+    }
+
+    ///
+    /// Reason to refuse a message published by a client, None if it can go to the broker
+    ///
+    pub(crate) fn refusal(&self, topic: &str, payload: &[u8]) -> Option<String> {
+        let (instance, attribute) = split_topic(topic.strip_suffix("/cmd")?)?;
+        if instance != "_" {
+            if let Some(reason) = self.hold.lock().unwrap().clone() {
+                return Some(reason);
+            }
+        }
+        self.guardrails.check(instance, attribute, payload).err()
+    }
+
+    ///
+    /// Log the refused command and raise it on its instance, if the instance exists
+    ///
+    fn report(&self, topic: &str, payload: &[u8], reason: &str) {
+        let value = String::from_utf8_lossy(payload);
+        log_warn!(
+            self.logger,
+            "Command {} on '{}' refused: {}",
+            value,
+            topic,
+            reason
+        );
+        let attribute_topic = topic.strip_suffix("/cmd").unwrap_or(topic);
+        if let (Some(info_pack), Some((instance, _))) = (
+            self.info_pack.lock().unwrap().as_ref(),
+            split_topic(attribute_topic),
+        ) {
+            info_pack.raise_alert_if_known(
+                &instance.to_string(),
+                Alert::new(
+                    attribute_topic,
+                    format!("Command {} refused: {}", value, reason),
+                ),
+            );
+        }
+    }
+
+    ///
+    /// Relay the packets of a client and of the broker until one of them disconnects
+    ///
+    async fn relay(&self, client: TcpStream, broker_addr: &str) -> Result<(), Error> {
+        let broker = TcpStream::connect(broker_addr)
+            .await
+            .map_err(|e| Error::Generic(format!("Gate unable to reach the broker ({:?})", e)))?;
+        let (client_rx, client_tx) = client.into_split();
+        let (broker_rx, mut broker_tx) = broker.into_split();
+
+        //
+        // The client receives the packets of the broker and the acks of the refused commands
+        let client_tx = Arc::new(Mutex::new(client_tx));
+
+        let downstream = {
+            let client_tx = client_tx.clone();
+            async move {
+                let mut broker_rx = BufReader::new(broker_rx);
+                while let Some(packet) = read_packet(&mut broker_rx).await? {
+                    write_packet(&client_tx, &packet).await?;
+                }
+                Ok::<(), Error>(())
+            }
+        };
+
+        let upstream = async move {
+            let mut client_rx = BufReader::new(client_rx);
+            //
+            // QoS 2 commands refused by the gate, their release is answered by the gate
+            let mut refused_releases: HashSet<u16> = HashSet::new();
+            while let Some(packet) = read_packet(&mut client_rx).await? {
+                match packet_type(&packet) {
+                    PUBLISH => {
+                        if let Some(publish) = Publish::parse(&packet) {
+                            if let Some(reason) = self.refusal(&publish.topic, publish.payload) {
+                                self.report(&publish.topic, publish.payload, &reason);
+                                match (publish.qos, publish.packet_id) {
+                                    (1, Some(id)) => {
+                                        write_packet(&client_tx, &ack(PUBACK, 0, id)).await?
+                                    }
+                                    (2, Some(id)) => {
+                                        refused_releases.insert(id);
+                                        write_packet(&client_tx, &ack(PUBREC, 0, id)).await?
+                                    }
+                                    _ => {}
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    PUBREL => {
+                        if let Some(id) = packet_id(&packet) {
+                            if refused_releases.remove(&id) {
+                                write_packet(&client_tx, &ack(PUBCOMP, 0, id)).await?;
+                                continue;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                broker_tx
+                    .write_all(&packet)
+                    .await
+                    .map_err(|e| Error::Generic(format!("Gate broker write failure ({:?})", e)))?;
+            }
+            Ok::<(), Error>(())
+        };
+
+        //
+        // The first side that ends closes both connections
+        tokio::select! {
+            result = downstream => result,
+            result = upstream => result,
+        }
+    }
+}
+
+/// Accept the clients on the address of the platform and relay them to the broker
+///
+pub async fn serve(listen_addr: String, broker_addr: String, gate: CommandGate) -> TaskResult {
+    let listener = TcpListener::bind(&listen_addr).await.map_err(|e| {
+        Error::Generic(format!(
+            "Gate unable to listen on {} ({:?})",
+            listen_addr, e
+        ))
+    })?;
+    log_info!(gate.logger, "Gate listen on: {}", listen_addr);
+
+    loop {
+        let (client, peer) = listener
+            .accept()
+            .await
+            .map_err(|e| Error::Generic(format!("Gate accept failure ({:?})", e)))?;
+        let gate = gate.clone();
+        let broker_addr = broker_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gate.relay(client, &broker_addr).await {
+                log_debug!(gate.logger, "Gate connection of {} closed ({:?})", peer, e);
+            }
+        });
+    }
+}
+
+///
+/// Fields of a PUBLISH packet used by the gate
+///
+struct Publish<'a> {
+    topic: String,
+    qos: u8,
+    packet_id: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Publish<'a> {
+    ///
+    /// Decode a complete PUBLISH packet, None if it is malformed
+    ///
+    fn parse(packet: &'a [u8]) -> Option<Publish<'a>> {
+        let qos = (packet[0] >> 1) & 0x03;
+        let body = &packet[header_length(packet)?..];
+        let topic_length = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+        let topic = std::str::from_utf8(body.get(2..2 + topic_length)?).ok()?;
+        let mut rest = &body[2 + topic_length..];
+        let packet_id = match qos {
+            0 => None,
+            _ => {
+                let id = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+                rest = &rest[2..];
+                Some(id)
+            }
+        };
+        Some(Publish {
+            topic: topic.to_string(),
+            qos: qos,
+            packet_id: packet_id,
+            payload: rest,
+        })
+    }
+}
+
+///
+/// Type of a packet, from its fixed header
+///
+fn packet_type(packet: &[u8]) -> u8 {
+    packet[0] >> 4
+}
+
+///
+/// Identifier of an acknowledgement packet (PUBACK, PUBREC, PUBREL, PUBCOMP)
+///
+fn packet_id(packet: &[u8]) -> Option<u16> {
+    let body = &packet[header_length(packet)?..];
+    Some(u16::from_be_bytes([*body.first()?, *body.get(1)?]))
+}
+
+///
+/// Acknowledgement packet
+///
+fn ack(kind: u8, flags: u8, id: u16) -> Vec<u8> {
+    let id = id.to_be_bytes();
+    vec![(kind << 4) | flags, 0x02, id[0], id[1]]
+}
+
+///
+/// Size of the fixed header, type byte and remaining length
+///
+fn header_length(packet: &[u8]) -> Option<usize> {
+    let length_bytes = packet[1..]
+        .iter()
+        .take(4)
+        .position(|byte| byte & 0x80 == 0)?
+        + 1;
+    Some(1 + length_bytes)
+}
+
+///
+/// Read a complete packet, None when the other side closed the connection
+///
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let read_error = |e: std::io::Error| Error::Generic(format!("Gate read failure ({:?})", e));
+
+    let mut packet = vec![0u8];
+    match reader.read_exact(&mut packet).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(read_error(e)),
+    }
+
+    //
+    // Remaining length, 1 to 4 bytes of 7 bits
+    let mut remaining: usize = 0;
+    for shift in 0..4 {
+        let byte = reader.read_u8().await.map_err(read_error)?;
+        packet.push(byte);
+        remaining |= ((byte & 0x7F) as usize) << (7 * shift);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if shift == 3 {
+            return Err(Error::Generic(
+                "Gate read malformed packet length".to_string(),
+            ));
+        }
+    }
+
+    //
+    // The broker refuses the same packets, they must not be buffered here
+    if remaining > MAX_PAYLOAD_SIZE {
+        return Err(Error::Generic(format!(
+            "Gate read packet of {} bytes, above the maximum of {}",
+            remaining, MAX_PAYLOAD_SIZE
+        )));
+    }
+
+    let start = packet.len();
+    packet.resize(start + remaining, 0);
+    reader
+        .read_exact(&mut packet[start..])
+        .await
+        .map_err(read_error)?;
+    Ok(Some(packet))
+}
+
+///
+/// Write a complete packet, packets of several sources are never mixed
+///
+async fn write_packet(writer: &Mutex<OwnedWriteHalf>, packet: &[u8]) -> Result<(), Error> {
+    writer
+        .lock()
+        .await
+        .write_all(packet)
+        .await
+        .map_err(|e| Error::Generic(format!("Gate client write failure ({:?})", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// PUBLISH packet with a one byte remaining length
+    ///
+    fn publish(topic: &str, qos: u8, id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic.as_bytes());
+        if qos > 0 {
+            body.extend_from_slice(&id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        let mut packet = vec![(PUBLISH << 4) | (qos << 1), body.len() as u8];
+        packet.extend(body);
+        packet
+    }
+
+    #[test]
+    fn publish_packets_are_decoded() {
+        let packet = publish("pza/psu/voltage/cmd", 0, 0, b"12");
+        let p = Publish::parse(&packet).unwrap();
+        assert_eq!(p.topic, "pza/psu/voltage/cmd");
+        assert_eq!(p.qos, 0);
+        assert_eq!(p.packet_id, None);
+        assert_eq!(p.payload, b"12");
+
+        let packet = publish("pza/psu/voltage/cmd", 1, 42, b"true");
+        let p = Publish::parse(&packet).unwrap();
+        assert_eq!(p.qos, 1);
+        assert_eq!(p.packet_id, Some(42));
+        assert_eq!(p.payload, b"true");
+
+        //
+        // Topic longer than the packet
+        let mut packet = publish("pza/psu", 0, 0, b"");
+        packet[3] = 50;
+        assert!(Publish::parse(&packet).is_none());
+        //
+        // Missing identifier
+        assert!(Publish::parse(&[(PUBLISH << 4) | 0x02, 3, 0, 1, b'a']).is_none());
+    }
+
+    #[test]
+    fn header_length_follows_the_remaining_length_bytes() {
+        assert_eq!(header_length(&[0x30, 0x05]), Some(2));
+        assert_eq!(header_length(&[0x30, 0xC1, 0x02]), Some(3));
+        assert_eq!(header_length(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F]), Some(5));
+        assert_eq!(header_length(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF]), None);
+        assert_eq!(packet_id(&ack(PUBACK, 0, 258)), Some(258));
+    }
+
+    #[tokio::test]
+    async fn packets_above_the_maximum_size_are_refused_before_reading_them() {
+        let packet = publish("pza/psu/voltage/cmd", 1, 7, b"12");
+        let mut reader: &[u8] = &packet;
+        assert_eq!(
+            read_packet(&mut reader).await.unwrap(),
+            Some(packet.clone())
+        );
+        assert_eq!(read_packet(&mut reader).await.unwrap(), None);
+
+        //
+        // Only the header is sent, the length alone is refused
+        let length = MAX_PAYLOAD_SIZE + 1;
+        let header = [
+            PUBLISH << 4,
+            (length & 0x7F) as u8 | 0x80,
+            ((length >> 7) & 0x7F) as u8 | 0x80,
+            (length >> 14) as u8,
+        ];
+        let mut reader: &[u8] = &header;
+        assert!(read_packet(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn held_gate_refuses_the_commands_of_the_instances_only() {
+        let gate = CommandGate::new(Guardrails::new(), Logger::new_for_platform());
+        assert_eq!(gate.refusal("pza/psu/voltage/cmd", b"1"), None);
+
+        gate.hold("the platform is stopping");
+        assert_eq!(
+            gate.refusal("pza/psu/voltage/cmd", b"1"),
+            Some("the platform is stopping".to_string())
+        );
+        assert_eq!(gate.refusal("pza/psu/voltage/att", b"1"), None);
+        assert_eq!(gate.refusal("pza/_/devices/cmd", b"1"), None);
+
+        gate.release();
+        assert_eq!(gate.refusal("pza/psu/voltage/cmd", b"1"), None);
+    }
+
+    #[test]
+    fn commands_out_of_the_limits_are_refused() {
+        let guardrails = Guardrails::new();
+        let mut attributes = std::collections::BTreeMap::new();
+        attributes.insert(
+            "voltage".to_string(),
+            crate::device_tree::AttributeLimits {
+                max: Some(5.0),
+                ..Default::default()
+            },
+        );
+        guardrails.set_limits(std::collections::HashMap::from([(
+            "psu".to_string(),
+            attributes,
+        )]));
+
+        let gate = CommandGate::new(guardrails, Logger::new_for_platform());
+        assert_eq!(gate.refusal("pza/psu/voltage/cmd", b"4"), None);
+        assert!(gate.refusal("pza/psu/voltage/cmd", b"30").is_some());
+        assert_eq!(gate.refusal("pza/psu/voltage/att", b"30"), None);
+    }
+}
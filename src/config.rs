@@ -3,6 +3,8 @@ use panduza_platform_core::log_warn;
 use panduza_platform_core::Logger;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BrokerConfig {
@@ -15,6 +17,64 @@ pub struct ServicesConfig {
     pub enable_plbd: Option<bool>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScannerConfig {
+    /// Maximum time allowed to each scanner, in seconds
+    pub timeout: Option<u64>,
+    /// Period of the background scan, in seconds, disabled if not set
+    pub auto_scan: Option<u64>,
+}
+
+/// What to do when a plugin fails to load at boot
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginLoadPolicy {
+    /// Log the error and load the other plugins
+    Skip,
+    /// Stop the platform
+    Fail,
+}
+
+/// Producer used when several ones provide the same driver reference
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProducerPrecedence {
+    /// Built-in drivers first, then plugins in their loading order
+    PreferBuiltIn,
+    /// Plugins in their loading order, then built-in drivers
+    PreferPlugin,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PluginsConfig {
+    /// Load each plugin in its own process, a plugin crash does not stop the platform
+    pub isolation: Option<bool>,
+    /// Directories searched in addition to the system plugin directories
+    pub paths: Option<Vec<String>>,
+    /// Plugin files always loaded, whatever the allow and deny patterns
+    pub files: Option<Vec<String>>,
+    /// File name patterns ('*' and '?') of the plugins that can be loaded from the directories, all if not set
+    pub allow: Option<Vec<String>>,
+    /// File name patterns ('*' and '?') of the plugins never loaded from the directories
+    pub deny: Option<Vec<String>>,
+    /// What to do when a plugin fails to load at boot, 'skip' if not set
+    pub on_load_error: Option<PluginLoadPolicy>,
+    /// Producer used for a driver provided several times, 'prefer_built_in' if not set
+    pub precedence: Option<ProducerPrecedence>,
+    /// Driver references pinned to a plugin file (path or file name) or to 'built-in'
+    pub pins: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShutdownConfig {
+    /// Maximum time allowed to each instance to reach its safe values, in seconds
+    pub instance_timeout: Option<u64>,
+    /// Apply the 'safe_values' of the tree devices before stopping them
+    pub safe_values: Option<bool>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     // Platform info
@@ -25,6 +85,15 @@ pub struct Config {
 
     // Services info
     pub services: Option<ServicesConfig>,
+
+    // Scanner info
+    pub scanner: Option<ScannerConfig>,
+
+    // Plugins info
+    pub plugins: Option<PluginsConfig>,
+
+    // Shutdown info
+    pub shutdown: Option<ShutdownConfig>,
 }
 
 impl Default for Config {
@@ -38,10 +107,118 @@ impl Default for Config {
             services: Some(ServicesConfig {
                 enable_plbd: Some(false),
             }),
+            scanner: Some(ScannerConfig {
+                timeout: Some(10),
+                auto_scan: None,
+            }),
+            plugins: Some(PluginsConfig {
+                isolation: Some(false),
+                paths: None,
+                files: None,
+                allow: None,
+                deny: None,
+                on_load_error: Some(PluginLoadPolicy::Skip),
+                precedence: Some(ProducerPrecedence::PreferBuiltIn),
+                pins: None,
+            }),
+            shutdown: Some(ShutdownConfig {
+                instance_timeout: Some(5),
+                safe_values: Some(true),
+            }),
         }
     }
 }
 
+impl Config {
+    /// Address of the local broker
+    ///
+    pub fn broker_addr(&self) -> String {
+        self.broker
+            .as_ref()
+            .and_then(|b| b.addr.clone())
+            .unwrap_or("127.0.0.1".to_string())
+    }
+
+    /// Port of the local broker
+    ///
+    pub fn broker_port(&self) -> u16 {
+        self.broker.as_ref().and_then(|b| b.port).unwrap_or(1883)
+    }
+
+    /// Maximum time allowed to each scanner
+    ///
+    pub fn scanner_timeout(&self) -> Duration {
+        let timeout = self.scanner.as_ref().and_then(|s| s.timeout).unwrap_or(10);
+        Duration::from_secs(timeout)
+    }
+
+    /// Period of the background scan, None if disabled
+    ///
+    pub fn scanner_auto_scan(&self) -> Option<Duration> {
+        self.scanner
+            .as_ref()
+            .and_then(|s| s.auto_scan)
+            .filter(|period| *period > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// True if plugins must be loaded in their own process
+    ///
+    pub fn plugins_isolation(&self) -> bool {
+        self.plugins
+            .as_ref()
+            .and_then(|p| p.isolation)
+            .unwrap_or(false)
+    }
+
+    /// What to do when a plugin fails to load at boot
+    ///
+    pub fn plugins_load_policy(&self) -> PluginLoadPolicy {
+        self.plugins
+            .as_ref()
+            .and_then(|p| p.on_load_error)
+            .unwrap_or(PluginLoadPolicy::Skip)
+    }
+
+    /// Producer used for a driver provided several times
+    ///
+    pub fn producer_precedence(&self) -> ProducerPrecedence {
+        self.plugins
+            .as_ref()
+            .and_then(|p| p.precedence)
+            .unwrap_or(ProducerPrecedence::PreferBuiltIn)
+    }
+
+    /// Driver references pinned to a producer
+    ///
+    pub fn producer_pins(&self) -> HashMap<String, String> {
+        self.plugins
+            .as_ref()
+            .and_then(|p| p.pins.clone())
+            .unwrap_or_default()
+    }
+
+    /// Maximum time allowed to each instance to reach its safe values during the shutdown
+    ///
+    pub fn shutdown_instance_timeout(&self) -> Duration {
+        let timeout = self
+            .shutdown
+            .as_ref()
+            .and_then(|s| s.instance_timeout)
+            .unwrap_or(5);
+        Duration::from_secs(timeout)
+    }
+
+    /// True if the safe values of the tree must be applied during the shutdown
+    ///
+    pub fn shutdown_safe_values(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .and_then(|s| s.safe_values)
+            .unwrap_or(true)
+    }
+}
+
 /// Get the platform configuration from the default config file
 ///
 pub fn get_platform_config(logger: Logger) -> Config {
@@ -1,31 +1,1293 @@
+use crate::tree_vars::TreeVariables;
+use panduza_platform_core::env::system_default_device_tree_file;
+use panduza_platform_core::Error;
 use panduza_platform_core::ProductionOrder;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Default, Deserialize, Serialize, Debug)]
+#[derive(Default, Deserialize, Serialize, Debug, Clone)]
 pub struct DeviceTree {
     ///
     ///
     ///
-    pub devices: Vec<ProductionOrder>,
+    pub devices: Vec<DeviceEntry>,
+
+    ///
+    /// Files and directories read to build the tree, with the variables file
+    ///
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+///
+/// Content of a tree file before the resolution
+///
+/// {
+///     "include": ["common.json", "conf.d"],
+///     "templates": { "psu": { "dref": "manuf.model", "settings": {} } },
+///     "devices": [ { "name": "psu_1", "template": "psu", "settings": {} } ],
+///     "profiles": { "bench_a": { "include": [], "devices": [] } }
+/// }
+///
+/// Included paths are relative to the file, a directory includes all its tree files.
+/// The same structure is used in toml and yaml files.
+///
+#[derive(Default, Deserialize, Debug)]
+struct TreeFile {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    templates: HashMap<String, JsonValue>,
+    #[serde(default)]
+    devices: Vec<JsonValue>,
+    #[serde(default)]
+    profiles: HashMap<String, TreeProfile>,
+}
+
+///
+/// Devices added to the tree when the profile is selected
+///
+#[derive(Default, Deserialize, Debug)]
+struct TreeProfile {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    devices: Vec<JsonValue>,
+}
+
+///
+/// Collect the devices and templates of a tree file and of its includes
+///
+struct TreeResolver<'a> {
+    profile: Option<&'a str>,
+    profile_found: bool,
+    sources: Vec<PathBuf>,
+    templates: HashMap<String, JsonValue>,
+    devices: Vec<(PathBuf, JsonValue)>,
+    /// Canonical paths of the files and directories already read
+    loaded: HashSet<PathBuf>,
+    /// Canonical paths of the files and directories being read, to detect include cycles
+    chain: Vec<PathBuf>,
+}
+
+impl<'a> TreeResolver<'a> {
+    fn new(profile: Option<&'a str>) -> Self {
+        Self {
+            profile: profile,
+            profile_found: false,
+            sources: Vec::new(),
+            templates: HashMap::new(),
+            devices: Vec::new(),
+            loaded: HashSet::new(),
+            chain: Vec::new(),
+        }
+    }
+
+    ///
+    /// Start the reading of a file or directory, false if it has already been read
+    ///
+    /// A file or directory can be included by several files, but not by itself
+    /// through its own includes.
+    ///
+    fn enter(&mut self, path: &Path) -> Result<bool, Error> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if self.chain.contains(&canonical) {
+            return Err(Error::Generic(format!(
+                "Tree file {:?} includes itself",
+                path
+            )));
+        }
+        if !self.loaded.insert(canonical.clone()) {
+            return Ok(false);
+        }
+        self.chain.push(canonical);
+        self.sources.push(path.to_path_buf());
+        Ok(true)
+    }
+
+    ///
+    /// Read a tree file, then the files it includes
+    ///
+    fn load_file(&mut self, path: &Path) -> Result<(), Error> {
+        if !self.enter(path)? {
+            return Ok(());
+        }
+
+        let file: TreeFile = serde_json::from_value(read_tree_file(path)?).map_err(|e| {
+            Error::Generic(format!("Unable to parse tree file {:?} - ({:?})", path, e))
+        })?;
+
+        let mut includes = file.include;
+        let mut devices = file.devices;
+        if let Some(profile) = self.profile.and_then(|name| file.profiles.get(name)) {
+            self.profile_found = true;
+            includes.extend(profile.include.iter().cloned());
+            devices.extend(profile.devices.iter().cloned());
+        }
+
+        for (name, template) in file.templates {
+            if self.templates.insert(name.clone(), template).is_some() {
+                return Err(Error::Generic(format!(
+                    "Template '{}' of tree file {:?} is already defined",
+                    name, path
+                )));
+            }
+        }
+        for device in devices {
+            self.devices.push((path.to_path_buf(), device));
+        }
+
+        let base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        for include in includes {
+            self.load_include(&base.join(include))?;
+        }
+        self.chain.pop();
+        Ok(())
+    }
+
+    ///
+    /// Read an included file or all the tree files of an included directory
+    ///
+    fn load_include(&mut self, path: &Path) -> Result<(), Error> {
+        if !path.is_dir() {
+            return self.load_file(path);
+        }
+
+        if !self.enter(path)? {
+            return Ok(());
+        }
+        let entries = std::fs::read_dir(path).map_err(|e| {
+            Error::Generic(format!(
+                "Unable to read tree directory {:?} - ({:?})",
+                path, e
+            ))
+        })?;
+        let mut files: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|file| file.is_file() && TreeFormat::from_path(file).is_ok())
+            .collect();
+        files.sort();
+        for file in files {
+            self.load_file(&file)?;
+        }
+        self.chain.pop();
+        Ok(())
+    }
+
+    ///
+    /// Apply the templates and build the flat tree
+    ///
+    fn into_tree(self, variables: &TreeVariables) -> Result<DeviceTree, Error> {
+        let mut tree = DeviceTree::default();
+
+        let mut devices = Vec::new();
+        let mut undefined = BTreeSet::new();
+        for (source, device) in self.devices {
+            let mut device = match device.get("template").and_then(|t| t.as_str()) {
+                Some(name) => {
+                    let template = self.templates.get(name).ok_or(Error::Generic(format!(
+                        "Unknown template '{}' in tree file {:?}",
+                        name, source
+                    )))?;
+                    let mut merged = template.clone();
+                    merge_json(&mut merged, &device);
+                    if let Some(object) = merged.as_object_mut() {
+                        object.remove("template");
+                    }
+                    merged
+                }
+                None => device,
+            };
+            variables.substitute(&mut device, &mut undefined);
+            devices.push((source, device));
+        }
+
+        //
+        // Report all the missing variables at once
+        if !undefined.is_empty() {
+            return Err(Error::Generic(format!(
+                "Undefined variables in device tree: {} (define them in the environment or in {:?})",
+                undefined.into_iter().collect::<Vec<_>>().join(", "),
+                variables.file()
+            )));
+        }
+
+        for (source, device) in devices {
+            let mut entry: DeviceEntry = serde_json::from_value(device).map_err(|e| {
+                Error::Generic(format!(
+                    "Invalid device in tree file {:?} - ({:?})",
+                    source, e
+                ))
+            })?;
+            if let Some(other) = tree
+                .devices
+                .iter()
+                .find(|d| d.order.name == entry.order.name)
+            {
+                return Err(Error::Generic(format!(
+                    "Device '{}' is defined twice, in {:?} and {:?}",
+                    entry.order.name,
+                    other.source.clone().unwrap_or_default(),
+                    source
+                )));
+            }
+            entry.source = Some(source);
+            tree.devices.push(entry);
+        }
+
+        tree.sources = self.sources;
+        tree.sources.push(variables.file().clone());
+        Ok(tree)
+    }
+}
+
+///
+/// Override the values of 'base' with the ones of 'overrides', objects are merged key by key
+///
+fn merge_json(base: &mut JsonValue, overrides: &JsonValue) {
+    match (base, overrides) {
+        (JsonValue::Object(base), JsonValue::Object(overrides)) => {
+            for (key, value) in overrides.iter() {
+                match base.get_mut(key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+///
+/// Extensions of the tree files, in the order used to find the default tree file
+///
+static TREE_FILE_EXTENSIONS: [&str; 6] = ["json", "jsonc", "json5", "toml", "yaml", "yml"];
+
+///
+/// Format of a tree file, detected from its extension
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TreeFormat {
+    /// Json, comments and trailing commas are accepted ('.json', '.jsonc', '.json5')
+    Json,
+    /// Toml ('.toml')
+    Toml,
+    /// Yaml ('.yaml', '.yml')
+    Yaml,
+}
+
+impl TreeFormat {
+    ///
+    /// Format of the file from its extension
+    ///
+    pub fn from_path(path: &Path) -> Result<TreeFormat, Error> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") | Some("jsonc") | Some("json5") => Ok(TreeFormat::Json),
+            Some("toml") => Ok(TreeFormat::Toml),
+            Some("yaml") | Some("yml") => Ok(TreeFormat::Yaml),
+            _ => Err(Error::InvalidArgument(format!(
+                "Unsupported tree file extension {:?}, expected one of {}",
+                path,
+                TREE_FILE_EXTENSIONS.join(", ")
+            ))),
+        }
+    }
+
+    ///
+    /// Parse the content of a file
+    ///
+    pub fn parse(&self, content: &str) -> Result<JsonValue, String> {
+        match self {
+            TreeFormat::Json => json5::from_str(content).map_err(|e| e.to_string()),
+            TreeFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            TreeFormat::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    ///
+    /// Write the value in this format, comments of the original file are not kept
+    ///
+    pub fn serialize(&self, value: &JsonValue) -> Result<String, Error> {
+        let payload = match self {
+            TreeFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+            TreeFormat::Toml => strip_nulls(value, "")
+                .and_then(|value| toml::to_string_pretty(&value).map_err(|e| e.to_string())),
+            TreeFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        };
+        payload.map_err(|e| Error::SerializeFailure(format!("{:?} - ({})", self, e)))
+    }
+
+    ///
+    /// True if the content has comments, they are not kept when the file is written again
+    ///
+    /// Json comments start with '//' or '/*', toml ones with '#' and yaml ones with '#' at
+    /// the start of a line or after a space. Quoted strings are skipped, in yaml a quote
+    /// only starts a string at the start of a value.
+    ///
+    pub fn has_comments(&self, content: &str) -> bool {
+        let mut quote: Option<char> = None;
+        let mut previous = '\n';
+        let mut chars = content.chars().peekable();
+        while let Some(c) = chars.next() {
+            match quote {
+                Some(q) => {
+                    if c == '\\' && q == '"' {
+                        chars.next();
+                    } else if c == q {
+                        quote = None;
+                    }
+                }
+                None => match (self, c) {
+                    (TreeFormat::Yaml, '"') | (TreeFormat::Yaml, '\'')
+                        if !(previous.is_whitespace() || "[{,".contains(previous)) => {}
+                    (_, '"') | (_, '\'') => quote = Some(c),
+                    (TreeFormat::Json, '/') if matches!(chars.peek(), Some('/') | Some('*')) => {
+                        return true
+                    }
+                    (TreeFormat::Toml, '#') => return true,
+                    (TreeFormat::Yaml, '#') if previous.is_whitespace() => return true,
+                    _ => {}
+                },
+            }
+            previous = c;
+        }
+        false
+    }
+}
+
+///
+/// Copy of the value without the null fields of its objects, toml has no null
+///
+/// A null inside a list cannot be removed without changing the list, it is refused.
+///
+fn strip_nulls(value: &JsonValue, path: &str) -> Result<JsonValue, String> {
+    match value {
+        JsonValue::Object(map) => {
+            let mut stripped = serde_json::Map::new();
+            for (key, item) in map.iter().filter(|(_, item)| !item.is_null()) {
+                let item_path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                stripped.insert(key.clone(), strip_nulls(item, &item_path)?);
+            }
+            Ok(JsonValue::Object(stripped))
+        }
+        JsonValue::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| match item {
+                JsonValue::Null => Err(format!(
+                    "toml has no null, remove the null value of '{}[{}]'",
+                    path, index
+                )),
+                item => strip_nulls(item, &format!("{}[{}]", path, index)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(JsonValue::Array),
+        other => Ok(other.clone()),
+    }
+}
+
+///
+/// Tree file of the platform
+///
+/// The first existing file among 'tree.json', 'tree.jsonc', 'tree.json5', 'tree.toml',
+/// 'tree.yaml' and 'tree.yml' of the default location, 'tree.json' if there is none.
+///
+pub fn default_tree_file() -> Result<PathBuf, Error> {
+    let default =
+        system_default_device_tree_file().map_err(|e| Error::Generic(format!("{:?}", e)))?;
+    Ok(TREE_FILE_EXTENSIONS
+        .iter()
+        .map(|ext| default.with_extension(ext))
+        .find(|candidate| candidate.exists())
+        .unwrap_or(default))
+}
+
+///
+/// Read a tree file in any supported format
+///
+fn read_tree_file(path: &Path) -> Result<JsonValue, Error> {
+    let format = TreeFormat::from_path(path)?;
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::Generic(format!("Unable to open tree file {:?} - ({:?})", path, e)))?;
+    format
+        .parse(&content)
+        .map_err(|e| Error::Generic(format!("Unable to parse tree file {:?} - ({})", path, e)))
+}
+
+///
+/// Write a tree file in the format given by its extension
+///
+/// The content is written in a temporary file of the same directory which then replaces
+/// the tree file, a failure never leaves a truncated tree.
+///
+fn write_tree_file(path: &Path, content: &JsonValue) -> Result<(), Error> {
+    let payload = TreeFormat::from_path(path)?.serialize(content)?;
+    let file_name = path.file_name().ok_or(Error::InvalidArgument(format!(
+        "Invalid tree file {:?}",
+        path
+    )))?;
+    let temporary = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let written = File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(payload.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temporary, path));
+    written.map_err(|e| {
+        let _ = std::fs::remove_file(&temporary);
+        Error::Generic(format!("Unable to write tree file {:?} - ({:?})", path, e))
+    })
+}
+
+///
+/// Device declared in the tree
+///
+/// The production order is flattened, so a simple order is a valid entry
+///
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceEntry {
+    ///
+    /// Order to produce the device
+    ///
+    #[serde(flatten)]
+    pub order: ProductionOrder,
+
+    ///
+    /// Physical unit to bind to the instance, the settings of the order are
+    /// replaced by the ones of the scanned device that matches it
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<DeviceIdentity>,
+
+    ///
+    /// Instances that must be running before this one is produced
+    ///
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+
+    ///
+    /// How to retry a failed production, default policy if not set
+    ///
+    /// A production fails when the producer refuses the order or when the instance
+    /// falls in error before running, an unknown driver or invalid settings are not retried.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+
+    ///
+    /// Values given to attributes before the device is stopped with the platform,
+    /// the key is the attribute path in the instance ("control/oe": false)
+    ///
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub safe_values: BTreeMap<String, JsonValue>,
+
+    ///
+    /// Limits of the commands accepted by attributes, the key is the attribute path
+    /// in the instance ("control/voltage": { "min": 0, "max": 5 })
+    ///
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub limits: BTreeMap<String, AttributeLimits>,
+
+    ///
+    /// Tree file where the device is declared
+    ///
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+impl From<ProductionOrder> for DeviceEntry {
+    fn from(order: ProductionOrder) -> Self {
+        DeviceEntry {
+            order: order,
+            identity: None,
+            depends_on: Vec::new(),
+            retry: None,
+            safe_values: BTreeMap::new(),
+            limits: BTreeMap::new(),
+            source: None,
+        }
+    }
+}
+
+///
+/// Retry of a failed production, the delay is doubled after each attempt
+///
+/// { "max_retries": 5, "initial_delay_ms": 1000, "max_delay_ms": 30000 }
+///
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of attempts after the first failure
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds
+    pub initial_delay_ms: u64,
+    /// Maximum delay between two attempts, in milliseconds
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay_ms: 1000,
+            max_delay_ms: 30000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    ///
+    /// Delay before the attempt that follows 'failures' failed attempts
+    ///
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u64.saturating_pow(failures.saturating_sub(1));
+        Duration::from_millis(
+            self.initial_delay_ms
+                .saturating_mul(factor)
+                .min(self.max_delay_ms),
+        )
+    }
+}
+
+///
+/// Commands accepted by an attribute, enforced by the platform
+///
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct AttributeLimits {
+    ///
+    /// Minimum numeric value
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+
+    ///
+    /// Maximum numeric value
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+
+    ///
+    /// Only values accepted, any value if empty
+    ///
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed: Vec<JsonValue>,
+
+    ///
+    /// Maximum numeric difference between a command and the current value of the attribute
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_slew: Option<f64>,
+}
+
+impl AttributeLimits {
+    ///
+    /// Check a command, 'current' is the last value published by the attribute
+    ///
+    /// Return the reason of the rejection
+    ///
+    pub fn check(&self, value: &JsonValue, current: Option<&JsonValue>) -> Result<(), String> {
+        if !self.allowed.is_empty() && !self.allowed.contains(value) {
+            return Err(format!(
+                "{} is not one of the allowed values {}",
+                value,
+                JsonValue::Array(self.allowed.clone())
+            ));
+        }
+
+        let numeric = self.min.is_some() || self.max.is_some() || self.max_slew.is_some();
+        let number = match value.as_f64() {
+            Some(number) => number,
+            None if numeric => return Err(format!("{} is not a number", value)),
+            None => return Ok(()),
+        };
+        if let Some(min) = self.min {
+            if number < min {
+                return Err(format!("{} is below the minimum {}", value, min));
+            }
+        }
+        if let Some(max) = self.max {
+            if number > max {
+                return Err(format!("{} is above the maximum {}", value, max));
+            }
+        }
+        if let (Some(max_slew), Some(current)) = (self.max_slew, current.and_then(|c| c.as_f64())) {
+            if (number - current).abs() > max_slew {
+                return Err(format!(
+                    "{} is more than {} away from the current value {}",
+                    value, max_slew, current
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Identification of a physical unit among the scanner results
+///
+/// Each defined field must match a value with the same key in the settings
+/// of a scanned order with the same dref, the model can also match the dref.
+///
+#[derive(Default, Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceIdentity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<JsonValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vid: Option<JsonValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<JsonValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<JsonValue>,
+}
+
+impl DeviceIdentity {
+    ///
+    /// True if the scanned order is the identified unit
+    ///
+    pub fn matches(&self, scanned: &ProductionOrder) -> bool {
+        let settings = scanned.settings.clone().unwrap_or(JsonValue::Null);
+        let model_matches = |expected: &JsonValue| {
+            plain_string(expected) == scanned.dref || settings_contain(&settings, "model", expected)
+        };
+
+        self.serial_number
+            .as_ref()
+            .map_or(true, |v| settings_contain(&settings, "serial_number", v))
+            && self
+                .vid
+                .as_ref()
+                .map_or(true, |v| settings_contain(&settings, "vid", v))
+            && self
+                .pid
+                .as_ref()
+                .map_or(true, |v| settings_contain(&settings, "pid", v))
+            && self.model.as_ref().map_or(true, model_matches)
+    }
+
+    ///
+    /// Identity keys given by a scanner in the settings of its order, None if it gives none
+    ///
+    pub fn of_scanned(scanned: &ProductionOrder) -> Option<DeviceIdentity> {
+        let settings = scanned.settings.clone().unwrap_or(JsonValue::Null);
+        let identity = DeviceIdentity {
+            serial_number: find_setting(&settings, "serial_number").cloned(),
+            vid: find_setting(&settings, "vid").cloned(),
+            pid: find_setting(&settings, "pid").cloned(),
+            model: None,
+        };
+        match (&identity.serial_number, &identity.vid, &identity.pid) {
+            (None, None, None) => None,
+            _ => Some(identity),
+        }
+    }
+}
+
+///
+/// First value of this key, at any depth of the settings
+///
+fn find_setting<'a>(settings: &'a JsonValue, key: &str) -> Option<&'a JsonValue> {
+    match settings {
+        JsonValue::Object(map) => map
+            .get(key)
+            .or_else(|| map.values().find_map(|v| find_setting(v, key))),
+        JsonValue::Array(values) => values.iter().find_map(|v| find_setting(v, key)),
+        _ => None,
+    }
+}
+
+///
+/// True if a value of this key, at any depth of the settings, equals the expected one
+///
+fn settings_contain(settings: &JsonValue, key: &str, expected: &JsonValue) -> bool {
+    match settings {
+        JsonValue::Object(map) => map.iter().any(|(k, v)| {
+            (k == key && plain_string(v) == plain_string(expected))
+                || settings_contain(v, key, expected)
+        }),
+        JsonValue::Array(values) => values.iter().any(|v| settings_contain(v, key, expected)),
+        _ => false,
+    }
+}
+
+///
+/// Users write serial numbers and ids as strings or numbers, compare them as text
+///
+fn plain_string(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+impl DeviceTree {
+    ///
+    /// Load the tree from a file in any supported format
+    ///
+    /// Includes, templates, variables and the selected profile are resolved into a flat list of devices
+    ///
+    pub fn from_file(path: &Path, profile: Option<&str>) -> Result<DeviceTree, Error> {
+        let variables = TreeVariables::from_file(&TreeVariables::default_file()?)?;
+        let mut resolver = TreeResolver::new(profile);
+        resolver.load_file(path)?;
+        if let Some(profile) = profile {
+            if !resolver.profile_found {
+                return Err(Error::Generic(format!(
+                    "Profile '{}' is not defined in tree file {:?}",
+                    profile, path
+                )));
+            }
+        }
+        resolver.into_tree(&variables)
+    }
+
+    ///
+    /// Apply a modification on the devices declared in the tree file itself
+    ///
+    /// Includes, templates and profiles of the file are kept untouched. The file is written
+    /// again from its content, so a file with comments is refused instead of losing them.
+    ///
+    pub fn edit_file_devices<F>(path: &Path, edit: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Vec<JsonValue>) -> Result<(), Error>,
+    {
+        let mut content = match path.exists() {
+            true => {
+                let source = std::fs::read_to_string(path).map_err(|e| {
+                    Error::Generic(format!("Unable to open tree file {:?} - ({:?})", path, e))
+                })?;
+                if TreeFormat::from_path(path)?.has_comments(&source) {
+                    return Err(Error::InvalidArgument(format!(
+                        "Tree file {:?} has comments that would be lost, edit it by hand",
+                        path
+                    )));
+                }
+                read_tree_file(path)?
+            }
+            false => JsonValue::Object(serde_json::Map::new()),
+        };
+        let file = content.as_object_mut().ok_or(Error::Generic(format!(
+            "Tree file {:?} is not an object",
+            path
+        )))?;
+        let devices = file
+            .entry("devices")
+            .or_insert(JsonValue::Array(Vec::new()))
+            .as_array_mut()
+            .ok_or(Error::Generic(format!(
+                "Devices of tree file {:?} are not a list",
+                path
+            )))?;
+        edit(devices)?;
+
+        write_tree_file(path, &content)
+    }
+
+    ///
+    /// Write a tree file in an other format ('--convert-tree')
+    ///
+    /// The file is converted as it is, includes are not followed and comments are lost.
+    ///
+    pub fn convert_file(input: &Path, output: &Path) -> Result<(), Error> {
+        if output.exists() {
+            return Err(Error::InvalidArgument(format!(
+                "Output file {:?} already exists",
+                output
+            )));
+        }
+        write_tree_file(output, &read_tree_file(input)?)
+    }
+
+    ///
+    /// Names of the devices that depend on themselves through their dependencies
+    ///
+    pub fn dependency_cycles(&self) -> Vec<String> {
+        let depends_on = |name: &String| {
+            self.devices
+                .iter()
+                .find(|d| &d.order.name == name)
+                .map(|d| d.depends_on.clone())
+                .unwrap_or_default()
+        };
+
+        let mut in_cycle = Vec::new();
+        for entry in self.devices.iter() {
+            let mut visited: Vec<String> = Vec::new();
+            let mut to_visit = entry.depends_on.clone();
+            while let Some(name) = to_visit.pop() {
+                if name == entry.order.name {
+                    in_cycle.push(name);
+                    break;
+                }
+                if !visited.contains(&name) {
+                    to_visit.extend(depends_on(&name));
+                    visited.push(name);
+                }
+            }
+        }
+        in_cycle
+    }
+
+    ///
+    /// True if some devices must be bound to scanned units
+    ///
+    pub fn has_identities(&self) -> bool {
+        self.devices.iter().any(|d| d.identity.is_some())
+    }
+
+    ///
+    /// Build the orders to produce, entries with an identity take the settings
+    /// of the scanned order that matches them
+    ///
+    /// Return the orders and the names of the entries without matching unit
+    ///
+    pub fn resolve(&self, scanned: &[ProductionOrder]) -> (Vec<ProductionOrder>, Vec<String>) {
+        let mut orders = Vec::new();
+        let mut unresolved = Vec::new();
+
+        for entry in self.devices.iter() {
+            match entry.identity.as_ref() {
+                None => orders.push(entry.order.clone()),
+                Some(identity) => {
+                    let unit = scanned
+                        .iter()
+                        .find(|po| po.dref == entry.order.dref && identity.matches(po));
+                    match unit {
+                        Some(unit) => {
+                            let mut po = entry.order.clone();
+                            po.settings = unit.settings.clone();
+                            orders.push(po);
+                        }
+                        None => unresolved.push(entry.order.name.clone()),
+                    }
+                }
+            }
+        }
+
+        (orders, unresolved)
+    }
 }
 
-// impl DeviceTree {
-//     ///
-//     ///
-//     ///
-//     pub fn new() -> DeviceTree {
-//         Default::default()
-//     }
+///
+/// Result of the comparison between a tree and the running instances
+///
+#[derive(Default, Debug)]
+pub struct DeviceTreeDiff {
+    /// Orders that are not running yet
+    pub added: Vec<ProductionOrder>,
+    /// Orders whose running instance does not match anymore
+    pub changed: Vec<ProductionOrder>,
+    /// Names of running instances that are not in the tree anymore
+    pub removed: Vec<String>,
+}
+
+impl DeviceTreeDiff {
+    ///
+    /// Compare the orders of the tree with the orders currently produced
+    ///
+    /// Instances are identified by their name, an instance whose dref or settings
+    /// changed must be destroyed then produced again.
+    ///
+    pub fn new(
+        orders: &[ProductionOrder],
+        running: &HashMap<String, ProductionOrder>,
+    ) -> DeviceTreeDiff {
+        let mut diff = DeviceTreeDiff::default();
+
+        for po in orders.iter() {
+            match running.get(&po.name) {
+                None => diff.added.push(po.clone()),
+                Some(current) => {
+                    if !same_order(current, po) {
+                        diff.changed.push(po.clone());
+                    }
+                }
+            }
+        }
+
+        for name in running.keys() {
+            if !orders.iter().any(|po| &po.name == name) {
+                diff.removed.push(name.clone());
+            }
+        }
+
+        diff
+    }
+
+    ///
+    /// True if the tree does not require any change on running instances
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+///
+/// Orders are compared through their json representation because the dref
+/// and the settings are all that matter for the driver
+///
+fn same_order(a: &ProductionOrder, b: &ProductionOrder) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order(name: &str, settings: JsonValue) -> ProductionOrder {
+        serde_json::from_value(json!({ "name": name, "dref": "manuf.model", "settings": settings }))
+            .unwrap()
+    }
+
+    fn tree(devices: &[(&str, &[&str])]) -> DeviceTree {
+        let mut tree = DeviceTree::default();
+        for (name, depends_on) in devices {
+            let mut entry = DeviceEntry::from(order(name, json!({})));
+            entry.depends_on = depends_on.iter().map(|d| d.to_string()).collect();
+            tree.devices.push(entry);
+        }
+        tree
+    }
+
+    ///
+    /// Tree files of a test, removed at the end of the test
+    ///
+    struct TreeDir(PathBuf);
+
+    impl TreeDir {
+        fn new(test: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("pza-tree-{}-{}", test, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TreeDir(path)
+        }
+
+        fn write(&self, name: &str, content: JsonValue) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, content.to_string()).unwrap();
+            path
+        }
+
+        fn resolve(&self, main: &Path, profile: Option<&str>) -> Result<DeviceTree, Error> {
+            let mut resolver = TreeResolver::new(profile);
+            resolver.load_file(main)?;
+            resolver.into_tree(&TreeVariables::default())
+        }
+    }
+
+    impl Drop for TreeDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(tree: &DeviceTree) -> Vec<String> {
+        tree.devices.iter().map(|d| d.order.name.clone()).collect()
+    }
+
+    #[test]
+    fn diff_sorts_added_changed_and_removed_instances() {
+        let running: HashMap<String, ProductionOrder> = [
+            ("kept", order("kept", json!({ "port": "a" }))),
+            ("changed", order("changed", json!({ "port": "b" }))),
+            ("removed", order("removed", json!({}))),
+        ]
+        .into_iter()
+        .map(|(name, po)| (name.to_string(), po))
+        .collect();
+        let orders = vec![
+            order("kept", json!({ "port": "a" })),
+            order("changed", json!({ "port": "c" })),
+            order("added", json!({})),
+        ];
 
-//     // fn create_default_file(&self, filepath: &Path) -> std::io::Result<()> {
-//     //     // let config = DeviceTree::default();
-//     //     // let mut file = File::create(filepath)?;
-//     //     // let payload = serde_json::to_string_pretty(&config)?;
+        let diff = DeviceTreeDiff::new(&orders, &running);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].name, "added");
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].name, "changed");
+        assert_eq!(diff.removed, vec!["removed".to_string()]);
+        assert!(!diff.is_empty());
+    }
 
-//     //     // write!(file, "{}", &payload)?;
-//     //     // file.flush()?;
+    #[test]
+    fn diff_of_running_tree_is_empty() {
+        let orders = vec![order("psu", json!({ "port": "a" }))];
+        let running = HashMap::from([("psu".to_string(), orders[0].clone())]);
+        assert!(DeviceTreeDiff::new(&orders, &running).is_empty());
+    }
 
-//     //     Ok(())
-//     // }
-// }
+    #[test]
+    fn dependency_cycles_report_devices_of_the_cycle_only() {
+        let tree = tree(&[("a", &["b"]), ("b", &["a"]), ("c", &["a"]), ("d", &[])]);
+        let mut cycles = tree.dependency_cycles();
+        cycles.sort();
+        assert_eq!(cycles, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn dependency_cycles_include_self_dependencies() {
+        let tree = tree(&[("a", &["a"]), ("b", &["missing"])]);
+        assert_eq!(tree.dependency_cycles(), vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn resolver_merges_templates_into_devices() {
+        let dir = TreeDir::new("templates");
+        let main = dir.write(
+            "tree.json",
+            json!({
+                "templates": { "psu": { "dref": "manuf.psu", "settings": { "port": "a", "voltage": 5 } } },
+                "devices": [ { "name": "psu_1", "template": "psu", "settings": { "port": "b" } } ]
+            }),
+        );
+
+        let tree = dir.resolve(&main, None).unwrap();
+        assert_eq!(tree.devices.len(), 1);
+        let entry = &tree.devices[0];
+        assert_eq!(entry.order.dref, "manuf.psu");
+        assert_eq!(
+            entry.order.settings,
+            Some(json!({ "port": "b", "voltage": 5 }))
+        );
+        assert_eq!(entry.source.as_ref(), Some(&main));
+    }
+
+    #[test]
+    fn resolver_refuses_unknown_templates() {
+        let dir = TreeDir::new("unknown-template");
+        let main = dir.write(
+            "tree.json",
+            json!({ "devices": [ { "name": "psu_1", "template": "missing" } ] }),
+        );
+        assert!(dir.resolve(&main, None).is_err());
+    }
+
+    #[test]
+    fn resolver_follows_included_files_and_directories() {
+        let dir = TreeDir::new("includes");
+        let main = dir.write(
+            "tree.json",
+            json!({
+                "include": ["common.json", "conf.d"],
+                "devices": [ { "name": "main", "dref": "manuf.model" } ]
+            }),
+        );
+        dir.write(
+            "common.json",
+            json!({
+                "templates": { "psu": { "dref": "manuf.psu" } },
+                "devices": [ { "name": "common", "dref": "manuf.model" } ]
+            }),
+        );
+        dir.write(
+            "conf.d/b.json",
+            json!({ "devices": [ { "name": "b", "template": "psu" } ] }),
+        );
+        dir.write(
+            "conf.d/a.json",
+            json!({ "devices": [ { "name": "a", "dref": "manuf.model" } ] }),
+        );
+
+        let tree = dir.resolve(&main, None).unwrap();
+        assert_eq!(names(&tree), vec!["main", "common", "a", "b"]);
+        assert_eq!(tree.devices[3].order.dref, "manuf.psu");
+        assert!(tree.sources.contains(&dir.0.join("conf.d")));
+        assert!(tree.sources.contains(&dir.0.join("conf.d/a.json")));
+    }
+
+    #[test]
+    fn resolver_reads_files_included_by_several_files_once() {
+        let dir = TreeDir::new("include-diamond");
+        let main = dir.write(
+            "tree.json",
+            json!({ "include": ["left.json", "right.json", "./sub/../common.json"] }),
+        );
+        dir.write("left.json", json!({ "include": ["common.json"] }));
+        dir.write("right.json", json!({ "include": ["sub/../common.json"] }));
+        dir.write(
+            "common.json",
+            json!({
+                "templates": { "psu": { "dref": "manuf.psu" } },
+                "devices": [ { "name": "common", "template": "psu" } ]
+            }),
+        );
+        std::fs::create_dir_all(dir.0.join("sub")).unwrap();
+
+        let tree = dir.resolve(&main, None).unwrap();
+        assert_eq!(names(&tree), vec!["common"]);
+    }
+
+    #[test]
+    fn resolver_refuses_include_cycles() {
+        let dir = TreeDir::new("include-cycle");
+        let main = dir.write("tree.json", json!({ "include": ["a.json"] }));
+        dir.write("a.json", json!({ "include": ["./sub/../b.json"] }));
+        dir.write("b.json", json!({ "include": ["a.json"] }));
+        std::fs::create_dir_all(dir.0.join("sub")).unwrap();
+        assert!(dir.resolve(&main, None).is_err());
+
+        let main = dir.write("self.json", json!({ "include": ["./sub/../self.json"] }));
+        assert!(dir.resolve(&main, None).is_err());
+    }
+
+    #[test]
+    fn resolver_refuses_devices_defined_twice() {
+        let dir = TreeDir::new("device-twice");
+        let main = dir.write(
+            "tree.json",
+            json!({
+                "include": ["common.json"],
+                "devices": [ { "name": "psu", "dref": "manuf.model" } ]
+            }),
+        );
+        dir.write(
+            "common.json",
+            json!({ "devices": [ { "name": "psu", "dref": "manuf.model" } ] }),
+        );
+        assert!(dir.resolve(&main, None).is_err());
+    }
+
+    #[test]
+    fn resolver_adds_the_devices_of_the_selected_profile() {
+        let dir = TreeDir::new("profiles");
+        let main = dir.write(
+            "tree.json",
+            json!({
+                "devices": [ { "name": "always", "dref": "manuf.model" } ],
+                "profiles": {
+                    "bench_a": {
+                        "include": ["bench_a.json"],
+                        "devices": [ { "name": "profile", "dref": "manuf.model" } ]
+                    }
+                }
+            }),
+        );
+        dir.write(
+            "bench_a.json",
+            json!({ "devices": [ { "name": "included", "dref": "manuf.model" } ] }),
+        );
+
+        let tree = dir.resolve(&main, None).unwrap();
+        assert_eq!(names(&tree), vec!["always"]);
+
+        let tree = dir.resolve(&main, Some("bench_a")).unwrap();
+        assert_eq!(names(&tree), vec!["always", "profile", "included"]);
+
+        let mut resolver = TreeResolver::new(Some("bench_b"));
+        resolver.load_file(&main).unwrap();
+        assert!(!resolver.profile_found);
+    }
+
+    #[test]
+    fn toml_drops_null_fields_and_refuses_null_in_lists() {
+        let payload = TreeFormat::Toml
+            .serialize(&json!({ "devices": [ { "name": "psu", "settings": null } ] }))
+            .unwrap();
+        let value = TreeFormat::Toml.parse(&payload).unwrap();
+        assert_eq!(value, json!({ "devices": [ { "name": "psu" } ] }));
+
+        let error = TreeFormat::Toml
+            .serialize(
+                &json!({ "devices": [ { "name": "psu", "settings": { "ports": [1, null] } } ] }),
+            )
+            .unwrap_err();
+        assert!(format!("{:?}", error).contains("devices[0].settings.ports[1]"));
+    }
+
+    #[test]
+    fn comments_are_found_outside_strings() {
+        assert!(TreeFormat::Json.has_comments("{\n  // bench\n  \"devices\": []\n}"));
+        assert!(TreeFormat::Json.has_comments("{ /* bench */ }"));
+        assert!(!TreeFormat::Json.has_comments("{ \"url\": \"http://host\", 'a': '/*' }"));
+
+        assert!(TreeFormat::Toml.has_comments("[[devices]]\nname = \"psu\" # bench\n"));
+        assert!(!TreeFormat::Toml.has_comments("name = \"psu #1\"\nport = 'a#b'\n"));
+
+        assert!(TreeFormat::Yaml.has_comments("# bench\ndevices: []\n"));
+        assert!(TreeFormat::Yaml.has_comments("info: it's a psu # bench\n"));
+        assert!(!TreeFormat::Yaml.has_comments("name: \"psu # 1\"\nport: a#b\n"));
+    }
+
+    #[test]
+    fn edited_file_is_replaced_and_commented_file_is_refused() {
+        let dir = TreeDir::new("edit");
+        let path = dir.write(
+            "tree.json",
+            json!({ "devices": [ { "name": "psu", "dref": "manuf.model" } ] }),
+        );
+        DeviceTree::edit_file_devices(&path, |devices| {
+            devices.push(json!({ "name": "relay", "dref": "manuf.relay" }));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            names(&dir.resolve(&path, None).unwrap()),
+            vec!["psu", "relay"]
+        );
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 1);
+
+        let path = dir.0.join("tree.jsonc");
+        std::fs::write(&path, "{\n  // bench\n  \"devices\": []\n}").unwrap();
+        assert!(DeviceTree::edit_file_devices(&path, |_| Ok(())).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\n  // bench\n  \"devices\": []\n}"
+        );
+    }
+
+    #[test]
+    fn limits_check_range_and_allowed_values() {
+        let limits = AttributeLimits {
+            min: Some(0.0),
+            max: Some(5.0),
+            ..Default::default()
+        };
+        assert!(limits.check(&json!(3.3), None).is_ok());
+        assert!(limits.check(&json!(0), None).is_ok());
+        assert!(limits.check(&json!(5), None).is_ok());
+        assert!(limits.check(&json!(-0.1), None).is_err());
+        assert!(limits.check(&json!(30), None).is_err());
+        assert!(limits.check(&json!("3"), None).is_err());
+
+        let limits = AttributeLimits {
+            allowed: vec![json!("on"), json!("off")],
+            ..Default::default()
+        };
+        assert!(limits.check(&json!("on"), None).is_ok());
+        assert!(limits.check(&json!("auto"), None).is_err());
+        assert!(AttributeLimits::default()
+            .check(&json!("any"), None)
+            .is_ok());
+    }
+
+    #[test]
+    fn limits_check_slew_from_the_current_value() {
+        let limits = AttributeLimits {
+            max_slew: Some(1.0),
+            ..Default::default()
+        };
+        assert!(limits.check(&json!(4.5), Some(&json!(4))).is_ok());
+        assert!(limits.check(&json!(6), Some(&json!(4))).is_err());
+        assert!(limits.check(&json!(2), Some(&json!(4))).is_err());
+        assert!(limits.check(&json!(6), None).is_ok());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::device_tree::AttributeLimits;
+use crate::underscore_device::pack::InfoPack;
+use crate::underscore_device::structure::instance::Alert;
+use panduza_platform_core::{log_info, Error, Logger, TaskResult};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, SubscribeFilter};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+///
+/// Limits of each instance, by attribute path
+///
+pub type InstanceLimits = HashMap<String, BTreeMap<String, AttributeLimits>>;
+
+///
+/// Delay before the next poll when the connection with the broker failed
+///
+static RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+///
+/// Topic of the alert raised on the underscore instance while the values are not followed
+///
+static GUARD_ALERT_TOPIC: &str = "pza/_/guardrails";
+
+/// Limits declared in the tree, shared between the platform, the command gate and the follower
+///
+#[derive(Clone)]
+pub struct Guardrails {
+    ///
+    ///
+    ///
+    limits: Arc<std::sync::Mutex<InstanceLimits>>,
+
+    ///
+    /// When the limits changed, the follower subscribes to the new attributes
+    ///
+    change_notifier: Arc<Notify>,
+
+    ///
+    /// Last value published by each limited attribute, by attribute topic
+    ///
+    current: Arc<std::sync::Mutex<HashMap<String, JsonValue>>>,
+}
+
+impl Guardrails {
+    ///
+    ///
+    ///
+    pub fn new() -> Self {
+        Self {
+            limits: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            change_notifier: Arc::new(Notify::new()),
+            current: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    ///
+    /// Replace the limits after a load of the tree
+    ///
+    pub fn set_limits(&self, limits: InstanceLimits) {
+        *self.limits.lock().unwrap() = limits
+            .into_iter()
+            .map(|(instance, attributes)| {
+                let attributes = attributes
+                    .into_iter()
+                    .map(|(attribute, limits)| (attribute.trim_matches('/').to_string(), limits))
+                    .collect();
+                (instance, attributes)
+            })
+            .collect();
+        //
+        // The follower may be busy with a message, keep the notification for it
+        self.change_notifier.notify_one();
+    }
+
+    ///
+    /// Limits of an attribute
+    ///
+    fn get(&self, instance: &str, attribute: &str) -> Option<AttributeLimits> {
+        self.limits
+            .lock()
+            .unwrap()
+            .get(instance)
+            .and_then(|attributes| attributes.get(attribute))
+            .cloned()
+    }
+
+    ///
+    /// Check a command sent to an attribute, the reason of the refusal if it breaks the limits
+    ///
+    /// A payload that is not JSON is refused on a limited attribute, as is a command limited
+    /// by a slew while the current value is unknown.
+    ///
+    pub fn check(&self, instance: &str, attribute: &str, payload: &[u8]) -> Result<(), String> {
+        let limits = match self.get(instance, attribute) {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        let value = serde_json::from_slice::<JsonValue>(payload)
+            .map_err(|_| "the payload is not a JSON value".to_string())?;
+        let topic = attribute_topic(instance, attribute);
+        let current = self.current.lock().unwrap().get(&topic).cloned();
+        if limits.max_slew.is_some() && current.as_ref().and_then(|c| c.as_f64()).is_none() {
+            return Err("the current value is unknown, the slew can not be checked".to_string());
+        }
+        limits.check(&value, current.as_ref())
+    }
+
+    ///
+    /// Record the value published by an attribute
+    ///
+    fn set_current(&self, topic: &str, value: JsonValue) {
+        self.current
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), value);
+    }
+
+    ///
+    /// Forget the values when they are no longer followed
+    ///
+    fn forget_current(&self) {
+        self.current.lock().unwrap().clear();
+    }
+
+    ///
+    /// Topics of the limited attributes, 'pza/<instance>/<attribute>'
+    ///
+    fn topics(&self) -> HashSet<String> {
+        let limits = self.limits.lock().unwrap();
+        limits
+            .iter()
+            .flat_map(|(instance, attributes)| {
+                attributes
+                    .keys()
+                    .map(move |attribute| attribute_topic(instance, attribute))
+            })
+            .collect()
+    }
+}
+
+/// Follow the values of the limited attributes
+///
+/// The commands are checked by the command gate before they reach the broker, the gate
+/// needs the current value of the attribute for the maximum slew. This task keeps these
+/// values up to date from the 'att' topics. While it is disconnected from the broker,
+/// the values are forgotten and the gate refuses the commands limited by a slew.
+///
+pub async fn follow(
+    client_id: String,
+    addr: String,
+    port: u16,
+    guardrails: Guardrails,
+    info_pack: InfoPack,
+    logger: Logger,
+) -> TaskResult {
+    let mut options = MqttOptions::new(client_id, addr, port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut event_loop) = AsyncClient::new(options, 100);
+
+    let mut subscribed: HashSet<String> = HashSet::new();
+    //
+    // None until the first connection attempt, the alert follows the transitions only
+    let mut connected: Option<bool> = None;
+
+    loop {
+        tokio::select! {
+            _ = guardrails.change_notifier.notified() => {
+                subscribe(&client, &guardrails, &mut subscribed, false)?;
+            },
+            event = event_loop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    if connected != Some(true) {
+                        connected = Some(true);
+                        log_info!(logger, "Guardrails follow the broker");
+                        info_pack.remove_alert(&"_".to_string(), &lost_alert());
+                    }
+                    //
+                    // Subscriptions do not survive a new connection
+                    subscribe(&client, &guardrails, &mut subscribed, true)?;
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    if let (Some(topic), Ok(value)) = (
+                        p.topic.strip_suffix("/att"),
+                        serde_json::from_slice::<JsonValue>(&p.payload),
+                    ) {
+                        guardrails.set_current(topic, value);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    //
+                    // Without the values the slew limits can not be checked, make it visible
+                    guardrails.forget_current();
+                    if connected != Some(false) {
+                        connected = Some(false);
+                        logger.error(format!("Guardrails lost the broker ({:?})", e));
+                        info_pack.raise_alert(&"_".to_string(), lost_alert());
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+/// Follow the 'att' topics of the limited attributes
+///
+/// The requests are queued without waiting, the event loop is polled by the caller.
+/// A full queue fails the task, its restart subscribes again from a new connection.
+///
+fn subscribe(
+    client: &AsyncClient,
+    guardrails: &Guardrails,
+    subscribed: &mut HashSet<String>,
+    reconnected: bool,
+) -> Result<(), Error> {
+    if reconnected {
+        subscribed.clear();
+    }
+    let topics = guardrails.topics();
+    for topic in subscribed.difference(&topics) {
+        client
+            .try_unsubscribe(format!("{}/att", topic))
+            .map_err(|e| Error::Generic(format!("Guard unsubscribe failure ({:?})", e)))?;
+    }
+    let filters: Vec<SubscribeFilter> = topics
+        .difference(subscribed)
+        .map(|topic| SubscribeFilter::new(format!("{}/att", topic), QoS::AtMostOnce))
+        .collect();
+    if !filters.is_empty() {
+        client
+            .try_subscribe_many(filters)
+            .map_err(|e| Error::Generic(format!("Guard subscribe failure ({:?})", e)))?;
+    }
+    *subscribed = topics;
+    Ok(())
+}
+
+/// Alert raised on the underscore instance while the values are not followed
+///
+fn lost_alert() -> Alert {
+    Alert::new(
+        GUARD_ALERT_TOPIC,
+        "Guardrails lost the broker, commands limited by a slew are refused",
+    )
+}
+
+/// Topic of an attribute of an instance
+///
+fn attribute_topic(instance: &str, attribute: &str) -> String {
+    format!("pza/{}/{}", instance, attribute.trim_matches('/'))
+}
+
+/// Instance name and attribute path of an attribute topic
+///
+pub fn split_topic(topic: &str) -> Option<(&str, &str)> {
+    topic.strip_prefix("pza/")?.split_once('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn guardrails() -> Guardrails {
+        let guardrails = Guardrails::new();
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "/voltage/".to_string(),
+            AttributeLimits {
+                min: Some(0.0),
+                max: Some(5.0),
+                max_slew: Some(1.0),
+                ..Default::default()
+            },
+        );
+        attributes.insert(
+            "mode".to_string(),
+            AttributeLimits {
+                allowed: vec![json!("on"), json!("off")],
+                ..Default::default()
+            },
+        );
+        let mut limits = HashMap::new();
+        limits.insert("psu".to_string(), attributes);
+        guardrails.set_limits(limits);
+        guardrails
+    }
+
+    #[test]
+    fn commands_are_checked_against_the_limits_of_their_attribute() {
+        let guardrails = guardrails();
+        assert!(guardrails.check("psu", "mode", b"\"on\"").is_ok());
+        assert!(guardrails.check("psu", "mode", b"\"auto\"").is_err());
+        assert!(guardrails.check("psu", "mode", b"on").is_err());
+
+        //
+        // Attributes and instances without limits are not checked
+        assert!(guardrails.check("psu", "current", b"not json").is_ok());
+        assert!(guardrails.check("dmm", "mode", b"\"auto\"").is_ok());
+        assert_eq!(
+            guardrails.topics(),
+            HashSet::from(["pza/psu/voltage".to_string(), "pza/psu/mode".to_string()])
+        );
+    }
+
+    #[test]
+    fn slew_is_refused_until_the_current_value_is_known() {
+        let guardrails = guardrails();
+        assert!(guardrails.check("psu", "voltage", b"3").is_err());
+
+        guardrails.set_current("pza/psu/voltage", json!(3));
+        assert!(guardrails.check("psu", "voltage", b"3.5").is_ok());
+        assert!(guardrails.check("psu", "voltage", b"4.5").is_err());
+        assert!(guardrails.check("psu", "voltage", b"-0.5").is_err());
+
+        guardrails.forget_current();
+        assert!(guardrails.check("psu", "voltage", b"3.5").is_err());
+    }
+}
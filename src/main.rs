@@ -18,15 +18,25 @@
 #[cfg(feature = "built-in-drivers")]
 mod built_in;
 
+mod broker;
+mod command_gate;
 mod config;
 mod device_tree;
+mod guardrails;
 mod local_broker_discovery;
+mod mqtt_pump;
+mod notification_pipeline;
 mod platform;
+mod plugin_host;
 mod plugins_manager;
+mod retained_cleaner;
+mod safe_state;
+mod supervisor;
 mod sys_info;
+mod tree_validation;
+mod tree_vars;
 mod underscore_device;
 
-use panduza_platform_core::env::system_default_device_tree_file;
 use panduza_platform_core::env::system_default_log_dir;
 pub use platform::Platform;
 
@@ -51,6 +61,30 @@ pub struct Args {
     /// Enable trace logs
     #[arg(short, long)]
     trace_log: bool,
+
+    /// Tree profile to apply, its devices and includes are added to the common ones
+    #[arg(long, value_name = "PROFILE")]
+    profile: Option<String>,
+
+    /// Check the device tree (default one if no path given) against the drivers and exit
+    #[arg(long, value_name = "TREE_FILE")]
+    check_tree: Option<Option<std::path::PathBuf>>,
+
+    /// Convert a tree file to the format given by the extension of the output file and exit
+    #[arg(long, num_args = 2, value_names = ["INPUT", "OUTPUT"])]
+    convert_tree: Option<Vec<std::path::PathBuf>>,
+
+    /// Internal: run as the host process of this plugin file
+    #[arg(long, hide = true, requires = "plugin_host_port")]
+    plugin_host: Option<std::path::PathBuf>,
+
+    /// Internal: port of the platform to connect in host mode
+    #[arg(long, hide = true)]
+    plugin_host_port: Option<u16>,
+
+    /// Internal: run as the broker process of the platform on this address
+    #[arg(long, hide = true)]
+    broker_listen: Option<String>,
 }
 
 /// At least print arguments when the platform is started
@@ -99,7 +133,11 @@ fn print_platform_header(args: &Args) {
     );
     println!(
         "- Tree file           : {:?}",
-        system_default_device_tree_file().unwrap()
+        device_tree::default_tree_file().unwrap()
+    );
+    println!(
+        "- Tree profile        : {}",
+        args.profile.as_deref().unwrap_or("NONE")
     );
 
     println!("----------------------------------------");
@@ -111,6 +149,57 @@ async fn main() {
     // Manage args
     let args = Args::parse();
 
+    //
+    // Only report the problems of the tree
+    if let Some(tree_path) = args.check_tree.clone() {
+        std::process::exit(tree_validation::check_tree_file(
+            tree_path,
+            args.profile.as_deref(),
+        ));
+    }
+
+    //
+    // Only write the tree in an other format
+    if let Some(files) = args.convert_tree.clone() {
+        if let Err(e) = device_tree::DeviceTree::convert_file(&files[0], &files[1]) {
+            eprintln!("Unable to convert the tree: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    //
+    // Child process that hosts a single plugin for the platform
+    if let (Some(filename), Some(port)) = (args.plugin_host.clone(), args.plugin_host_port) {
+        if let Err(e) = plugin_host::run(
+            filename,
+            port,
+            !args.quiet_log,
+            args.debug_log,
+            args.trace_log,
+        ) {
+            eprintln!("Plugin host error: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    //
+    // Child process that runs the embedded broker for the platform
+    if let Some(listen_addr) = args.broker_listen.clone() {
+        panduza_platform_core::tracing::init(
+            !args.quiet_log,
+            args.broker_log_enable,
+            args.debug_log,
+            args.trace_log,
+        );
+        if let Err(e) = broker::run(listen_addr) {
+            eprintln!("Broker error: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     //
     // Give some information when the platform start
     print_platform_header(&args);
@@ -130,7 +219,13 @@ async fn main() {
     // - 1 broker
     // - 1 runtime pour les services de bases
     // - N plugins runtime
-    let mut platform = Platform::new(!args.quiet_log, args.debug_log, args.trace_log);
+    let mut platform = Platform::new(
+        !args.quiet_log,
+        args.broker_log_enable,
+        args.debug_log,
+        args.trace_log,
+        args.profile.clone(),
+    );
 
     //
     // Log minimal set of information
@@ -139,4 +234,8 @@ async fn main() {
     //
     // Platform loop
     platform.run().await;
+
+    //
+    // The blocking tasks (scans) cannot be stopped, end them with the process
+    std::process::exit(0);
 }
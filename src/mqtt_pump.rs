@@ -0,0 +1,72 @@
+use panduza_platform_core::Error;
+use rumqttc::{Event, EventLoop, Packet};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Event loop of a short lived MQTT client, polled in its own task
+///
+/// The requests of the client ('publish', 'subscribe'...) only progress while the
+/// event loop is polled, so a client that awaits many requests before reading
+/// the incoming packets needs the loop to run beside it.
+/// The task is aborted when the pump is dropped.
+///
+pub struct EventPump {
+    ///
+    /// Incoming packets, or the connection error that stopped the loop
+    ///
+    packets: UnboundedReceiver<Result<Packet, String>>,
+
+    ///
+    ///
+    ///
+    handle: JoinHandle<()>,
+}
+
+impl EventPump {
+    ///
+    /// Start polling the event loop
+    ///
+    pub fn start(mut event_loop: EventLoop) -> Self {
+        let (packet_tx, packets) = unbounded_channel();
+        let handle = tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(packet)) => {
+                        if packet_tx.send(Ok(packet)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Event::Outgoing(_)) => {}
+                    Err(e) => {
+                        let _ = packet_tx.send(Err(format!("{:?}", e)));
+                        return;
+                    }
+                }
+            }
+        });
+        Self {
+            packets: packets,
+            handle: handle,
+        }
+    }
+
+    ///
+    /// Next incoming packet, None if nothing came before the delay
+    ///
+    pub async fn next(&mut self, delay: Duration) -> Result<Option<Packet>, Error> {
+        match timeout(delay, self.packets.recv()).await {
+            Ok(Some(Ok(packet))) => Ok(Some(packet)),
+            Ok(Some(Err(e))) => Err(Error::Generic(format!("MQTT connection error ({})", e))),
+            Ok(None) => Err(Error::Generic("MQTT event loop stopped".to_string())),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl Drop for EventPump {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
@@ -0,0 +1,287 @@
+use panduza_platform_core::{Error, Notification};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{Mutex, Notify};
+
+///
+/// Notifications waiting for the underscore device, a burst above this is dropped
+///
+pub static NOTIFICATION_BUFFER_SIZE: usize = 1024;
+
+///
+/// Maximum number of notifications given to the underscore device at once
+///
+static NOTIFICATION_BATCH_SIZE: usize = 64;
+
+/// Create the pipeline between the notification sources and the underscore device
+///
+/// The plugin hosts and the local runtime push their notifications and wait while the
+/// buffer is full. The plugins loaded in the platform process can only be pulled, they
+/// are not pulled while the buffer is full and their overflow is dropped.
+///
+pub fn pipeline(capacity: usize) -> (NotificationSender, NotificationReceiver) {
+    let (tx, rx) = channel(capacity);
+    let metrics = PipelineMetrics::new(capacity);
+    (
+        NotificationSender {
+            sender: tx,
+            metrics: metrics.clone(),
+        },
+        NotificationReceiver {
+            receiver: Arc::new(Mutex::new(rx)),
+            metrics: metrics,
+        },
+    )
+}
+
+/// Counters of the pipeline, published on the underscore device
+///
+#[derive(Clone)]
+pub struct PipelineMetrics {
+    ///
+    /// When a counter changed
+    ///
+    pub change_notifier: Arc<Notify>,
+
+    ///
+    ///
+    ///
+    capacity: usize,
+
+    ///
+    /// Notifications accepted in the buffer
+    ///
+    accepted: Arc<AtomicU64>,
+
+    ///
+    /// Notifications given to the underscore device
+    ///
+    delivered: Arc<AtomicU64>,
+
+    ///
+    /// Notifications lost because the buffer was full
+    ///
+    dropped: Arc<AtomicU64>,
+}
+
+///
+/// Content of the notifications attribute
+///
+#[derive(Serialize)]
+struct PipelineStatus {
+    capacity: usize,
+    pending: u64,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl PipelineMetrics {
+    ///
+    ///
+    ///
+    fn new(capacity: usize) -> Self {
+        Self {
+            change_notifier: Arc::new(Notify::new()),
+            capacity: capacity,
+            accepted: Arc::new(AtomicU64::new(0)),
+            delivered: Arc::new(AtomicU64::new(0)),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    ///
+    /// Notifications lost since the start of the platform
+    ///
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    ///
+    ///
+    ///
+    pub fn into_json_value(&self) -> Result<JsonValue, Error> {
+        let delivered = self.delivered.load(Ordering::Relaxed);
+        let status = PipelineStatus {
+            capacity: self.capacity,
+            pending: self
+                .accepted
+                .load(Ordering::Relaxed)
+                .saturating_sub(delivered),
+            delivered: delivered,
+            dropped: self.dropped(),
+        };
+        serde_json::to_value(&status).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+}
+
+/// Side of the sources, the plugins and the local runtime
+///
+#[derive(Clone)]
+pub struct NotificationSender {
+    sender: Sender<Notification>,
+    metrics: PipelineMetrics,
+}
+
+impl NotificationSender {
+    ///
+    /// Free places in the buffer, the sources are not pulled when it is full
+    ///
+    pub fn room(&self) -> usize {
+        self.sender.capacity()
+    }
+
+    ///
+    ///
+    ///
+    pub fn metrics(&self) -> PipelineMetrics {
+        self.metrics.clone()
+    }
+
+    ///
+    /// Queue the notifications, waiting for room in the buffer (backpressure on the source)
+    ///
+    /// Return false once the underscore device is gone. It blocks the thread, only for
+    /// the threads that read the plugin hosts.
+    ///
+    pub fn blocking_send(&self, notifications: Vec<Notification>) -> bool {
+        for notification in notifications {
+            if self.sender.blocking_send(notification).is_err() {
+                return false;
+            }
+            self.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+        self.metrics.change_notifier.notify_waiters();
+        true
+    }
+
+    ///
+    /// Same as blocking_send, for the tasks of the async runtime
+    ///
+    pub async fn send(&self, notifications: Vec<Notification>) -> bool {
+        for notification in notifications {
+            if self.sender.send(notification).await.is_err() {
+                return false;
+            }
+            self.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+        self.metrics.change_notifier.notify_waiters();
+        true
+    }
+
+    ///
+    /// Queue the notifications without waiting, return the number of dropped ones
+    ///
+    pub fn push(&self, notifications: Vec<Notification>) -> usize {
+        let mut accepted = 0;
+        let mut dropped = 0;
+        for notification in notifications {
+            match self.sender.try_send(notification) {
+                Ok(_) => accepted += 1,
+                Err(_) => dropped += 1,
+            }
+        }
+        self.metrics
+            .accepted
+            .fetch_add(accepted as u64, Ordering::Relaxed);
+        self.metrics
+            .dropped
+            .fetch_add(dropped as u64, Ordering::Relaxed);
+        if accepted + dropped > 0 {
+            self.metrics.change_notifier.notify_waiters();
+        }
+        dropped
+    }
+}
+
+/// Side of the underscore device
+///
+/// It can be cloned for a restart of the task that reads it, only one reader at a time.
+///
+#[derive(Clone)]
+pub struct NotificationReceiver {
+    receiver: Arc<Mutex<Receiver<Notification>>>,
+    metrics: PipelineMetrics,
+}
+
+impl NotificationReceiver {
+    ///
+    /// Wait for the next notifications, None when all the senders are gone
+    ///
+    pub async fn next_batch(&self) -> Option<Vec<Notification>> {
+        let mut batch = Vec::new();
+        let mut receiver = self.receiver.lock().await;
+        match receiver
+            .recv_many(&mut batch, NOTIFICATION_BATCH_SIZE)
+            .await
+        {
+            0 => None,
+            count => {
+                self.metrics
+                    .delivered
+                    .fetch_add(count as u64, Ordering::Relaxed);
+                self.metrics.change_notifier.notify_waiters();
+                Some(batch)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use panduza_platform_core::instance::State;
+    use panduza_platform_core::StateNotification;
+    use std::time::Duration;
+
+    fn notification(topic: &str) -> Notification {
+        Notification::State(StateNotification {
+            topic: topic.to_string(),
+            state: State::Running,
+        })
+    }
+
+    #[tokio::test]
+    async fn burst_above_the_capacity_is_dropped_and_counted() {
+        let (sender, receiver) = pipeline(2);
+        let dropped = sender.push(vec![
+            notification("pza/a"),
+            notification("pza/b"),
+            notification("pza/c"),
+        ]);
+        assert_eq!(dropped, 1);
+        assert_eq!(sender.room(), 0);
+
+        let status = sender.metrics().into_json_value().unwrap();
+        assert_eq!(status["pending"], 2);
+        assert_eq!(status["dropped"], 1);
+
+        assert_eq!(receiver.next_batch().await.unwrap().len(), 2);
+        let status = sender.metrics().into_json_value().unwrap();
+        assert_eq!(status["pending"], 0);
+        assert_eq!(status["delivered"], 2);
+    }
+
+    #[tokio::test]
+    async fn pushing_sources_wait_for_room() {
+        let (sender, receiver) = pipeline(1);
+        assert!(sender.send(vec![notification("pza/a")]).await);
+
+        let waiting = sender.clone();
+        let send = tokio::spawn(async move { waiting.send(vec![notification("pza/b")]).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!send.is_finished());
+
+        assert_eq!(receiver.next_batch().await.unwrap().len(), 1);
+        assert!(send.await.unwrap());
+        assert_eq!(receiver.next_batch().await.unwrap().len(), 1);
+        assert_eq!(sender.metrics().dropped(), 0);
+
+        //
+        // The source stops once the underscore device is gone
+        drop(receiver);
+        assert!(!sender.send(vec![notification("pza/c")]).await);
+    }
+}
@@ -1,31 +1,50 @@
+use crate::broker::{BrokerProcess, INTERNAL_BROKER_ADDR};
 #[cfg(feature = "built-in-drivers")]
 use crate::built_in;
+use crate::command_gate::{self, CommandGate};
 
-use crate::device_tree::DeviceTree;
+use crate::config::PluginLoadPolicy;
+use crate::device_tree::{default_tree_file, DeviceTree, DeviceTreeDiff, RetryPolicy};
+use crate::guardrails::{self, Guardrails};
 use crate::local_broker_discovery;
-use crate::plugins_manager::PluginsManager;
+use crate::notification_pipeline::{self, NotificationReceiver, NotificationSender};
+use crate::plugins_manager::{
+    run_blocking, PluginHandler, PluginSelection, PluginsManager, ProducerSource,
+};
+use crate::retained_cleaner;
+use crate::safe_state;
+use crate::supervisor::{RestartPolicy, SupervisedTask, TaskStats};
+use crate::tree_validation::{self, TreeIssueKind};
+use crate::underscore_device::estop::data::{EstopCommand, EstopDriver, EstopOutcome};
 use crate::underscore_device::pack::InfoPack;
+use crate::underscore_device::plugins::data::{PluginCommand, PluginsDriver};
 use crate::underscore_device::scanner::data::ScannerDriver;
 use crate::underscore_device::store::data::SharedStore;
+use crate::underscore_device::structure::instance::{Alert, ProductionOutcome, ProductionRecord};
+use crate::underscore_device::tasks::data::TasksDriver;
+use crate::underscore_device::tree::data::TreeDriver;
 use crate::underscore_device::UnderscoreDevice;
+use futures::future::{join_all, BoxFuture};
 use futures::FutureExt;
+use panduza_platform_core::instance::State;
 use panduza_platform_core::{
-    create_task_channel, env, log_debug, log_warn, Factory, InstanceMonitor, Logger, Notification,
+    create_task_channel, env, log_debug, log_warn, Error, Factory, InstanceMonitor, Logger,
     NotificationGroup, ProductionOrder, Runtime, Store, TaskReceiver, TaskResult, TaskSender,
 };
 use panduza_platform_core::{Reactor, ReactorSettings};
-use rumqttd::Broker;
-use rumqttd::Config;
-use std::fs::File;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::signal;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::{Mutex, Notify};
-use tokio::task::JoinSet;
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, Id as TaskId, JoinSet};
+use tokio::time::MissedTickBehavior;
 
 use panduza_platform_core::log_info;
 
@@ -34,17 +53,147 @@ use panduza_platform_core::log_info;
 ///
 static REQUEST_CHANNEL_SIZE: usize = 256;
 
+///
+/// Blocking scan of a plugin or a built-in scanner, with its label
+///
+type ScanJob = (
+    String,
+    Box<dyn FnOnce() -> Result<Vec<ProductionOrder>, Error> + Send>,
+);
+
+///
+/// Period between two checks of the device tree file modification time
+///
+static TREE_WATCH_PERIOD: Duration = Duration::from_secs(2);
+
+///
+/// Period between two checks of the plugin directories
+///
+static PLUGINS_WATCH_PERIOD: Duration = Duration::from_secs(2);
+
+///
+/// Period between two dispatches of the pending productions
+///
+static PRODUCTION_DISPATCH_PERIOD: Duration = Duration::from_millis(500);
+
+///
+/// Period between two pulls of the notifications of the plugins loaded in the platform process
+///
+static NOTIFICATION_PULL_PERIOD: Duration = Duration::from_millis(100);
+
+///
+/// Period between two checks of the notifications of the local runtime while it has none
+///
+static LOCAL_NOTIFICATION_PERIOD: Duration = Duration::from_millis(10);
+
+///
+/// Period between two checks of the plugin hosts
+///
+static PLUGIN_HOSTS_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+///
+/// Platform tasks that turn user commands and watched files into requests
+///
+static REQUEST_TASKS: [&str; 10] = [
+    "device_tree_watcher",
+    "plugins_dir_watcher",
+    "auto_scanner",
+    "scanning",
+    "scanner_request_processor",
+    "scanner_instantiation_processor",
+    "plugins_request_processor",
+    "tree_request_processor",
+    "estop_request_processor",
+    "tasks_request_processor",
+];
+
+///
+/// Finished tasks kept in the task table when they will not run again
+///
+static FINISHED_TASKS_KEPT: usize = 64;
+
+///
+/// Maximum time allowed to clear the retained topics of a destroyed instance
+///
+static RETAINED_CLEAR_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// Maximum time allowed to the broker process to end at the shutdown
+///
+static BROKER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// Production of a tree device waiting for its dependencies or for its next attempt
+///
+struct PendingProduction {
+    order: ProductionOrder,
+    depends_on: Vec<String>,
+    retry: RetryPolicy,
+    /// Number of failed attempts
+    failures: u32,
+    next_attempt: Instant,
+    /// Abandoned dependency, already recorded on the instance
+    blocked_by: Option<String>,
+}
+
 pub enum ServiceRequest {
     Boot,
     ReadConfig,
     StartBroker,
     StartLocalBrokerDiscovery,
     LoadPlugins,
+    LoadPlugin(PathBuf),
+    UnloadPlugin(PathBuf),
+    ReloadPlugin(PathBuf),
     LoadDeviceTree,
+    ReloadDeviceTree,
     LoadLocalRuntime,
     LoadUnderscoreDevice,
+    /// The platform loop calls the service directly, the request is for the tasks
+    #[allow(dead_code)]
     ProduceDevice(ProductionOrder),
+    /// The platform loop calls the service directly, the request is for the tasks
+    #[allow(dead_code)]
+    DestroyDevice(String),
     StartScanning,
+    ScanFinished,
+    PluginHostRestarted(PathBuf, Result<(), String>),
+    InstantiateScanned(Vec<ProductionOrder>),
+    TriggerEstop,
+    RearmEstop,
+    AbortTask(String),
+}
+
+/// Queue a request for the main loop, waiting for room when the channel is full
+///
+/// Only for the tasks, the main loop would wait for itself.
+///
+async fn post_request(
+    request_sender: &Sender<ServiceRequest>,
+    request: ServiceRequest,
+) -> Result<(), Error> {
+    request_sender
+        .send(request)
+        .await
+        .map_err(|_| Error::Generic("Platform request channel closed".to_string()))
+}
+
+/// Alert of the instances whose plugin host crashed, removed once they are produced again
+///
+fn crashed_host_alert(name: &str) -> Alert {
+    Alert::new(
+        format!("pza/{}", name),
+        "The plugin host of this device crashed",
+    )
+}
+
+/// Alert of a problem of the tree that does not prevent the production of the device
+///
+fn tree_issue_alert(name: &str, description: &str) -> Alert {
+    Alert::new(
+        format!("pza/{}", name),
+        format!("Device tree: {}", description),
+    )
 }
 
 /// Platform
@@ -76,6 +225,12 @@ pub struct Platform {
     task_receiver: Option<TaskReceiver<TaskResult>>,
     /// Notify when a new task has been loaded
     new_task_notifier: Arc<Notify>,
+    /// Name and abort handle of each running task
+    task_handles: HashMap<TaskId, (String, AbortHandle)>,
+    /// Tasks started again when they end, by name
+    supervised_tasks: HashMap<String, SupervisedTask>,
+    /// Failures and restarts of each task, by name
+    task_stats: HashMap<String, TaskStats>,
 
     // -- Services management
     ///
@@ -103,11 +258,27 @@ pub struct Platform {
     /// Notifications that comes from devices
     /// They will help the underscore device to give informations to the user
     ///
-    notifications: Arc<Mutex<Vec<Notification>>>,
+    notifications: NotificationSender,
     ///
+    /// Read by the task that gives the notifications to the underscore device
     ///
+    notification_receiver: NotificationReceiver,
     ///
-    new_notifications_notifier: Arc<Notify>,
+    /// Last check of the plugin hosts
+    ///
+    plugin_hosts_checked: Instant,
+    ///
+    /// Instances lost with a crashed plugin host, produced again once it is restarted
+    ///
+    crashed_plugin_orders: HashMap<PathBuf, Vec<ProductionOrder>>,
+    ///
+    /// Plugin hosts being restarted by a task
+    ///
+    restarting_plugin_hosts: HashSet<PathBuf>,
+    ///
+    /// Informations shared with the underscore device
+    ///
+    info_pack: Option<InfoPack>,
 
     ///
     ///
@@ -121,18 +292,135 @@ pub struct Platform {
     ///
     scanner_driver: ScannerDriver,
 
+    ///
+    ///
+    ///
+    tree_driver: TreeDriver,
+
+    ///
+    ///
+    ///
+    plugins_driver: PluginsDriver,
+
+    ///
+    ///
+    ///
+    estop_driver: EstopDriver,
+
+    ///
+    /// Publish the task table and receive the abort requests
+    ///
+    tasks_driver: TasksDriver,
+
     local_runtime_po_sender: Option<tokio::sync::mpsc::Sender<ProductionOrder>>,
-    local_runtime_notifications: Option<Arc<std::sync::Mutex<NotificationGroup>>>,
+
+    ///
+    /// Orders of the instances currently produced, the key is the instance name
+    ///
+    produced_orders: HashMap<String, ProductionOrder>,
+    ///
+    /// Producer selected for each instance currently produced
+    ///
+    produced_sources: HashMap<String, ProducerSource>,
+    ///
+    /// Instances produced from the scanner results but not from the device tree
+    ///
+    scanned_instances: HashSet<String>,
+    ///
+    /// Instances of the tree whose identity matches no scanned unit
+    ///
+    unresolved_instances: HashSet<String>,
+    ///
+    /// Problems found by the last validation of the tree, with their instance
+    ///
+    tree_issues: HashMap<String, String>,
+    ///
+    /// Instances whose last production failed
+    ///
+    failed_instances: HashSet<String>,
+    ///
+    /// Scanners whose blocking call has not returned yet, a timeout does not stop them
+    ///
+    busy_scanners: Arc<std::sync::Mutex<HashSet<String>>>,
+    ///
+    /// Retained messages of destroyed instances being cleared, by instance name
+    ///
+    retained_cleanings: HashMap<String, tokio::task::JoinHandle<()>>,
+    ///
+    /// Productions of the tree waiting for their dependencies or retries
+    ///
+    pending_productions: Vec<PendingProduction>,
+    ///
+    /// Productions of the tree accepted by their producer, watched until the
+    /// instance runs or falls in error
+    ///
+    started_productions: HashMap<String, PendingProduction>,
+    ///
+    /// True if the tree must be resolved again after each scan
+    ///
+    tree_has_identities: bool,
+    ///
+    /// Profile of the tree selected on the command line
+    ///
+    tree_profile: Option<String>,
+    ///
+    /// Files and directories of the last loaded tree, watched for modifications
+    ///
+    tree_sources: Arc<std::sync::Mutex<Vec<PathBuf>>>,
+    ///
+    /// Safe values of the tree devices, applied when the platform stops
+    ///
+    safe_values: HashMap<String, BTreeMap<String, JsonValue>>,
+    ///
+    /// Limits of the tree devices, enforced on the commands
+    ///
+    guardrails: Guardrails,
+    ///
+    /// Names of the produced instances, in their production order
+    ///
+    production_sequence: Vec<String>,
+    ///
+    /// Process of the embedded broker
+    ///
+    broker: Option<BrokerProcess>,
+    ///
+    /// Local port of the embedded broker, the clients reach it through the command gate
+    ///
+    internal_broker_port: u16,
+    ///
+    /// Checks the commands of the clients before the broker
+    ///
+    command_gate: CommandGate,
+    ///
+    /// Log flags given to the broker process
+    ///
+    enable_stdout: bool,
+    broker_log: bool,
+    debug: bool,
+    trace: bool,
+    ///
+    /// True from the trigger of the emergency stop to its re-arm
+    ///
+    estop_engaged: bool,
 }
 
 impl Platform {
     /// Create a new instance of the Platform
     ///
-    pub fn new(enable_stdout: bool, debug: bool, trace: bool) -> Self {
+    pub fn new(
+        enable_stdout: bool,
+        broker_log: bool,
+        debug: bool,
+        trace: bool,
+        tree_profile: Option<String>,
+    ) -> Self {
         //
         // Task creation request channel
         let (main_tx, main_rx) = create_task_channel::<TaskResult>(20);
         let (rqst_tx, rqst_rx) = channel::<ServiceRequest>(REQUEST_CHANNEL_SIZE);
+        let (notif_tx, notif_rx) =
+            notification_pipeline::pipeline(notification_pipeline::NOTIFICATION_BUFFER_SIZE);
+        let guardrails = Guardrails::new();
         //
         // Create object
         return Self {
@@ -147,22 +435,57 @@ impl Platform {
             task_sender: main_tx,
             task_receiver: Some(main_rx),
             new_task_notifier: Arc::new(Notify::new()),
+            task_handles: HashMap::new(),
+            supervised_tasks: HashMap::new(),
+            task_stats: HashMap::new(),
 
             request_sender: rqst_tx.clone(),
             request_receiver: Some(rqst_rx),
 
             reactor: None,
-            plugin_manager: PluginsManager::new(enable_stdout, debug, trace),
+            plugin_manager: PluginsManager::new(notif_tx.clone(), enable_stdout, debug, trace),
 
-            notifications: Arc::new(Mutex::new(Vec::new())),
-            new_notifications_notifier: Arc::new(Notify::new()),
+            notifications: notif_tx,
+            notification_receiver: notif_rx,
+            plugin_hosts_checked: Instant::now(),
+            crashed_plugin_orders: HashMap::new(),
+            restarting_plugin_hosts: HashSet::new(),
+            info_pack: None,
 
             store: SharedStore::new(),
             built_in_store: Store::default(),
             scanner_driver: ScannerDriver::new(),
+            tree_driver: TreeDriver::new(),
+            plugins_driver: PluginsDriver::new(),
+            estop_driver: EstopDriver::new(),
+            tasks_driver: TasksDriver::new(),
 
             local_runtime_po_sender: None,
-            local_runtime_notifications: None,
+
+            produced_orders: HashMap::new(),
+            produced_sources: HashMap::new(),
+            scanned_instances: HashSet::new(),
+            unresolved_instances: HashSet::new(),
+            tree_issues: HashMap::new(),
+            failed_instances: HashSet::new(),
+            busy_scanners: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            retained_cleanings: HashMap::new(),
+            pending_productions: Vec::new(),
+            started_productions: HashMap::new(),
+            tree_has_identities: false,
+            tree_profile: tree_profile,
+            tree_sources: Arc::new(std::sync::Mutex::new(Vec::new())),
+            safe_values: HashMap::new(),
+            guardrails: guardrails.clone(),
+            production_sequence: Vec::new(),
+            broker: None,
+            internal_broker_port: 0,
+            command_gate: CommandGate::new(guardrails.clone(), Logger::new_for_platform()),
+            enable_stdout: enable_stdout,
+            broker_log: broker_log,
+            debug: debug,
+            trace: trace,
+            estop_engaged: false,
         };
     }
 
@@ -192,6 +515,13 @@ impl Platform {
         let mut task_receiver = self.task_receiver.take().unwrap();
         let mut request_receiver = self.request_receiver.take().unwrap();
 
+        //
+        // Timers are created once, other branches of the loop must not restart them
+        let mut notification_timer = tokio::time::interval(NOTIFICATION_PULL_PERIOD);
+        notification_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut dispatch_timer = tokio::time::interval(PRODUCTION_DISPATCH_PERIOD);
+        dispatch_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         //
         // Main running loop
         //
@@ -199,10 +529,12 @@ impl Platform {
             tokio::select! {
                 _ = signal::ctrl_c() => {
                     //
-                    // Exit due to user request
-                    log_warn!(self.logger, "User ctrl-c, abort requested");
+                    // Exit due to user request, the requests still queued are dropped
+                    log_warn!(self.logger, "User ctrl-c, shutdown requested");
+                    self.service_shutdown().await;
                     self.task_pool.abort_all();
                     self.must_stop.store(true, Ordering::Relaxed);
+                    self.keep_alive.store(false, Ordering::Relaxed);
                     self.new_task_notifier.notify_waiters();
                 },
                 //
@@ -214,6 +546,9 @@ impl Platform {
                             // Function to effectily spawn tasks requested by the system
                             let ah = self.task_pool.spawn(task.future);
                             log_debug!(self.logger, "New task created [{:?} => {:?}]", ah.id(), task.name);
+                            self.start_task_stats(&task.name);
+                            self.task_handles.insert(ah.id(), (task.name, ah));
+                            self.publish_tasks().await;
                             self.new_task_notifier.notify_waiters();
                         },
                         None => {
@@ -244,9 +579,21 @@ impl Platform {
                         ServiceRequest::LoadPlugins => {
                             self.service_load_plugins().await;
                         },
+                        ServiceRequest::LoadPlugin(filename) => {
+                            self.service_load_plugin(filename).await;
+                        },
+                        ServiceRequest::UnloadPlugin(filename) => {
+                            self.service_unload_plugin(filename).await;
+                        },
+                        ServiceRequest::ReloadPlugin(filename) => {
+                            self.service_reload_plugin(filename).await;
+                        },
                         ServiceRequest::LoadDeviceTree => {
                             self.service_load_device_tree().await;
                         },
+                        ServiceRequest::ReloadDeviceTree => {
+                            self.service_reload_device_tree().await;
+                        },
                         ServiceRequest::LoadLocalRuntime => {
                             self.service_load_local_runtime().await;
                         },
@@ -256,15 +603,44 @@ impl Platform {
                         ServiceRequest::ProduceDevice(order) => {
                             self.service_produce_device(order).await;
                         },
+                        ServiceRequest::DestroyDevice(name) => {
+                            let _ = self.service_destroy_device(name).await;
+                        },
                         ServiceRequest::StartScanning => {
                             self.service_start_scanning(self.scanner_driver.clone()).await;
                         },
+                        ServiceRequest::PluginHostRestarted(filename, result) => {
+                            self.service_plugin_host_restarted(filename, result).await;
+                        },
+                        ServiceRequest::ScanFinished => {
+                            if self.tree_has_identities {
+                                self.service_reload_device_tree().await;
+                            }
+                        },
+                        ServiceRequest::InstantiateScanned(orders) => {
+                            self.service_instantiate_scanned(orders).await;
+                        },
+                        ServiceRequest::TriggerEstop => {
+                            self.service_trigger_estop().await;
+                        },
+                        ServiceRequest::RearmEstop => {
+                            self.service_rearm_estop().await;
+                        },
+                        ServiceRequest::AbortTask(name) => {
+                            self.service_abort_task(name).await;
+                        },
                     }
                 },
-                _ = tokio::time::sleep(Duration::from_secs(1)) => {
+                _ = notification_timer.tick() => {
                     self.pull_notifications().await;
                 },
                 //
+                // Productions wait for their dependencies and retry delays
+                //
+                _ = dispatch_timer.tick(), if self.has_productions_to_dispatch() => {
+                    self.service_dispatch_productions().await;
+                },
+                //
                 // task to create monitor plugin manager notifications
                 //
                 continue_running = self.end_of_all_tasks() => {
@@ -286,21 +662,38 @@ impl Platform {
     async fn end_of_all_tasks(&mut self) -> bool {
         //
         // Make tasks run
-        while let Some(join_result) = self.task_pool.join_next().await {
+        while let Some(join_result) = self.task_pool.join_next_with_id().await {
             match join_result {
-                Ok(jr) => match jr {
-                    Ok(_) => {
-                        self.logger.warn("Task completed successly");
-                    }
-                    Err(e) => {
-                        self.logger.error(format!("Task end badly: {:?}", e));
-                        self.task_pool.abort_all();
+                Ok((id, jr)) => {
+                    let name = self.forget_task(&id);
+                    match jr {
+                        Ok(_) => {
+                            self.logger
+                                .warn(format!("Task {:?} completed successly", name));
+                            self.supervise_task(name, None);
+                        }
+                        Err(e) => {
+                            self.logger
+                                .error(format!("Task {:?} end badly: {:?}", name, e));
+                            self.supervise_task(name, Some(format!("{:?}", e)));
+                        }
                     }
-                },
+                }
                 Err(e) => {
-                    self.logger.error(format!("Task join_next error: {:?}", e));
+                    let name = self.forget_task(&e.id());
+                    if e.is_cancelled() {
+                        log_debug!(self.logger, "Task cancelled: {:?}", name);
+                        if let Some(stats) = self.task_stats.get_mut(&name) {
+                            stats.record_cancel();
+                        }
+                    } else {
+                        self.logger
+                            .error(format!("Task {:?} join_next error: {:?}", name, e));
+                        self.supervise_task(name, Some(format!("{:?}", e)));
+                    }
                 }
             }
+            self.publish_tasks().await;
         }
         //
         // Reaching here means that there is no task anymore
@@ -319,35 +712,245 @@ impl Platform {
         }
     }
 
+    /// Spawn a task that the platform starts again according to its policy
+    ///
+    fn spawn_supervised<F>(&mut self, name: &str, policy: RestartPolicy, factory: F)
+    where
+        F: Fn() -> BoxFuture<'static, TaskResult> + Send + 'static,
+    {
+        self.task_sender.spawn_with_name(name, factory()).unwrap();
+        self.supervised_tasks.insert(
+            name.to_string(),
+            SupervisedTask {
+                policy: policy,
+                factory: Box::new(factory),
+            },
+        );
+    }
+
+    /// Name of a task that ended, its handle is dropped
+    ///
+    fn forget_task(&mut self, id: &TaskId) -> String {
+        self.task_handles
+            .remove(id)
+            .map(|(name, _)| name)
+            .unwrap_or_default()
+    }
+
+    /// Record the start of a run of the task
+    ///
+    /// Tasks spawned through the task sender (instance tasks of the core and the
+    /// plugins) are not supervised, their policy is 'never'.
+    ///
+    fn start_task_stats(&mut self, name: &String) {
+        let policy = self
+            .supervised_tasks
+            .get(name)
+            .map_or(RestartPolicy::Never, |task| task.policy.clone());
+        self.task_stats
+            .entry(name.clone())
+            .or_default()
+            .record_start(name, &policy);
+    }
+
+    /// Publish the task table on the underscore device
+    ///
+    async fn publish_tasks(&mut self) {
+        self.prune_task_stats();
+        let tasks = self
+            .task_stats
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.clone()))
+            .collect();
+        self.tasks_driver.set_tasks(tasks).await;
+    }
+
+    /// Forget the oldest finished tasks that will not run again
+    ///
+    /// Instance tasks come and go with their instance, the table would grow without end.
+    /// The supervised tasks are kept, their restart can be pending.
+    ///
+    fn prune_task_stats(&mut self) {
+        let mut finished: Vec<(Instant, String)> = self
+            .task_stats
+            .iter()
+            .filter(|(name, _)| !self.supervised_tasks.contains_key(*name))
+            .filter_map(|(name, stats)| stats.ended.map(|ended| (ended, name.clone())))
+            .collect();
+        if finished.len() <= FINISHED_TASKS_KEPT {
+            return;
+        }
+        finished.sort();
+        for (_, name) in finished.drain(..finished.len() - FINISHED_TASKS_KEPT) {
+            self.task_stats.remove(&name);
+        }
+    }
+
+    /// Apply the policy of a task that ended, 'error' is None if it ended well
+    ///
+    /// A failing instance task does not stop the platform, the instance is put in error.
+    ///
+    fn supervise_task(&mut self, name: String, error: Option<String>) {
+        let stats = self.task_stats.entry(name.clone()).or_default();
+        stats.record_end(error.clone());
+        let consecutive_failures = stats.consecutive_failures;
+
+        //
+        // Instance tasks are named "<instance>/<task>"
+        if let (Some(error), Some((instance, _))) = (error.as_ref(), name.split_once('/')) {
+            if let Some(info_pack) = self.info_pack.as_ref() {
+                info_pack.set_instance_error(
+                    &instance.to_string(),
+                    Alert::new(
+                        format!("pza/{}", instance),
+                        format!("Task '{}' failed: {}", name, error),
+                    ),
+                );
+            }
+        }
+
+        if self.must_stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let task = match self.supervised_tasks.get(&name) {
+            Some(task) => task,
+            None => return,
+        };
+        let delay = match task
+            .policy
+            .restart_delay(error.is_some(), consecutive_failures)
+        {
+            Some(delay) => delay,
+            None => {
+                if error.is_some() && !matches!(task.policy, RestartPolicy::Never) {
+                    self.logger.error(format!(
+                        "Task {:?} abandoned after {} failure(s)",
+                        name, consecutive_failures
+                    ));
+                }
+                return;
+            }
+        };
+
+        log_warn!(self.logger, "Task {:?} restarted in {:?}", name, delay);
+        let future = (task.factory)();
+        let ah = self.task_pool.spawn(async move {
+            tokio::time::sleep(delay).await;
+            future.await
+        });
+        self.start_task_stats(&name);
+        if let Some(stats) = self.task_stats.get_mut(&name) {
+            stats.restarts += 1;
+        }
+        self.task_handles.insert(ah.id(), (name, ah));
+    }
+
     /// -------------------------------------------------------------
     ///
+    /// The plugins loaded in the platform process only give their notifications on demand,
+    /// so they are pulled periodically. While the buffer is full they are not pulled and
+    /// keep their notifications. The plugin hosts and the local runtime push theirs.
+    ///
     async fn pull_notifications(&mut self) {
-        let result = self.plugin_manager.pull_notifications();
-        match result {
-            Ok(new_notifications) => {
-                let mut n = self.notifications.lock().await;
-                n.extend(new_notifications);
-                self.new_notifications_notifier.notify_waiters();
-            }
+        if self.plugin_hosts_checked.elapsed() >= PLUGIN_HOSTS_CHECK_PERIOD {
+            self.plugin_hosts_checked = Instant::now();
+            self.restart_crashed_plugins();
+        }
+
+        //
+        // Backpressure, try again soon
+        if self.notifications.room() == 0 {
+            log_debug!(self.logger, "Notification buffer full, pull delayed");
+            return;
+        }
+
+        let mut new_notifications = Vec::new();
+        match self.plugin_manager.pull_notifications().await {
+            Ok(notifications) => new_notifications.extend(notifications),
             Err(e) => {
                 self.logger
                     .error(format!("error while pulling notifis {:?}", e));
             }
         }
 
-        //
-        // Local notifications
-        let local_notifs = self
-            .local_runtime_notifications
-            .as_mut()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .pull();
-        if !local_notifs.is_empty() {
-            let mut n = self.notifications.lock().await;
-            n.extend(local_notifs);
-            self.new_notifications_notifier.notify_waiters();
+        let dropped = self.notifications.push(new_notifications);
+        if dropped > 0 {
+            log_warn!(
+                self.logger,
+                "{} notification(s) dropped, buffer full ({} since start)",
+                dropped,
+                self.notifications.metrics().dropped()
+            );
+        }
+    }
+
+    /// Restart the host processes of the crashed plugins
+    ///
+    /// Instances of the plugin are put in error and forgotten, they are produced again
+    /// once a task restarted the host, the loop keeps serving meanwhile.
+    ///
+    fn restart_crashed_plugins(&mut self) {
+        for ph in self.plugin_manager.crashed_handlers() {
+            let filename = ph.filename().clone();
+            if self.restarting_plugin_hosts.contains(&filename) {
+                continue;
+            }
+
+            //
+            // The instances died with the host, a failed restart is tried again at the next check
+            if !self.crashed_plugin_orders.contains_key(&filename) {
+                self.logger
+                    .error(format!("Plugin host crashed: {:?}", filename));
+                let orders = self.plugin_orders(&ph);
+                for po in orders.iter() {
+                    self.produced_orders.remove(&po.name);
+                    self.produced_sources.remove(&po.name);
+                    self.production_sequence.retain(|name| name != &po.name);
+                    if let Some(info_pack) = self.info_pack.as_ref() {
+                        info_pack.set_instance_error(&po.name, crashed_host_alert(&po.name));
+                    }
+                }
+                self.crashed_plugin_orders.insert(filename.clone(), orders);
+            }
+
+            self.restarting_plugin_hosts.insert(filename.clone());
+            self.task_sender
+                .spawn_with_name(
+                    "plugin_host_restart",
+                    Self::task_restart_plugin_host(ph, self.request_sender.clone()).boxed(),
+                )
+                .unwrap();
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// Produce again the instances of a plugin whose host has been restarted
+    ///
+    async fn service_plugin_host_restarted(
+        &mut self,
+        filename: PathBuf,
+        result: Result<(), String>,
+    ) {
+        self.restarting_plugin_hosts.remove(&filename);
+        if let Err(e) = result {
+            self.logger.error(format!(
+                "Unable to restart plugin host {:?}: {}",
+                filename, e
+            ));
+            return;
+        }
+        log_info!(self.logger, "Plugin host restarted: {:?}", filename);
+
+        let orders = self
+            .crashed_plugin_orders
+            .remove(&filename)
+            .unwrap_or_default();
+        for po in orders {
+            if let Some(info_pack) = self.info_pack.as_ref() {
+                info_pack.remove_alert(&po.name, &crashed_host_alert(&po.name));
+            }
+            self.service_produce_device(po).await;
         }
     }
 
@@ -416,124 +1019,213 @@ impl Platform {
         // info
         log_info!(self.logger, "----- SERVICE : START BROKER -----");
 
-        let addr = self
-            .config
-            .broker
-            .as_ref()
-            .and_then(|b| b.addr.clone())
-            .unwrap_or("127.0.0.1".to_string());
-
-        let port = self
-            .config
-            .broker
-            .as_ref()
-            .and_then(|b| b.port.clone())
-            .unwrap_or(1883);
-
-        let listen_addr = format!("{}:{}", addr, port);
-
-        let mut router: std::collections::HashMap<String, config::Value> = config::Map::new();
-        router.insert("id".to_string(), config::Value::new(None, 0));
-        router.insert(
-            "max_connections".to_string(),
-            config::Value::new(None, 10010),
-        );
-        router.insert(
-            "max_outgoing_packet_count".to_string(),
-            config::Value::new(None, 200),
-        );
-        router.insert(
-            "max_segment_size".to_string(),
-            config::Value::new(None, 104857600),
-        );
-        router.insert(
-            "max_segment_count".to_string(),
-            config::Value::new(None, 10),
-        );
-
-        let mut server_connections: std::collections::HashMap<String, config::Value> =
-            config::Map::new();
-        server_connections.insert(
-            "connection_timeout_ms".to_string(),
-            config::Value::new(None, 60000),
-        );
-        server_connections.insert(
-            "max_payload_size".to_string(),
-            config::Value::new(None, 20480),
-        );
-        server_connections.insert(
-            "max_inflight_count".to_string(),
-            config::Value::new(None, 10000),
-        );
-        server_connections.insert(
-            "dynamic_filters".to_string(),
-            config::Value::new(None, true),
-        );
-
-        let mut server: std::collections::HashMap<String, config::Value> = config::Map::new();
-        server.insert("name".to_string(), config::Value::new(None, "v4-1"));
-        server.insert(
-            "listen".to_string(),
-            config::Value::new(None, listen_addr.clone()),
-        );
-        server.insert(
-            "next_connection_delay_ms".to_string(),
-            config::Value::new(None, 1),
-        );
-        server.insert(
-            "connections".to_string(),
-            config::Value::new(None, server_connections),
+        let public_addr = format!(
+            "{}:{}",
+            self.config.broker_addr(),
+            self.config.broker_port()
         );
 
-        // see docs of config crate to know more
-        let config = config::Config::builder()
-            .set_default("id", 0)
-            .unwrap()
-            .set_default("router", router)
-            .unwrap()
-            .set_default("v4.1", server)
-            .unwrap()
-            .build()
-            .unwrap();
-
         //
-        // this is where we deserialize it into Config
-        let rumqttd_config: Config = config.try_deserialize().unwrap();
-        let mut broker = Broker::new(rumqttd_config);
+        // The broker only listens locally, the clients go through the command gate
+        let broker =
+            match BrokerProcess::start(self.enable_stdout, self.broker_log, self.debug, self.trace)
+                .await
+            {
+                Ok(broker) => broker,
+                Err(e) => {
+                    self.logger
+                        .error(format!("Unable to start the broker: {:?}", e));
+                    return;
+                }
+            };
+        self.internal_broker_port = broker.port();
+        self.broker = Some(broker);
+        let internal_addr = format!("{}:{}", INTERNAL_BROKER_ADDR, self.internal_broker_port);
+        log_info!(self.logger, "Broker listen on: {}", internal_addr);
 
         //
-        // start broker
-        log_info!(self.logger, "Broker listen on: {}", listen_addr);
-        let _jh = std::thread::spawn(move || {
-            broker.start().unwrap();
-            println!("BROKER STOPPPED !!!!!!!!!!!!!!!!!");
+        // Without the gate no client reaches the broker, it must live as long as the platform
+        let gate = self.command_gate.clone();
+        self.spawn_supervised("command_gate", RestartPolicy::always(), move || {
+            command_gate::serve(public_addr.clone(), internal_addr.clone(), gate.clone()).boxed()
         });
     }
 
     /// -------------------------------------------------------------
     ///
-    async fn service_start_local_discovery(&mut self) {
+    /// Ordered stop of the platform
+    ///
+    /// 1. Platform requests (tree, plugins, scanner) are not processed anymore and the
+    ///    command gate refuses the commands of the clients to the instances
+    /// 2. Instances are stopped in reverse production order, each one after reaching
+    ///    its safe values (with a timeout by instance), then destroyed in any case
+    /// 3. Retained topics left by the instances and the underscore device are cleared
+    /// 4. The broker process is stopped and waited for, with a timeout
+    ///
+    /// A second ctrl-c skips the remaining steps, the broker is stopped anyway.
+    ///
+    async fn service_shutdown(&mut self) {
         //
         // info
-        log_info!(self.logger, "----- SERVICE : START LOCAL DISCOVERY -----");
+        log_info!(self.logger, "----- SERVICE : SHUTDOWN -----");
+
+        let logger = self.logger.clone();
+        tokio::select! {
+            _ = self.shutdown_sequence() => {
+                log_info!(logger, "Shutdown completed");
+            },
+            _ = signal::ctrl_c() => {
+                log_warn!(logger, "User ctrl-c, shutdown forced");
+            }
+        }
+        self.stop_broker().await;
+    }
 
+    ///
+    /// Steps of the shutdown, see 'service_shutdown'
+    ///
+    async fn shutdown_sequence(&mut self) {
         //
-        // Check in config if we must start the local discovery
-        let plbd_is_enabled = self
-            .config
-            .services
-            .as_ref()
-            .and_then(|s| s.enable_plbd)
-            .unwrap_or(false);
+        // Tasks that feed the requests are stopped, instances are kept until their turn
+        for (name, handle) in self.task_handles.values() {
+            if REQUEST_TASKS.contains(&name.as_str()) {
+                log_debug!(self.logger, "Stop task {:?}", name);
+                handle.abort();
+            }
+        }
+        self.pending_productions.clear();
+        self.started_productions.clear();
+        self.command_gate.hold("the platform is stopping");
 
-        if plbd_is_enabled {
-            log_info!(self.logger, "PLBD is enabled");
-            self.task_sender
-                .spawn_with_name(
-                    "local_broker_discovery",
-                    local_broker_discovery::task(self.config.platform_name.clone()).boxed(),
-                )
-                .unwrap();
+        //
+        // Last produced, first stopped
+        let sequence: Vec<String> = self.production_sequence.iter().rev().cloned().collect();
+        for name in sequence.iter() {
+            log_info!(self.logger, "Stop instance '{}'", name);
+            self.stop_instance(name.clone()).await;
+        }
+
+        //
+        // Topics left by the instances and by the underscore device, other clients
+        // of the broker keep theirs
+        let mut filters: Vec<String> = sequence
+            .iter()
+            .map(|name| format!("pza/{}/#", name))
+            .collect();
+        filters.push("pza/_/#".to_string());
+        let cleaning = retained_cleaner::clear(
+            format!(
+                "{}-cleaner",
+                self.config.platform_name.clone().unwrap_or_default()
+            ),
+            INTERNAL_BROKER_ADDR.to_string(),
+            self.internal_broker_port,
+            filters,
+        );
+        match tokio::time::timeout(RETAINED_CLEAR_TIMEOUT, cleaning).await {
+            Ok(Ok(count)) => {
+                log_info!(self.logger, "{} retained topic(s) cleared", count);
+            }
+            Ok(Err(e)) => {
+                self.logger
+                    .error(format!("Unable to clear retained topics: {:?}", e));
+            }
+            Err(_) => {
+                self.logger
+                    .error("Timeout while clearing retained topics".to_string());
+            }
+        }
+    }
+
+    ///
+    /// Apply the safe values of the instance then destroy it, even if the values are not reached
+    ///
+    async fn stop_instance(&mut self, name: String) {
+        if self.config.shutdown_safe_values() {
+            if let Err(e) = self.apply_safe_values(&name).await {
+                self.logger.error(format!(
+                    "Unable to apply safe values of '{}': {:?}",
+                    name, e
+                ));
+            }
+        }
+        if let Err(e) = self.service_destroy_device(name.clone()).await {
+            self.logger
+                .error(format!("Unable to destroy '{}': {:?}", name, e));
+        }
+    }
+
+    ///
+    /// Drive the instance to the safe values declared in the tree
+    ///
+    /// Return the number of applied values, 0 if the tree declares none
+    ///
+    async fn apply_safe_values(&self, name: &str) -> Result<usize, Error> {
+        let values = match self.safe_values.get(name) {
+            Some(values) => values,
+            None => return Ok(0),
+        };
+        //
+        // One client by instance, the emergency stop applies them at the same time
+        let applying = safe_state::apply(
+            format!(
+                "{}-safe-state-{}",
+                self.config.platform_name.clone().unwrap_or_default(),
+                name
+            ),
+            INTERNAL_BROKER_ADDR.to_string(),
+            self.internal_broker_port,
+            name,
+            values,
+        );
+        let count = tokio::time::timeout(self.config.shutdown_instance_timeout(), applying)
+            .await
+            .map_err(|_| Error::Generic("Timeout while applying the safe values".to_string()))??;
+        log_info!(self.logger, "{} safe value(s) applied on '{}'", count, name);
+        Ok(count)
+    }
+
+    ///
+    /// Stop the broker process and wait for its end
+    ///
+    /// The gate still accepts the connections of the clients, they are closed with the
+    /// broker.
+    ///
+    async fn stop_broker(&mut self) {
+        if let Some(broker) = self.broker.take() {
+            if broker.stop(BROKER_STOP_TIMEOUT).await {
+                log_info!(self.logger, "Broker stopped");
+            } else {
+                self.logger
+                    .error("Timeout while stopping the broker".to_string());
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn service_start_local_discovery(&mut self) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : START LOCAL DISCOVERY -----");
+
+        //
+        // Check in config if we must start the local discovery
+        let plbd_is_enabled = self
+            .config
+            .services
+            .as_ref()
+            .and_then(|s| s.enable_plbd)
+            .unwrap_or(false);
+
+        if plbd_is_enabled {
+            log_info!(self.logger, "PLBD is enabled");
+            let platform_name = self.config.platform_name.clone();
+            self.spawn_supervised(
+                "local_broker_discovery",
+                RestartPolicy::on_failure(),
+                move || local_broker_discovery::task(platform_name.clone()).boxed(),
+            );
         } else {
             log_info!(self.logger, "PLBD is disabled");
         }
@@ -546,213 +1238,1471 @@ impl Platform {
         // info
         log_info!(self.logger, "----- SERVICE : LOAD PLUGINS -----");
 
-        self.plugin_manager.load_system_plugins().unwrap();
+        self.plugin_manager
+            .set_isolation(self.config.plugins_isolation());
+        self.plugin_manager.set_precedence(
+            self.config.producer_precedence(),
+            self.config.producer_pins(),
+        );
+        match PluginSelection::from_config(self.config.plugins.as_ref()) {
+            Ok(selection) => self.plugin_manager.set_selection(selection),
+            Err(e) => {
+                self.logger
+                    .error(format!("Plugin selection ignored: {:?}", e));
+            }
+        }
 
-        self.store
-            .set_stores(self.plugin_manager.merge_stores())
-            .await;
+        //
+        // With the 'fail' policy the platform does not start with a missing plugin
+        let fail_on_error = self.config.plugins_load_policy() == PluginLoadPolicy::Fail;
+        if let Err(e) = self.plugin_manager.load_system_plugins(fail_on_error) {
+            self.logger
+                .error(format!("Plugins loading failed, platform stopped: {:?}", e));
+            self.task_pool.abort_all();
+            self.must_stop.store(true, Ordering::Relaxed);
+            self.keep_alive.store(false, Ordering::Relaxed);
+            return;
+        }
+        self.update_plugins_info().await;
+
+        //
+        // Then watch the directories to apply the future modifications
+        let selection = self.plugin_manager.selection();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "plugins_dir_watcher",
+            RestartPolicy::on_failure(),
+            move || {
+                Self::task_watch_plugins_dirs(selection.clone(), request_sender.clone()).boxed()
+            },
+        );
     }
 
     /// -------------------------------------------------------------
     ///
-    async fn service_load_device_tree(&mut self) {
+    async fn service_load_plugin(&mut self, filename: PathBuf) {
         //
         // info
-        log_info!(self.logger, "----- SERVICE : LOAD DEVICE TREE -----");
+        log_info!(self.logger, "----- SERVICE : LOAD PLUGIN -----");
+        log_info!(self.logger, "PLUGIN: {:?}", filename);
 
-        //
-        // Get path
-        let tree_path = env::system_default_device_tree_file().unwrap();
+        if let Err(e) = self.plugin_manager.load_plugin(filename) {
+            self.logger.error(format!("Unable to load plugin: {:?}", e));
+        }
+        self.update_plugins_info().await;
+    }
 
+    /// -------------------------------------------------------------
+    ///
+    /// Return the orders of the destroyed instances, None if the plugin is still loaded
+    ///
+    async fn service_unload_plugin(&mut self, filename: PathBuf) -> Option<Vec<ProductionOrder>> {
         //
         // info
-        self.logger
-            .info(format!("TREE PATH: \"{}\"", tree_path.display()));
+        log_info!(self.logger, "----- SERVICE : UNLOAD PLUGIN -----");
+        log_info!(self.logger, "PLUGIN: {:?}", filename);
+
+        let ph = match self.plugin_manager.handler(&filename) {
+            Some(ph) => ph,
+            None => {
+                log_warn!(self.logger, "Plugin {:?} is not loaded", filename);
+                return None;
+            }
+        };
 
-        let file = File::open(tree_path).unwrap();
-        let dt: DeviceTree = serde_json::from_reader(&file).unwrap();
+        //
+        // A library loaded in the platform process cannot be released safely,
+        // its code may still be referenced by tasks and callbacks of the core
+        if !ph.is_isolated() {
+            self.logger.error(format!(
+                "Plugin {:?} is not isolated, unload and reload refused (enable the plugin isolation)",
+                filename
+            ));
+            return None;
+        }
 
-        for po in dt.devices {
-            self.request_sender
-                .try_send(ServiceRequest::ProduceDevice(po))
-                .unwrap();
+        //
+        // Running instances would use the code of the unloaded library
+        let orders = self.plugin_orders(&ph);
+        if !orders.is_empty() && !ph.can_unproduce() {
+            self.logger.error(format!(
+                "Plugin {:?} cannot destroy its {} instance(s), unload refused",
+                filename,
+                orders.len()
+            ));
+            return None;
+        }
+        for po in orders.iter() {
+            if self.service_destroy_device(po.name.clone()).await.is_err() {
+                self.logger.error(format!(
+                    "Plugin {:?} still runs '{}', unload refused",
+                    filename, po.name
+                ));
+                return None;
+            }
+        }
+
+        drop(ph);
+        if let Err(e) = self.plugin_manager.unload_plugin(&filename) {
+            self.logger
+                .error(format!("Unable to unload plugin: {:?}", e));
         }
+        self.update_plugins_info().await;
+        Some(orders)
     }
 
     /// -------------------------------------------------------------
     ///
-    async fn service_load_local_runtime(&mut self) {
+    async fn service_reload_plugin(&mut self, filename: PathBuf) {
         //
         // info
-        log_info!(self.logger, "----- SERVICE : LOAD LOCAL RUNTIME -----");
+        log_info!(self.logger, "----- SERVICE : RELOAD PLUGIN -----");
 
         //
-        // Allow mut here because it depend of features enabled
-        #[allow(unused_mut)]
-        let mut factory = Factory::new();
+        // Instances are produced again with the new code of the plugin
+        let scanned = self.scanned_instances.clone();
+        let orders = match self.service_unload_plugin(filename.clone()).await {
+            Some(orders) => orders,
+            None => return,
+        };
+        self.service_load_plugin(filename).await;
+        for po in orders {
+            if scanned.contains(&po.name) {
+                self.scanned_instances.insert(po.name.clone());
+            }
+            self.service_produce_device(po).await;
+        }
+    }
 
-        //
-        // Append built-in drivers
-        #[cfg(feature = "built-in-drivers")]
-        factory.add_producers(crate::built_in::plugin_producers());
+    /// Share the plugins with the underscore device (_/store and _/plugins)
+    ///
+    async fn update_plugins_info(&mut self) {
+        self.store
+            .set_stores(self.plugin_manager.merge_stores(&self.built_in_store))
+            .await;
 
-        //
-        //
-        self.built_in_store = factory.store();
+        match self.plugin_manager.plugins_info() {
+            Ok(plugins) => {
+                self.plugins_driver
+                    .set_plugins(plugins, self.plugin_manager.load_errors())
+                    .await;
+            }
+            Err(e) => {
+                self.logger
+                    .error(format!("Unable to describe plugins: {:?}", e));
+            }
+        }
 
         //
-        let settings = ReactorSettings::new("localhost", 1883, None);
-        let mut reactor = Reactor::new(settings);
-        reactor.start(self.task_sender.clone()).unwrap();
+        // Drivers provided by several producers, detected by the merge
+        self.plugins_driver
+            .set_conflicts(self.plugin_manager.producer_conflicts())
+            .await;
+    }
 
-        self.reactor = Some(reactor.clone());
+    /// Orders of the running instances produced by this plugin
+    ///
+    fn plugin_orders(&self, ph: &PluginHandler) -> Vec<ProductionOrder> {
+        let source = ProducerSource::Plugin(ph.filename().clone());
+        self.produced_orders
+            .values()
+            .filter(|po| self.produced_sources.get(&po.name) == Some(&source))
+            .cloned()
+            .collect()
+    }
 
+    /// -------------------------------------------------------------
+    ///
+    async fn service_load_device_tree(&mut self) {
         //
-        //
-        let runtime: Runtime = Runtime::new(factory, reactor);
+        // info
+        log_info!(self.logger, "----- SERVICE : LOAD DEVICE TREE -----");
 
         //
+        // Get path
+        let tree_path = default_tree_file().unwrap();
+
         //
-        self.local_runtime_notifications = Some(runtime.clone_notifications());
+        // info
+        self.logger
+            .info(format!("TREE PATH: \"{}\"", tree_path.display()));
+        if let Some(profile) = self.tree_profile.as_ref() {
+            self.logger.info(format!("TREE PROFILE: \"{}\"", profile));
+        }
 
         //
+        // Produce the devices of the tree
+        self.service_reload_device_tree().await;
+
         //
-        self.local_runtime_po_sender = Some(runtime.clone_production_order_sender());
+        // Then watch the files to apply the future modifications
+        let tree_sources = self.tree_sources.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "device_tree_watcher",
+            RestartPolicy::on_failure(),
+            move || {
+                Self::task_watch_device_tree(tree_sources.clone(), request_sender.clone()).boxed()
+            },
+        );
 
         //
-        // Start thread
-        self.task_sender
-            .spawn_with_name("platform_runtime", runtime.task().boxed())
-            .unwrap();
+        // The command gate needs the current values of the limited attributes
+        match self.info_pack.clone() {
+            Some(info_pack) => {
+                let client_id = format!(
+                    "{}-guardrails",
+                    self.config.platform_name.clone().unwrap_or_default()
+                );
+                let port = self.internal_broker_port;
+                let guardrails = self.guardrails.clone();
+                let logger = self.logger.clone();
+                self.spawn_supervised("guardrails", RestartPolicy::always(), move || {
+                    guardrails::follow(
+                        client_id.clone(),
+                        INTERNAL_BROKER_ADDR.to_string(),
+                        port,
+                        guardrails.clone(),
+                        info_pack.clone(),
+                        logger.clone(),
+                    )
+                    .boxed()
+                });
+            }
+            None => {
+                self.logger
+                    .error("Guardrails not started, underscore device missing".to_string());
+            }
+        }
     }
 
     /// -------------------------------------------------------------
     ///
-    async fn service_load_underscore_device(&mut self) {
+    async fn service_reload_device_tree(&mut self) {
         //
         // info
-        log_info!(self.logger, "----- SERVICE : LOAD UNDERSCORE DEVICE -----");
+        log_info!(self.logger, "----- SERVICE : RELOAD DEVICE TREE -----");
 
         //
-        //
-        let (underscore_device_operations, info_pack) =
-            UnderscoreDevice::new(self.store.clone(), self.scanner_driver.clone());
+        // A tree being edited can be invalid, keep running instances untouched in this case
+        let tree_path = default_tree_file().unwrap();
+        let dt = match DeviceTree::from_file(&tree_path, self.tree_profile.as_deref()) {
+            Ok(dt) => dt,
+            Err(e) => {
+                self.logger.error(format!("Device tree ignored: {:?}", e));
+                return;
+            }
+        };
+        *self.tree_sources.lock().unwrap() = dt.sources.clone();
+        self.safe_values = dt
+            .devices
+            .iter()
+            .filter(|entry| !entry.safe_values.is_empty())
+            .map(|entry| (entry.order.name.clone(), entry.safe_values.clone()))
+            .collect();
+        self.guardrails.set_limits(
+            dt.devices
+                .iter()
+                .filter(|entry| !entry.limits.is_empty())
+                .map(|entry| (entry.order.name.clone(), entry.limits.clone()))
+                .collect(),
+        );
 
         //
+        // Share it with the underscore device
+        self.tree_driver.set_tree(dt.clone()).await;
+
         //
-        let (mut monitor, mut device) = InstanceMonitor::new(
-            self.reactor.as_ref().unwrap().clone(),
-            None, // this device will manage info_pack and cannot use it to boot like other devices
-            Box::new(underscore_device_operations),
-            ProductionOrder::new("_", "_"),
-        );
+        // Devices that do not match their driver are not produced
+        let invalid = self.validate_device_tree(&dt);
 
-        self.task_sender
-            .spawn_with_name(
-                "_/fsm",
-                async move {
-                    device.run_fsm().await;
-                    Ok(())
-                }
-                .boxed(),
-            )
-            .unwrap();
+        //
+        // Instances from the tree are not managed as scanned instances anymore
+        for entry in dt.devices.iter() {
+            self.scanned_instances.remove(&entry.order.name);
+        }
 
-        self.task_sender
-            .spawn_with_name(
-                "_/monitor",
-                async move {
-                    monitor.run().await;
-                    Ok(())
-                }
-                .boxed(),
-            )
-            .unwrap();
+        //
+        // Bind identities to the units found by the last scan, scan first if needed
+        self.tree_has_identities = dt.has_identities();
+        let scanned = self.scanner_driver.last_found_instances().await;
+        if self.tree_has_identities && scanned.is_none() {
+            log_info!(self.logger, "Device tree identities need a scan");
+            self.service_start_scanning(self.scanner_driver.clone())
+                .await;
+        }
+        let (mut orders, unresolved) = dt.resolve(&scanned.unwrap_or_default());
+        self.update_unresolved_instances(&dt, &unresolved);
+        orders.retain(|po| !invalid.contains(&po.name));
 
-        // // self.info_pack.
-        let n_n = self.notifications.clone();
-        let n_notifier = self.new_notifications_notifier.clone();
-        self.task_sender
-            .spawn_with_name(
-                "notifications",
-                Self::task_process_notifications(info_pack, n_notifier, n_n).boxed(),
-            )
-            .unwrap();
+        //
+        // Failed instances removed from the tree must not be reported anymore
+        let forgotten: Vec<String> = self
+            .failed_instances
+            .iter()
+            .filter(|name| !dt.devices.iter().any(|d| &d.order.name == *name))
+            .cloned()
+            .collect();
+        for name in forgotten {
+            self.failed_instances.remove(&name);
+            if let Some(info_pack) = self.info_pack.as_ref() {
+                info_pack.remove_instance(&name);
+            }
+        }
 
         //
+        // A produced instance whose unit is not found anymore keeps running
+        for name in unresolved.iter() {
+            if let Some(po) = self.produced_orders.get(name) {
+                orders.push(po.clone());
+            }
+        }
+
+        let mut diff = DeviceTreeDiff::new(&orders, &self.produced_orders);
+        diff.removed
+            .retain(|name| !self.scanned_instances.contains(name));
+
         //
-        self.task_sender
-            .spawn_with_name(
-                "scanner_request_processor",
-                Self::task_process_scanner(
-                    self.scanner_driver.clone(),
-                    self.request_sender.clone(),
-                )
-                .boxed(),
-            )
-            .unwrap();
+        // Pending productions of devices removed from the tree are dropped
+        self.pending_productions
+            .retain(|p| orders.iter().any(|po| po.name == p.order.name));
+        self.started_productions
+            .retain(|name, _| orders.iter().any(|po| &po.name == name));
+        if diff.is_empty() {
+            log_info!(self.logger, "Device tree unchanged");
+            return;
+        }
+        log_info!(self.logger, "Device tree changes: {:?}", diff);
+        self.apply_tree_diff(&dt, diff).await;
     }
 
-    /// -------------------------------------------------------------
+    /// Destroy the instances removed or changed in the tree and schedule the new orders
+    ///
+    /// Destructions are done before the productions are scheduled, because a changed
+    /// instance is produced again with the same name. A changed instance that cannot
+    /// be destroyed keeps running with its previous settings.
+    ///
+    async fn apply_tree_diff(&mut self, dt: &DeviceTree, diff: DeviceTreeDiff) {
+        for name in diff.removed {
+            let _ = self.service_destroy_device(name).await;
+        }
+        let mut changed = Vec::new();
+        for po in diff.changed {
+            if self.service_destroy_device(po.name.clone()).await.is_ok() {
+                changed.push(po);
+            }
+        }
+        for po in changed.into_iter().chain(diff.added) {
+            match dt.devices.iter().find(|d| d.order.name == po.name) {
+                Some(entry) => self.schedule_production(
+                    po,
+                    entry.depends_on.clone(),
+                    entry.retry.clone().unwrap_or_default(),
+                ),
+                None => self.schedule_production(po, Vec::new(), RetryPolicy::default()),
+            }
+        }
+        self.service_dispatch_productions().await;
+    }
+
+    /// Add a production to the ones waiting for their dependencies and retries
+    ///
+    /// An identical order already pending keeps its retry state
     ///
-    async fn service_produce_device(&mut self, po: ProductionOrder) {
+    fn schedule_production(
+        &mut self,
+        po: ProductionOrder,
+        depends_on: Vec<String>,
+        retry: RetryPolicy,
+    ) {
         //
-        // info
-        log_info!(self.logger, "----- SERVICE : PRODUCE DEVICE -----");
-        log_info!(self.logger, "ORDER: {:?}", po);
+        // The dependents of this device are not blocked anymore, they wait for it again
+        self.started_productions.remove(&po.name);
+        for pending in self.pending_productions.iter_mut() {
+            if pending.blocked_by.as_ref() == Some(&po.name) {
+                pending.blocked_by = None;
+            }
+        }
 
-        if self.built_in_store.contains(&po.dref()) {
-            log_info!(self.logger, "LOCAL PRODUCER");
-            self.local_runtime_po_sender
-                .as_ref()
-                .unwrap()
-                .try_send(po)
-                .unwrap();
-        } else {
-            log_info!(self.logger, "PLUGIN PRODUCER");
-            let _res = self.plugin_manager.produce(&po).unwrap();
+        let order_value = serde_json::to_value(&po).ok();
+        if let Some(pending) = self
+            .pending_productions
+            .iter_mut()
+            .find(|p| p.order.name == po.name)
+        {
+            if serde_json::to_value(&pending.order).ok() != order_value {
+                pending.failures = 0;
+                pending.next_attempt = Instant::now();
+            }
+            pending.order = po;
+            pending.depends_on = depends_on;
+            pending.retry = retry;
+            return;
         }
+        self.pending_productions.push(PendingProduction {
+            order: po,
+            depends_on: depends_on,
+            retry: retry,
+            failures: 0,
+            next_attempt: Instant::now(),
+            blocked_by: None,
+        });
     }
 
     /// -------------------------------------------------------------
     ///
-    async fn service_start_scanning(&mut self, mut scanner_shared_data: ScannerDriver) {
+    /// Produce the pending orders whose dependencies are running and whose retry delay is over
+    ///
+    async fn service_dispatch_productions(&mut self) {
         //
-        // info
-        log_info!(self.logger, "----- SERVICE : START SCANNING -----");
+        // Pending orders wait for the re-arm of the emergency stop
+        if self.estop_engaged {
+            return;
+        }
+
+        let states: HashMap<String, State> = match self.info_pack.as_ref() {
+            Some(info_pack) => info_pack
+                .pack_instance_status()
+                .into_iter()
+                .map(|status| (status.0, status.1))
+                .collect(),
+            None => HashMap::new(),
+        };
+        let now = Instant::now();
+        let mut abandoned = Vec::new();
 
-        let mut orders = Vec::new();
+        //
+        // A driver can accept the order and fail later, for example while its device is
+        // still being enumerated, the state notified by the instance ends the production
+        for (name, mut started) in std::mem::take(&mut self.started_productions) {
+            if !self.produced_orders.contains_key(&name) {
+                continue;
+            }
+            match states.get(&name) {
+                Some(State::Running) => continue,
+                Some(State::Error) => {}
+                _ => {
+                    self.started_productions.insert(name, started);
+                    continue;
+                }
+            }
+            if let Err(e) = self.service_destroy_device(name.clone()).await {
+                self.logger.error(format!(
+                    "Production of '{}' abandoned, instance in error not destroyed: {:?}",
+                    name, e
+                ));
+                abandoned.push(name);
+                continue;
+            }
+            started.failures += 1;
+            if let Some(started) = self.retry_production(started, now) {
+                abandoned.push(started.order.name.clone());
+            }
+        }
 
-        orders.extend(self.plugin_manager.scan().unwrap());
+        for mut pending in std::mem::take(&mut self.pending_productions) {
+            let ready = pending.next_attempt <= now
+                && !self.is_cleaning(&pending.order.name)
+                && pending
+                    .depends_on
+                    .iter()
+                    .all(|d| matches!(states.get(d), Some(State::Running)));
+            if !ready {
+                self.pending_productions.push(pending);
+                continue;
+            }
 
-        #[cfg(feature = "built-in-drivers")]
-        for scanner in built_in::plugin_scanners() {
-            orders.extend(scanner.scan());
+            //
+            // The state of a previous failed attempt must not be taken for this one
+            if matches!(states.get(&pending.order.name), Some(State::Error)) {
+                if let Some(info_pack) = self.info_pack.as_ref() {
+                    info_pack.remove_instance(&pending.order.name);
+                }
+            }
+
+            match self.service_produce_device(pending.order.clone()).await {
+                ProductionOutcome::Ok => {
+                    self.started_productions
+                        .insert(pending.order.name.clone(), pending);
+                }
+                ProductionOutcome::EmergencyStop => {
+                    self.pending_productions.push(pending);
+                }
+                //
+                // The same order would fail the same way
+                ProductionOutcome::UnknownDriver | ProductionOutcome::InvalidSettings(_) => {
+                    self.logger.error(format!(
+                        "Production of '{}' abandoned, the order cannot be produced",
+                        pending.order.name
+                    ));
+                    abandoned.push(pending.order.name.clone());
+                }
+                _ => {
+                    pending.failures += 1;
+                    if let Some(pending) = self.retry_production(pending, now) {
+                        abandoned.push(pending.order.name.clone());
+                    }
+                }
+            }
         }
 
-        log_info!(self.logger, "Found instances : {:?}", orders);
+        for name in abandoned {
+            self.block_dependents(&name);
+        }
+    }
 
-        scanner_shared_data.store_instances(orders).await;
-        scanner_shared_data.stop_running().await;
+    /// True if productions wait for their dispatch or for the state of their instance
+    ///
+    fn has_productions_to_dispatch(&self) -> bool {
+        !self.pending_productions.is_empty() || !self.started_productions.is_empty()
     }
 
-    /// -------------------------------------------------------------
+    /// Put a failed production back in the pending ones after its retry delay
     ///
-    async fn task_process_notifications(
-        mut info_pack: InfoPack,
-        n_notifier: Arc<Notify>,
-        n_notifications: Arc<Mutex<Vec<Notification>>>,
+    /// Return the production if it has been abandoned
+    ///
+    fn retry_production(
+        &mut self,
+        mut pending: PendingProduction,
+        now: Instant,
+    ) -> Option<PendingProduction> {
+        if pending.failures > pending.retry.max_retries {
+            self.logger.error(format!(
+                "Production of '{}' abandoned after {} attempt(s)",
+                pending.order.name, pending.failures
+            ));
+            return Some(pending);
+        }
+        let delay = pending.retry.delay(pending.failures);
+        log_warn!(
+            self.logger,
+            "Production of '{}' retried in {:?}",
+            pending.order.name,
+            delay
+        );
+        pending.next_attempt = now + delay;
+        self.pending_productions.push(pending);
+        None
+    }
+
+    /// Record on the productions that depend on an abandoned one that they are blocked
+    ///
+    /// They stay pending, the dependency can come back with a reload of the tree.
+    ///
+    fn block_dependents(&mut self, abandoned: &String) {
+        let mut dependencies = vec![abandoned.clone()];
+        while let Some(dependency) = dependencies.pop() {
+            let mut blocked = Vec::new();
+            for pending in self.pending_productions.iter_mut() {
+                if pending.blocked_by.is_none() && pending.depends_on.contains(&dependency) {
+                    pending.blocked_by = Some(dependency.clone());
+                    blocked.push(pending.order.clone());
+                }
+            }
+            for po in blocked {
+                self.record_production(&po, ProductionOutcome::Blocked(dependency.clone()));
+                dependencies.push(po.name);
+            }
+        }
+    }
+
+    /// Check the tree against the drivers
+    ///
+    /// Problems that prevent the production are recorded as production failures, the others
+    /// are raised as alerts on the instance which is produced anyway.
+    ///
+    /// Return the names of the devices that must not be produced
+    ///
+    fn validate_device_tree(&mut self, dt: &DeviceTree) -> HashSet<String> {
+        let store = self.plugin_manager.merge_stores(&self.built_in_store);
+        let store = match store.into_json_value() {
+            Ok(store) => store,
+            Err(e) => {
+                self.logger
+                    .error(format!("Device tree not validated: {:?}", e));
+                return HashSet::new();
+            }
+        };
+        //
+        // The tree is validated on each reload, only new problems are reported
+        // to keep the production history for real productions
+        let mut invalid = HashSet::new();
+        let mut recorded = HashSet::new();
+        let mut issues = HashMap::new();
+        for issue in tree_validation::validate(dt, &store) {
+            let description = format!("{}", issue);
+            if issue.kind.blocks_production() {
+                invalid.insert(issue.instance.clone());
+            }
+            if issues
+                .insert(description.clone(), issue.instance.clone())
+                .is_some()
+                || self.tree_issues.contains_key(&description)
+            {
+                continue;
+            }
+            log_warn!(self.logger, "Device tree: {}", description);
+            if !issue.kind.blocks_production() {
+                if let Some(info_pack) = self.info_pack.as_ref() {
+                    info_pack.raise_alert(
+                        &issue.instance,
+                        tree_issue_alert(&issue.instance, &description),
+                    );
+                }
+                continue;
+            }
+            if !recorded.insert(issue.instance.clone()) {
+                continue;
+            }
+            let outcome = match issue.kind {
+                TreeIssueKind::UnknownDriver => ProductionOutcome::UnknownDriver,
+                _ => ProductionOutcome::InvalidSettings(description),
+            };
+            if let Some(entry) = dt.devices.iter().find(|d| d.order.name == issue.instance) {
+                self.record_production(&entry.order, outcome);
+            }
+        }
+
+        //
+        // Alerts of the problems fixed in the tree are removed
+        for (description, instance) in self.tree_issues.iter() {
+            if issues.contains_key(description) {
+                continue;
+            }
+            if let Some(info_pack) = self.info_pack.as_ref() {
+                info_pack.remove_alert(instance, &tree_issue_alert(instance, description));
+            }
+        }
+        self.tree_issues = issues;
+        invalid
+    }
+
+    /// Raise an alert on the instances whose identity has no matching unit
+    ///
+    fn update_unresolved_instances(&mut self, dt: &DeviceTree, unresolved: &Vec<String>) {
+        let info_pack = match self.info_pack.as_ref() {
+            Some(info_pack) => info_pack,
+            None => return,
+        };
+
+        let unresolved_alert = |name: &String| {
+            Alert::new(
+                format!("pza/{}", name),
+                "No scanned unit matches the identity of this device",
+            )
+        };
+
+        for name in self.unresolved_instances.iter() {
+            if unresolved.contains(name) {
+                continue;
+            }
+            if dt.devices.iter().any(|d| &d.order.name == name) {
+                info_pack.remove_alert(name, &unresolved_alert(name));
+            } else if !self.produced_orders.contains_key(name) {
+                info_pack.remove_instance(name);
+            }
+        }
+
+        //
+        // Reloads are frequent with the auto scan, the alert is raised once
+        for name in unresolved.iter() {
+            if self.unresolved_instances.contains(name) {
+                continue;
+            }
+            log_warn!(self.logger, "No unit matches the identity of '{}'", name);
+            info_pack.raise_alert(name, unresolved_alert(name));
+        }
+
+        self.unresolved_instances = unresolved.iter().cloned().collect();
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn service_load_local_runtime(&mut self) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : LOAD LOCAL RUNTIME -----");
+
+        //
+        // Allow mut here because it depend of features enabled
+        #[allow(unused_mut)]
+        let mut factory = Factory::new();
+
+        //
+        // Append built-in drivers
+        #[cfg(feature = "built-in-drivers")]
+        factory.add_producers(crate::built_in::plugin_producers());
+
+        //
+        //
+        self.built_in_store = factory.store();
+        if let Err(e) = self.plugins_driver.set_built_in(&self.built_in_store).await {
+            self.logger
+                .error(format!("Unable to describe built-in producers: {:?}", e));
+        }
+
+        //
+        // The built-in drivers take part in the producer conflicts
+        self.update_plugins_info().await;
+
+        //
+        //
+        // The clients of the platform itself skip the command gate
+        let settings = ReactorSettings::new(INTERNAL_BROKER_ADDR, self.internal_broker_port, None);
+        let mut reactor = Reactor::new(settings);
+        reactor.start(self.task_sender.clone()).unwrap();
+
+        self.reactor = Some(reactor.clone());
+
+        //
+        //
+        let runtime: Runtime = Runtime::new(factory, reactor);
+
+        //
+        // The notifications of the local instances go to the pipeline in their own task
+        let group = runtime.clone_notifications();
+        let notifications = self.notifications.clone();
+        self.spawn_supervised(
+            "local_notifications",
+            RestartPolicy::on_failure(),
+            move || Self::task_forward_notifications(group.clone(), notifications.clone()).boxed(),
+        );
+
+        //
+        //
+        self.local_runtime_po_sender = Some(runtime.clone_production_order_sender());
+
+        //
+        // Start thread
+        self.task_sender
+            .spawn_with_name("platform_runtime", runtime.task().boxed())
+            .unwrap();
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn service_load_underscore_device(&mut self) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : LOAD UNDERSCORE DEVICE -----");
+
+        //
+        //
+        let (underscore_device_operations, info_pack) = UnderscoreDevice::new(
+            self.store.clone(),
+            self.scanner_driver.clone(),
+            self.tree_driver.clone(),
+            self.plugins_driver.clone(),
+            self.estop_driver.clone(),
+            self.tasks_driver.clone(),
+            self.notifications.metrics(),
+        );
+
+        //
+        //
+        let (mut monitor, mut device) = InstanceMonitor::new(
+            self.reactor.as_ref().unwrap().clone(),
+            None, // this device will manage info_pack and cannot use it to boot like other devices
+            Box::new(underscore_device_operations),
+            ProductionOrder::new("_", "_"),
+        );
+
+        self.task_sender
+            .spawn_with_name(
+                "_/fsm",
+                async move {
+                    device.run_fsm().await;
+                    Ok(())
+                }
+                .boxed(),
+            )
+            .unwrap();
+
+        self.task_sender
+            .spawn_with_name(
+                "_/monitor",
+                async move {
+                    monitor.run().await;
+                    Ok(())
+                }
+                .boxed(),
+            )
+            .unwrap();
+
+        self.info_pack = Some(info_pack.clone());
+        self.command_gate.set_info_pack(info_pack.clone());
+        let receiver = self.notification_receiver.clone();
+        self.spawn_supervised("notifications", RestartPolicy::on_failure(), move || {
+            Self::task_process_notifications(info_pack.clone(), receiver.clone()).boxed()
+        });
+
+        //
+        //
+        let scanner_driver = self.scanner_driver.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "scanner_request_processor",
+            RestartPolicy::always(),
+            move || {
+                Self::task_process_scanner(scanner_driver.clone(), request_sender.clone()).boxed()
+            },
+        );
+
+        //
+        // Background scanning to detect hotplug
+        if let Some(period) = self.config.scanner_auto_scan() {
+            log_info!(self.logger, "Auto scan every {:?}", period);
+            let scanner_driver = self.scanner_driver.clone();
+            let request_sender = self.request_sender.clone();
+            self.spawn_supervised("auto_scanner", RestartPolicy::on_failure(), move || {
+                Self::task_auto_scan(period, scanner_driver.clone(), request_sender.clone()).boxed()
+            });
+        }
+
+        //
+        //
+        let scanner_driver = self.scanner_driver.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "scanner_instantiation_processor",
+            RestartPolicy::always(),
+            move || {
+                Self::task_process_instantiation(scanner_driver.clone(), request_sender.clone())
+                    .boxed()
+            },
+        );
+
+        //
+        //
+        let plugins_driver = self.plugins_driver.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "plugins_request_processor",
+            RestartPolicy::always(),
+            move || {
+                Self::task_process_plugins(plugins_driver.clone(), request_sender.clone()).boxed()
+            },
+        );
+
+        //
+        //
+        let tree_driver = self.tree_driver.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "tree_request_processor",
+            RestartPolicy::always(),
+            move || Self::task_process_tree(tree_driver.clone(), request_sender.clone()).boxed(),
+        );
+
+        //
+        //
+        let estop_driver = self.estop_driver.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "estop_request_processor",
+            RestartPolicy::always(),
+            move || Self::task_process_estop(estop_driver.clone(), request_sender.clone()).boxed(),
+        );
+
+        let tasks_driver = self.tasks_driver.clone();
+        let request_sender = self.request_sender.clone();
+        self.spawn_supervised(
+            "tasks_request_processor",
+            RestartPolicy::always(),
+            move || Self::task_process_tasks(tasks_driver.clone(), request_sender.clone()).boxed(),
+        );
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// Return true if the order has been given to its producer
+    ///
+    async fn service_produce_device(&mut self, po: ProductionOrder) -> ProductionOutcome {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : PRODUCE DEVICE -----");
+        log_info!(self.logger, "ORDER: {:?}", po);
+
+        //
+        // Nothing is produced while the emergency stop is engaged
+        if self.estop_engaged {
+            self.record_production(&po, ProductionOutcome::EmergencyStop);
+            return ProductionOutcome::EmergencyStop;
+        }
+
+        //
+        // An instance that could not be destroyed is still running
+        if self.produced_orders.contains_key(&po.name) {
+            let outcome = ProductionOutcome::DriverError(format!(
+                "Instance '{}' is already running",
+                po.name
+            ));
+            self.record_production(&po, outcome.clone());
+            return outcome;
+        }
+
+        //
+        // The topics of the previous instance with this name must be cleared first
+        if let Some(cleaning) = self.retained_cleanings.remove(&po.name) {
+            let _ = cleaning.await;
+        }
+
+        //
+        // Several producers can provide the driver, the precedence rule selects one
+        let source = match self
+            .plugin_manager
+            .select_producer(&po.dref(), &self.built_in_store)
+        {
+            Some(source) => source,
+            None => {
+                log_warn!(self.logger, "No producer found for '{}'", po.dref());
+                self.record_production(&po, ProductionOutcome::UnknownDriver);
+                return ProductionOutcome::UnknownDriver;
+            }
+        };
+
+        //
+        // Drivers expect their settings as an object
+        if let Some(settings) = po.settings.as_ref() {
+            if !settings.is_object() {
+                let outcome =
+                    ProductionOutcome::InvalidSettings("Settings must be an object".to_string());
+                self.record_production(&po, outcome.clone());
+                return outcome;
+            }
+        }
+
+        let outcome = match &source {
+            ProducerSource::BuiltIn => {
+                log_info!(self.logger, "LOCAL PRODUCER");
+                self.local_runtime_po_sender
+                    .as_ref()
+                    .unwrap()
+                    .try_send(po.clone())
+                    .map(|_| ProductionOutcome::Ok)
+                    .unwrap_or_else(|e| ProductionOutcome::DriverError(format!("{:?}", e)))
+            }
+            ProducerSource::Plugin(filename) => {
+                log_info!(self.logger, "PLUGIN PRODUCER {:?}", filename);
+                match self.plugin_manager.produce(filename, &po).await {
+                    Ok(true) => ProductionOutcome::Ok,
+                    Ok(false) => ProductionOutcome::UnknownDriver,
+                    Err(Error::InvalidArgument(e)) | Err(Error::SerializeFailure(e)) => {
+                        ProductionOutcome::InvalidSettings(e)
+                    }
+                    Err(e) => ProductionOutcome::DriverError(format!("{:?}", e)),
+                }
+            }
+        };
+
+        //
+        // A failed order is not running, the next tree reload will try again
+        self.record_production(&po, outcome.clone());
+        if outcome.is_ok() {
+            self.produced_sources.insert(po.name.clone(), source);
+            self.production_sequence.retain(|name| name != &po.name);
+            self.production_sequence.push(po.name.clone());
+            self.produced_orders.insert(po.name.clone(), po);
+        }
+        outcome
+    }
+
+    /// Publish the result of a production order on the underscore device
+    ///
+    fn record_production(&mut self, po: &ProductionOrder, outcome: ProductionOutcome) {
+        if outcome.is_ok() {
+            self.failed_instances.remove(&po.name);
+        } else {
+            self.failed_instances.insert(po.name.clone());
+            self.logger
+                .error(format!("Production of '{}' failed: {:?}", po.name, outcome));
+        }
+        if let Some(info_pack) = self.info_pack.as_ref() {
+            info_pack.record_production(ProductionRecord::now(po.name.clone(), po.dref(), outcome));
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn service_destroy_device(&mut self, name: String) -> Result<(), Error> {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : DESTROY DEVICE -----");
+        log_info!(self.logger, "INSTANCE: {:?}", name);
+
+        let po = match self.produced_orders.get(&name).cloned() {
+            Some(po) => po,
+            None => {
+                log_warn!(self.logger, "Instance '{}' is not produced", name);
+                return Ok(());
+            }
+        };
+
+        //
+        // Stop the instance where it has been produced, an instance that is still
+        // running must stay known to avoid a second production with the same name
+        let destroyed = match self.produced_sources.get(&name).cloned() {
+            Some(ProducerSource::BuiltIn) => {
+                //
+                // The runtime of the core gives no way to remove an instance, aborting
+                // its tasks would leave its state machine running
+                log_info!(self.logger, "LOCAL PRODUCER");
+                Err(Error::Generic(
+                    "The local runtime cannot stop its instances".to_string(),
+                ))
+            }
+            Some(ProducerSource::Plugin(filename)) => {
+                log_info!(self.logger, "PLUGIN PRODUCER {:?}", filename);
+                self.plugin_manager
+                    .unproduce(&filename, &po)
+                    .await
+                    .and_then(|destroyed| match destroyed {
+                        true => Ok(()),
+                        false => Err(Error::PluginError(format!(
+                            "Plugin {:?} does not manage '{}'",
+                            filename, name
+                        ))),
+                    })
+            }
+            None => {
+                log_warn!(self.logger, "Producer of '{}' is unknown", name);
+                Ok(())
+            }
+        };
+        if let Err(e) = destroyed {
+            self.logger
+                .error(format!("Unable to destroy '{}': {:?}", name, e));
+            if let Some(info_pack) = self.info_pack.as_ref() {
+                info_pack.raise_alert(
+                    &name,
+                    Alert::new(
+                        format!("pza/{}", name),
+                        format!(
+                            "Instance cannot be destroyed, it keeps running until the platform restarts: {:?}",
+                            e
+                        ),
+                    ),
+                );
+            }
+            return Err(e);
+        }
+
+        self.scanned_instances.remove(&name);
+        self.production_sequence.retain(|n| n != &name);
+        self.produced_orders.remove(&name);
+        self.produced_sources.remove(&name);
+
+        //
+        // Forget it in the underscore device (_/devices and _/structure)
+        if let Some(info_pack) = self.info_pack.as_ref() {
+            info_pack.remove_instance(&name);
+        }
+
+        self.spawn_retained_cleaning(&name);
+        Ok(())
+    }
+
+    /// Clear the retained messages of a destroyed instance in the background
+    ///
+    /// A new production with the same name waits for the end of the cleaning,
+    /// otherwise the cleaner could remove the topics of the new instance.
+    ///
+    fn spawn_retained_cleaning(&mut self, name: &str) {
+        let cleaning = retained_cleaner::clear(
+            format!(
+                "{}-cleaner-{}",
+                self.config.platform_name.clone().unwrap_or_default(),
+                name
+            ),
+            INTERNAL_BROKER_ADDR.to_string(),
+            self.internal_broker_port,
+            vec![
+                format!("pza/{}/#", name),
+                format!("pza/_/devices/{}/#", name),
+            ],
+        );
+        let logger = self.logger.clone();
+        let name_2 = name.to_string();
+        let handle = tokio::spawn(async move {
+            match tokio::time::timeout(RETAINED_CLEAR_TIMEOUT, cleaning).await {
+                Ok(Ok(count)) => {
+                    log_info!(
+                        logger,
+                        "{} retained topic(s) cleared for '{}'",
+                        count,
+                        name_2
+                    );
+                }
+                Ok(Err(e)) => {
+                    logger.error(format!("Unable to clear topics of '{}': {:?}", name_2, e));
+                }
+                Err(_) => {
+                    logger.error(format!("Timeout while clearing topics of '{}'", name_2));
+                }
+            }
+        });
+        self.retained_cleanings.insert(name.to_string(), handle);
+    }
+
+    /// True while the retained messages of a previous instance with this name are cleared
+    ///
+    fn is_cleaning(&self, name: &str) -> bool {
+        self.retained_cleanings
+            .get(name)
+            .map_or(false, |handle| !handle.is_finished())
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// Drive every produced instance to its safe values and hold their commands
+    ///
+    /// The instances keep running, the command gate refuses the commands of the clients
+    /// and nothing is produced until the re-arm.
+    ///
+    async fn service_trigger_estop(&mut self) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : TRIGGER ESTOP -----");
+
+        //
+        // No command of the clients reaches the instances until the re-arm,
+        // the safe values are sent by the platform behind the gate
+        self.command_gate.hold("the emergency stop is engaged");
+        self.estop_engaged = true;
+
+        //
+        // All the instances are driven to their safe values at the same time
+        let sequence: Vec<String> = self.production_sequence.iter().rev().cloned().collect();
+        let results = join_all(sequence.iter().map(|name| self.apply_safe_values(name))).await;
+
+        let mut outcomes = BTreeMap::new();
+        for (name, result) in sequence.into_iter().zip(results) {
+            let outcome = match result {
+                Ok(0) => EstopOutcome::Held,
+                Ok(_) => EstopOutcome::Safe,
+                Err(e) => {
+                    self.logger
+                        .error(format!("Estop of '{}' not confirmed: {:?}", name, e));
+                    EstopOutcome::Failed(format!("{:?}", e))
+                }
+            };
+            outcomes.insert(name, outcome);
+        }
+
+        log_warn!(
+            self.logger,
+            "Estop engaged, commands of {} instance(s) held",
+            outcomes.len()
+        );
+        self.estop_driver.set_engaged(outcomes).await;
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// Accept again the commands of the instances and resume the pending productions
+    ///
+    async fn service_rearm_estop(&mut self) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : REARM ESTOP -----");
+
+        if !self.estop_engaged {
+            log_warn!(self.logger, "Estop is not engaged");
+            return;
+        }
+        self.estop_engaged = false;
+        self.command_gate.release();
+        self.estop_driver.set_rearmed().await;
+
+        //
+        // Tree orders received meanwhile waited in the pending productions
+        self.service_dispatch_productions().await;
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// Abort all the running tasks with this name, they are not restarted
+    ///
+    /// The tasks of the underscore device and the runtime are kept, without them
+    /// the platform could not be controlled anymore.
+    ///
+    async fn service_abort_task(&mut self, name: String) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : ABORT TASK -----");
+
+        if name == "platform_runtime" || name == "tasks_request_processor" || name.starts_with("_/")
+        {
+            log_warn!(self.logger, "Task {:?} cannot be aborted", name);
+            return;
+        }
+
+        let mut aborted = 0;
+        for (task_name, handle) in self.task_handles.values() {
+            if task_name == &name {
+                handle.abort();
+                aborted += 1;
+            }
+        }
+        match aborted {
+            0 => log_warn!(self.logger, "No running task named {:?}", name),
+            n => log_warn!(self.logger, "{} task(s) {:?} aborted", n, name),
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn service_start_scanning(&mut self, mut scanner_shared_data: ScannerDriver) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : START SCANNING -----");
+
+        if scanner_shared_data.is_already_running().await {
+            log_warn!(self.logger, "Scanning already running");
+            return;
+        }
+
+        //
+        // Scanners are blocking, they will run in their own threads
+        let mut jobs: Vec<ScanJob> = Vec::new();
+        for ph in self.plugin_manager.handlers() {
+            let label = ph.filename().display().to_string();
+            jobs.push((label, Box::new(move || ph.scan())));
+        }
+
+        #[cfg(feature = "built-in-drivers")]
+        for (i, scanner) in built_in::plugin_scanners().into_iter().enumerate() {
+            let label = format!("built-in/{}", i);
+            jobs.push((label, Box::new(move || Ok(scanner.scan()))));
+        }
+
+        //
+        // A scanner still blocked in the previous session must not be called twice
+        let labels: Vec<String> = jobs.iter().map(|(label, _)| label.clone()).collect();
+        let busy_scanners = self.busy_scanners.lock().unwrap().clone();
+        let busy: Vec<String> = labels
+            .iter()
+            .filter(|label| busy_scanners.contains(*label))
+            .cloned()
+            .collect();
+        jobs.retain(|(label, _)| !busy.contains(label));
+        scanner_shared_data.start_session(labels).await;
+        for label in busy {
+            log_warn!(self.logger, "Scanner '{}' still running, skipped", label);
+            scanner_shared_data
+                .join_scan(
+                    label,
+                    Err(Error::Generic("Previous scan still running".to_string())),
+                )
+                .await;
+        }
+
+        self.task_sender
+            .spawn_with_name(
+                "scanning",
+                Self::task_scan(
+                    self.logger.clone(),
+                    jobs,
+                    self.busy_scanners.clone(),
+                    self.config.scanner_timeout(),
+                    self.produced_orders.values().cloned().collect(),
+                    scanner_shared_data,
+                    self.request_sender.clone(),
+                )
+                .boxed(),
+            )
+            .unwrap();
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn service_instantiate_scanned(&mut self, orders: Vec<ProductionOrder>) {
+        //
+        // info
+        log_info!(self.logger, "----- SERVICE : INSTANTIATE SCANNED -----");
+
+        for po in orders {
+            if self.produced_orders.contains_key(&po.name) {
+                log_warn!(self.logger, "Instance '{}' is already produced", po.name);
+                continue;
+            }
+            self.scanned_instances.insert(po.name.clone());
+            self.service_produce_device(po).await;
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// Forward the notifications of the local runtime to the pipeline
+    ///
+    /// The notification group of the core can only be pulled, it gives no way to wait
+    /// for a new notification, so it is checked at a short period. The forward waits
+    /// while the pipeline is full, the notifications stay in the group meanwhile.
+    ///
+    async fn task_forward_notifications(
+        group: Arc<std::sync::Mutex<NotificationGroup>>,
+        notifications: NotificationSender,
+    ) -> TaskResult {
+        loop {
+            let pulled = group.lock().unwrap().pull();
+            if pulled.is_empty() {
+                tokio::time::sleep(LOCAL_NOTIFICATION_PERIOD).await;
+                continue;
+            }
+            if !notifications.send(pulled).await {
+                return Ok(());
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_process_notifications(
+        mut info_pack: InfoPack,
+        receiver: NotificationReceiver,
     ) -> TaskResult {
+        while let Some(notifications) = receiver.next_batch().await {
+            info_pack.process_notifications(notifications);
+        }
+        Ok(())
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_watch_device_tree(
+        tree_sources: Arc<std::sync::Mutex<Vec<PathBuf>>>,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        //
+        // The main file is always watched, even if the last load failed. It is found again
+        // at each check, a tree file created in an other format can take its place.
+        let watched_paths = || -> Result<(PathBuf, Vec<PathBuf>), Error> {
+            let tree_path = default_tree_file()?;
+            let mut paths = tree_sources.lock().unwrap().clone();
+            if !paths.contains(&tree_path) {
+                paths.insert(0, tree_path.clone());
+            }
+            Ok((tree_path, paths))
+        };
+        let stamp = |paths: &Vec<PathBuf>| {
+            paths
+                .iter()
+                .map(|path| {
+                    std::fs::metadata(path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (mut last_tree_path, mut last_paths) = watched_paths()?;
+        let mut last_stamp = stamp(&last_paths);
         loop {
-            n_notifier.notified().await;
-            let mut lock = n_notifications.lock().await;
-            let copy_notifs = lock.clone();
-            lock.clear();
-            drop(lock);
-            info_pack.process_notifications(copy_notifs);
+            tokio::time::sleep(TREE_WATCH_PERIOD).await;
+            let (tree_path, paths) = watched_paths()?;
+            let new_stamp = stamp(&paths);
+
+            //
+            // New sources come from a reload that already read them
+            if tree_path != last_tree_path || (paths == last_paths && new_stamp != last_stamp) {
+                post_request(&request_sender, ServiceRequest::ReloadDeviceTree).await?;
+            }
+            last_tree_path = tree_path;
+            last_paths = paths;
+            last_stamp = new_stamp;
         }
     }
 
+    /// -------------------------------------------------------------
+    ///
+    /// Start a new host process for a crashed plugin and give the result to the platform
+    ///
+    async fn task_restart_plugin_host(
+        ph: Arc<PluginHandler>,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        let host = ph.clone();
+        let result = run_blocking(move || host.restart())
+            .await
+            .map_err(|e| format!("{:?}", e));
+        post_request(
+            &request_sender,
+            ServiceRequest::PluginHostRestarted(ph.filename().clone(), result),
+        )
+        .await
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_scan(
+        logger: Logger,
+        jobs: Vec<ScanJob>,
+        busy_scanners: Arc<std::sync::Mutex<HashSet<String>>>,
+        scan_timeout: Duration,
+        produced: Vec<ProductionOrder>,
+        mut driver: ScannerDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        //
+        // Run all the scanners concurrently
+        let mut scans = JoinSet::new();
+        for (label, job) in jobs {
+            let busy_scanners = busy_scanners.clone();
+            scans.spawn(async move {
+                //
+                // The blocking call goes on after a timeout or a cancel, the scanner
+                // stays busy until it really returns
+                busy_scanners.lock().unwrap().insert(label.clone());
+                let label_2 = label.clone();
+                let scanning = tokio::task::spawn_blocking(move || {
+                    let result = job();
+                    busy_scanners.lock().unwrap().remove(&label_2);
+                    result
+                });
+                let result = match tokio::time::timeout(scan_timeout, scanning).await {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(e)) => Err(Error::Generic(format!("Scanner crashed ({:?})", e))),
+                    Err(_) => Err(Error::Generic(format!(
+                        "Scanner timeout after {:?}",
+                        scan_timeout
+                    ))),
+                };
+                (label, result)
+            });
+        }
+
+        //
+        // Collect results until the end or the user cancel
+        let cancel_notifier = driver.cancel_notifier.clone();
+        let mut cancelled = false;
+        loop {
+            tokio::select! {
+                joined = scans.join_next() => {
+                    match joined {
+                        Some(Ok((label, result))) => {
+                            log_info!(logger, "Scanner '{}' joined: {:?}", label, result);
+                            driver.join_scan(label, result).await;
+                        }
+                        Some(Err(e)) => {
+                            logger.error(format!("Scan join error: {:?}", e));
+                        }
+                        None => break,
+                    }
+                },
+                _ = cancel_notifier.notified() => {
+                    log_warn!(logger, "Scanning cancelled");
+                    scans.abort_all();
+                    cancelled = true;
+                    break;
+                }
+            }
+        }
+
+        driver.finish_session(cancelled, produced).await;
+        post_request(&request_sender, ServiceRequest::ScanFinished).await?;
+        Ok(())
+    }
+
     /// -------------------------------------------------------------
     ///
     async fn task_process_scanner(
@@ -762,10 +2712,255 @@ impl Platform {
         loop {
             driver.request_notifier.notified().await;
             if !driver.is_already_running().await {
-                request_sender
-                    .try_send(ServiceRequest::StartScanning)
-                    .unwrap();
+                post_request(&request_sender, ServiceRequest::StartScanning).await?;
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_auto_scan(
+        period: Duration,
+        driver: ScannerDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        loop {
+            tokio::time::sleep(period).await;
+            if !driver.is_already_running().await {
+                post_request(&request_sender, ServiceRequest::StartScanning).await?;
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_process_instantiation(
+        driver: ScannerDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        loop {
+            driver.instantiate_notifier.notified().await;
+            let orders = driver.take_instantiation_requests().await;
+            if !orders.is_empty() {
+                post_request(&request_sender, ServiceRequest::InstantiateScanned(orders)).await?;
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_process_estop(
+        driver: EstopDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        loop {
+            driver.request_notifier.notified().await;
+            for command in driver.take_requests().await {
+                let request = match command {
+                    EstopCommand::Trigger => ServiceRequest::TriggerEstop,
+                    EstopCommand::Rearm => ServiceRequest::RearmEstop,
+                };
+                post_request(&request_sender, request).await?;
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_process_tasks(
+        driver: TasksDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        loop {
+            driver.request_notifier.notified().await;
+            for name in driver.take_requests().await {
+                post_request(&request_sender, ServiceRequest::AbortTask(name)).await?;
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_process_tree(
+        driver: TreeDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        loop {
+            driver.request_notifier.notified().await;
+            post_request(&request_sender, ServiceRequest::ReloadDeviceTree).await?;
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    async fn task_process_plugins(
+        driver: PluginsDriver,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        loop {
+            driver.request_notifier.notified().await;
+            for command in driver.take_requests().await {
+                let request = match command {
+                    PluginCommand::Load(filename) => ServiceRequest::LoadPlugin(filename),
+                    PluginCommand::Unload(filename) => ServiceRequest::UnloadPlugin(filename),
+                    PluginCommand::Reload(filename) => ServiceRequest::ReloadPlugin(filename),
+                };
+                post_request(&request_sender, request).await?;
+            }
+        }
+    }
+
+    /// -------------------------------------------------------------
+    ///
+    /// A file is managed once its size and modification time are the same on
+    /// two consecutive checks, to avoid loading a plugin that is being copied
+    ///
+    async fn task_watch_plugins_dirs(
+        selection: PluginSelection,
+        request_sender: Sender<ServiceRequest>,
+    ) -> TaskResult {
+        let dyn_lib_ext =
+            env::system_dyn_lib_extension().map_err(|e| Error::Generic(format!("{:?}", e)))?;
+        let list_plugin_files = || {
+            let mut files = HashMap::new();
+            for path in selection.plugin_files(&dyn_lib_ext) {
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    files.insert(path, (metadata.modified().ok(), metadata.len()));
+                }
+            }
+            files
+        };
+
+        let mut known = list_plugin_files();
+        let mut previous = known.clone();
+        loop {
+            tokio::time::sleep(PLUGINS_WATCH_PERIOD).await;
+            let current = list_plugin_files();
+
+            for (path, stamp) in current.iter() {
+                if previous.get(path) != Some(stamp) {
+                    continue;
+                }
+                let request = match known.get(path) {
+                    None => ServiceRequest::LoadPlugin(path.clone()),
+                    Some(known_stamp) if known_stamp != stamp => {
+                        ServiceRequest::ReloadPlugin(path.clone())
+                    }
+                    Some(_) => continue,
+                };
+                known.insert(path.clone(), stamp.clone());
+                post_request(&request_sender, request).await?;
+            }
+
+            let removed: Vec<PathBuf> = known
+                .keys()
+                .filter(|path| !current.contains_key(*path))
+                .cloned()
+                .collect();
+            for path in removed {
+                known.remove(&path);
+                post_request(&request_sender, ServiceRequest::UnloadPlugin(path)).await?;
             }
+
+            previous = current;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_tree::DeviceEntry;
+    use serde_json::json;
+
+    fn order(name: &str, settings: JsonValue) -> ProductionOrder {
+        serde_json::from_value(json!({ "name": name, "dref": "manuf.model", "settings": settings }))
+            .unwrap()
+    }
+
+    fn reloaded(platform: &mut Platform, po: ProductionOrder) -> (DeviceTree, DeviceTreeDiff) {
+        let mut dt = DeviceTree::default();
+        dt.devices.push(DeviceEntry::from(po.clone()));
+        let diff = DeviceTreeDiff::new(&vec![po], &platform.produced_orders);
+        (dt, diff)
+    }
+
+    #[tokio::test]
+    async fn changed_device_is_destroyed_before_its_new_order_is_scheduled() {
+        let mut platform = Platform::new(false, false, false, false, None);
+        platform
+            .produced_orders
+            .insert("psu".to_string(), order("psu", json!({ "port": "a" })));
+
+        let (dt, diff) = reloaded(&mut platform, order("psu", json!({ "port": "b" })));
+        assert_eq!(diff.changed.len(), 1);
+        platform.apply_tree_diff(&dt, diff).await;
+
+        //
+        // The new order waits for the cleaning of the topics of the previous instance
+        assert!(!platform.produced_orders.contains_key("psu"));
+        assert!(platform.is_cleaning("psu"));
+        assert_eq!(platform.pending_productions.len(), 1);
+        assert_eq!(
+            platform.pending_productions[0].order.settings,
+            Some(json!({ "port": "b" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn changed_device_that_cannot_be_destroyed_keeps_running() {
+        let mut platform = Platform::new(false, false, false, false, None);
+        platform
+            .produced_orders
+            .insert("psu".to_string(), order("psu", json!({ "port": "a" })));
+        platform
+            .produced_sources
+            .insert("psu".to_string(), ProducerSource::BuiltIn);
+
+        let (dt, diff) = reloaded(&mut platform, order("psu", json!({ "port": "b" })));
+        platform.apply_tree_diff(&dt, diff).await;
+
+        assert_eq!(
+            platform.produced_orders["psu"].settings,
+            Some(json!({ "port": "a" }))
+        );
+        assert!(platform.pending_productions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn estop_holds_the_commands_of_running_instances_until_the_rearm() {
+        let mut platform = Platform::new(false, false, false, false, None);
+        platform
+            .produced_orders
+            .insert("psu".to_string(), order("psu", json!({})));
+        platform.production_sequence.push("psu".to_string());
+
+        platform.service_trigger_estop().await;
+        assert!(platform.produced_orders.contains_key("psu"));
+        assert!(platform
+            .command_gate
+            .refusal("pza/psu/voltage/cmd", b"1")
+            .is_some());
+        let status = platform.estop_driver.into_json_value().await.unwrap();
+        assert_eq!(status["engaged"], true);
+        assert_eq!(status["devices"]["psu"]["outcome"], "held");
+
+        //
+        // Nothing is produced meanwhile
+        assert_eq!(
+            platform
+                .service_produce_device(order("dmm", json!({})))
+                .await,
+            ProductionOutcome::EmergencyStop
+        );
+
+        platform.service_rearm_estop().await;
+        assert!(platform
+            .command_gate
+            .refusal("pza/psu/voltage/cmd", b"1")
+            .is_none());
+        let status = platform.estop_driver.into_json_value().await.unwrap();
+        assert_eq!(status["engaged"], false);
+        assert_eq!(status["devices"]["psu"]["outcome"], "held");
+    }
+}
@@ -0,0 +1,642 @@
+use crate::notification_pipeline::NotificationSender;
+use crate::plugins_manager::{PluginHandler, PluginMetadata};
+use panduza_platform_core::Error;
+use panduza_platform_core::Logger;
+use panduza_platform_core::Notification;
+use panduza_platform_core::ProductionOrder;
+use panduza_platform_core::Store;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+///
+/// Maximum time allowed to a host to load its plugin and connect back to the platform
+///
+static HOST_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// Period between two checks of the host connection during its start
+///
+static HOST_START_POLL_PERIOD: Duration = Duration::from_millis(20);
+
+///
+/// Maximum time allowed to a host to answer a request, a host that does not answer
+/// in time is considered crashed and restarted
+///
+static HOST_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// Same as HOST_REQUEST_TIMEOUT for scans, which probe the hardware
+///
+static HOST_SCAN_TIMEOUT: Duration = Duration::from_secs(60);
+
+///
+/// Period between two pulls of the plugin by its host while it has no notification,
+/// the plugin interface cannot notify, the pull stays inside the host process
+///
+static HOST_NOTIFICATION_PERIOD: Duration = Duration::from_millis(10);
+
+///
+/// Requests sent by the platform to a plugin host, one json object per line
+///
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HostRequest {
+    Produce(ProductionOrder),
+    Unproduce(ProductionOrder),
+    Scan,
+}
+
+///
+/// Responses of a plugin host, one json object per line
+///
+/// 'Ready' is sent once the plugin is loaded, before any request. 'Notifications' are
+/// pushed by the host on a second connection, without request.
+///
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HostResponse {
+    Ready(HostReady),
+    Produced(bool),
+    Unproduced(bool),
+    Scanned(Vec<ProductionOrder>),
+    Notifications(Vec<Notification>),
+    Failure(String),
+}
+
+///
+/// Plugin loaded by a host
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HostReady {
+    pub metadata: PluginMetadata,
+    pub store: Store,
+    /// True if the plugin exports 'plugin_unproduce'
+    #[serde(default)]
+    pub can_unproduce: bool,
+}
+
+///
+/// Line based connection with the host
+///
+struct HostChannel {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl HostChannel {
+    ///
+    /// Wrap a connected stream
+    ///
+    fn new(stream: TcpStream) -> Result<HostChannel, Error> {
+        let writer = stream
+            .try_clone()
+            .map_err(|e| Error::PluginError(format!("Plugin host stream error ({:?})", e)))?;
+        Ok(HostChannel {
+            reader: BufReader::new(stream),
+            writer: writer,
+        })
+    }
+
+    ///
+    /// Limit the time spent waiting for the other side, None to wait forever
+    ///
+    fn set_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.writer
+            .set_read_timeout(timeout)
+            .and_then(|_| self.writer.set_write_timeout(timeout))
+            .map_err(|e| Error::PluginError(format!("Plugin host stream error ({:?})", e)))
+    }
+
+    ///
+    /// Send a message on a single line
+    ///
+    fn send<T: Serialize>(&mut self, message: &T) -> Result<(), Error> {
+        let mut line = serde_json::to_string(message)
+            .map_err(|e| Error::SerializeFailure(format!("{:?}", e)))?;
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| Error::PluginError(format!("Plugin host connection lost ({:?})", e)))
+    }
+
+    ///
+    /// Receive the next message, None if the other side closed the connection
+    ///
+    fn receive<T: for<'de> Deserialize<'de>>(&mut self) -> Result<Option<T>, Error> {
+        let mut line = String::new();
+        let size = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                    Error::PluginError("Plugin host does not answer (timeout)".to_string())
+                }
+                _ => Error::PluginError(format!("Plugin host connection lost ({:?})", e)),
+            })?;
+        if size == 0 {
+            return Ok(None);
+        }
+        serde_json::from_str(&line).map(Some).map_err(|e| {
+            Error::InvalidArgument(format!("Invalid plugin host message: {:?} {:?}", e, line))
+        })
+    }
+}
+
+/// Plugin loaded in a child process of the platform
+///
+/// The child is the platform executable started in host mode, a crash of the
+/// plugin only kills the child. The host is restarted by the platform.
+///
+/// The host opens two connections: one for the requests of the platform and their
+/// responses, one on which it pushes the notifications of the plugin. A thread of the
+/// platform reads them into the notification pipeline, it stops reading while the
+/// pipeline is full and the host then stops pulling its plugin.
+///
+pub struct PluginHost {
+    ///
+    /// Plugin file loaded by the host
+    filename: PathBuf,
+    ///
+    /// Log flags given to the plugin
+    enable_stdout: bool,
+    debug: bool,
+    trace: bool,
+    ///
+    /// Where the notifications pushed by the host go
+    notifications: NotificationSender,
+    ///
+    /// Host process
+    child: Mutex<Child>,
+    ///
+    /// Connection with the host, locked for the whole request
+    channel: Mutex<HostChannel>,
+    ///
+    /// Set when the connection failed
+    broken: AtomicBool,
+    ///
+    /// True if the plugin can destroy its instances
+    can_unproduce: AtomicBool,
+}
+
+impl PluginHost {
+    ///
+    /// Start a host for this plugin and wait for it to be ready
+    ///
+    pub fn start(
+        filename: PathBuf,
+        notifications: NotificationSender,
+        enable_stdout: bool,
+        debug: bool,
+        trace: bool,
+    ) -> Result<(PluginHost, PluginMetadata, Store), Error> {
+        let (child, channel, ready) =
+            Self::spawn(&filename, &notifications, enable_stdout, debug, trace)?;
+        let host = PluginHost {
+            filename: filename,
+            enable_stdout: enable_stdout,
+            debug: debug,
+            trace: trace,
+            notifications: notifications,
+            child: Mutex::new(child),
+            channel: Mutex::new(channel),
+            broken: AtomicBool::new(false),
+            can_unproduce: AtomicBool::new(ready.can_unproduce),
+        };
+        Ok((host, ready.metadata, ready.store))
+    }
+
+    ///
+    /// True if the plugin loaded by the host can destroy its instances
+    ///
+    pub fn can_unproduce(&self) -> bool {
+        self.can_unproduce.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Replace a crashed host by a new one
+    ///
+    pub fn restart(&self) -> Result<(), Error> {
+        let mut child = self.child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+
+        let (new_child, new_channel, ready) = Self::spawn(
+            &self.filename,
+            &self.notifications,
+            self.enable_stdout,
+            self.debug,
+            self.trace,
+        )?;
+        *self.channel.lock().unwrap() = new_channel;
+        *child = new_child;
+        self.can_unproduce
+            .store(ready.can_unproduce, Ordering::Relaxed);
+        self.broken.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    ///
+    /// True if the host process died or its connection failed
+    ///
+    pub fn has_crashed(&self) -> bool {
+        if self.broken.load(Ordering::Relaxed) {
+            return true;
+        }
+        !matches!(self.child.lock().unwrap().try_wait(), Ok(None))
+    }
+
+    ///
+    /// Send a request and wait for its response
+    ///
+    /// The call blocks the thread until the response or the request timeout,
+    /// it must be run outside of the async runtime threads.
+    ///
+    pub fn request(&self, request: HostRequest) -> Result<HostResponse, Error> {
+        let mut channel = self.channel.lock().unwrap();
+        self.exchange(&mut channel, request)
+    }
+
+    ///
+    ///
+    ///
+    fn exchange(
+        &self,
+        channel: &mut HostChannel,
+        request: HostRequest,
+    ) -> Result<HostResponse, Error> {
+        if self.broken.load(Ordering::Relaxed) {
+            return Err(Error::PluginError(format!(
+                "Plugin host of [{:?}] is down",
+                self.filename
+            )));
+        }
+        let timeout = match request {
+            HostRequest::Scan => HOST_SCAN_TIMEOUT,
+            _ => HOST_REQUEST_TIMEOUT,
+        };
+        let response = channel
+            .set_timeout(Some(timeout))
+            .and_then(|_| channel.send(&request))
+            .and_then(|_| channel.receive());
+        match response {
+            Ok(Some(HostResponse::Failure(message))) => Err(Error::PluginError(message)),
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => {
+                self.broken.store(true, Ordering::Relaxed);
+                Err(Error::PluginError(format!(
+                    "Plugin host of [{:?}] closed the connection",
+                    self.filename
+                )))
+            }
+            //
+            // A timeout leaves the response of the request in the stream, the host
+            // cannot be used anymore and is restarted as a crashed one
+            Err(e) => {
+                self.broken.store(true, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    ///
+    /// Start the host process, wait for its 'Ready' message and read its notifications
+    ///
+    fn spawn(
+        filename: &PathBuf,
+        notifications: &NotificationSender,
+        enable_stdout: bool,
+        debug: bool,
+        trace: bool,
+    ) -> Result<(Child, HostChannel, HostReady), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .map_err(|e| Error::PluginError(format!("Unable to open host listener ({:?})", e)))?;
+        let port = listener
+            .local_addr()
+            .map_err(|e| Error::PluginError(format!("Unable to open host listener ({:?})", e)))?
+            .port();
+
+        //
+        // The host is the platform itself started in host mode
+        let exe = std::env::current_exe()
+            .map_err(|e| Error::PluginError(format!("Unable to find platform exe ({:?})", e)))?;
+        let mut command = Command::new(exe);
+        command
+            .arg("--plugin-host")
+            .arg(filename)
+            .arg("--plugin-host-port")
+            .arg(port.to_string())
+            .stdin(Stdio::null());
+        if !enable_stdout {
+            command.arg("--quiet-log");
+        }
+        if debug {
+            command.arg("--debug-log");
+        }
+        if trace {
+            command.arg("--trace-log");
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| Error::PluginError(format!("Unable to start plugin host ({:?})", e)))?;
+
+        match Self::connect(&listener, &mut child) {
+            Ok((channel, notification_channel, ready)) => {
+                //
+                // The thread ends with the connection, when the host stops
+                let notifications = notifications.clone();
+                let filename = filename.clone();
+                std::thread::spawn(move || {
+                    receive_notifications(notification_channel, notifications, filename)
+                });
+                Ok((child, channel, ready))
+            }
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+
+    ///
+    /// Accept the connections of the host and read its 'Ready' message
+    ///
+    /// Return the request channel then the notification channel.
+    ///
+    fn connect(
+        listener: &TcpListener,
+        child: &mut Child,
+    ) -> Result<(HostChannel, HostChannel, HostReady), Error> {
+        let listener_error =
+            |e: std::io::Error| Error::PluginError(format!("Plugin host listener error ({:?})", e));
+        listener.set_nonblocking(true).map_err(listener_error)?;
+
+        let start = Instant::now();
+        let mut channel = HostChannel::new(Self::accept(listener, child, start)?)?;
+
+        //
+        // Requests set their own timeout before being sent
+        channel.set_timeout(Some(HOST_START_TIMEOUT))?;
+        let ready = match channel.receive::<HostResponse>()? {
+            Some(HostResponse::Ready(ready)) => ready,
+            Some(HostResponse::Failure(message)) => return Err(Error::PluginError(message)),
+            Some(other) => {
+                return Err(Error::PluginError(format!(
+                    "Unexpected plugin host message {:?}",
+                    other
+                )))
+            }
+            None => {
+                return Err(Error::PluginError(
+                    "Plugin host closed the connection during its start".to_string(),
+                ))
+            }
+        };
+
+        //
+        // Notifications come whenever the plugin has some
+        let notification_channel = HostChannel::new(Self::accept(listener, child, start)?)?;
+        notification_channel.set_timeout(None)?;
+        Ok((channel, notification_channel, ready))
+    }
+
+    ///
+    /// Wait for the next connection of the host, fail if it exits or does not connect in time
+    ///
+    fn accept(
+        listener: &TcpListener,
+        child: &mut Child,
+        start: Instant,
+    ) -> Result<TcpStream, Error> {
+        let listener_error =
+            |e: std::io::Error| Error::PluginError(format!("Plugin host listener error ({:?})", e));
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if let Ok(Some(status)) = child.try_wait() {
+                        return Err(Error::PluginError(format!(
+                            "Plugin host exited before connecting ({})",
+                            status
+                        )));
+                    }
+                    if start.elapsed() > HOST_START_TIMEOUT {
+                        return Err(Error::PluginError("Plugin host start timeout".to_string()));
+                    }
+                    std::thread::sleep(HOST_START_POLL_PERIOD);
+                }
+                Err(e) => return Err(listener_error(e)),
+            }
+        };
+        stream.set_nonblocking(false).map_err(listener_error)?;
+        Ok(stream)
+    }
+}
+
+impl Drop for PluginHost {
+    fn drop(&mut self) {
+        if let Ok(mut child) = self.child.lock() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Read the notifications pushed by a host into the pipeline, until the host stops
+///
+/// The read waits while the pipeline is full, the host is then blocked on its writes.
+///
+fn receive_notifications(
+    mut channel: HostChannel,
+    notifications: NotificationSender,
+    filename: PathBuf,
+) {
+    let logger = Logger::new_for_platform();
+    loop {
+        match channel.receive::<HostResponse>() {
+            Ok(Some(HostResponse::Notifications(pushed))) => {
+                if !notifications.blocking_send(pushed) {
+                    return;
+                }
+            }
+            Ok(Some(HostResponse::Failure(message))) => {
+                logger.error(format!(
+                    "Plugin host of [{:?}] unable to pull notifications: {}",
+                    filename, message
+                ));
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Pull the plugin and push its notifications to the platform, until the platform
+/// closes the connection
+///
+fn push_notifications(handler: Arc<PluginHandler>, mut channel: HostChannel) -> Result<(), Error> {
+    loop {
+        let message = match handler.pull_notifications() {
+            Ok(notifications) if !notifications.is_empty() => {
+                HostResponse::Notifications(notifications)
+            }
+            Ok(_) => {
+                std::thread::sleep(HOST_NOTIFICATION_PERIOD);
+                continue;
+            }
+            //
+            // Reported to the platform, the next pull may succeed
+            Err(e) => {
+                std::thread::sleep(HOST_NOTIFICATION_PERIOD);
+                HostResponse::Failure(format!("{:?}", e))
+            }
+        };
+        channel.send(&message)?;
+    }
+}
+
+/// Host mode entry point, executed in the child process
+///
+/// Load the plugin, connect to the platform and serve its requests until
+/// the platform closes the connection. The notifications of the plugin are pushed
+/// on a second connection by an other thread.
+///
+pub fn run(
+    filename: PathBuf,
+    port: u16,
+    enable_stdout: bool,
+    debug: bool,
+    trace: bool,
+) -> Result<(), Error> {
+    let connect = || {
+        TcpStream::connect(("127.0.0.1", port))
+            .map_err(|e| Error::PluginError(format!("Unable to connect to the platform ({:?})", e)))
+    };
+    let mut channel = HostChannel::new(connect()?)?;
+
+    //
+    // Load errors are reported to the platform
+    let handler = match PluginHandler::from_filename(filename, enable_stdout, debug, trace) {
+        Ok(handler) => handler,
+        Err(e) => {
+            channel.send(&HostResponse::Failure(format!("{:?}", e)))?;
+            return Err(e);
+        }
+    };
+    channel.send(&HostResponse::Ready(HostReady {
+        metadata: handler.metadata().clone(),
+        store: handler.store().clone(),
+        can_unproduce: handler.can_unproduce(),
+    }))?;
+
+    //
+    // The platform accepts the notification connection after the 'Ready' message
+    let handler = Arc::new(handler);
+    let notification_channel = HostChannel::new(connect()?)?;
+    let pusher = handler.clone();
+    std::thread::spawn(move || push_notifications(pusher, notification_channel));
+
+    //
+    // Serve requests one by one
+    while let Some(request) = channel.receive::<HostRequest>()? {
+        let response = match request {
+            HostRequest::Produce(order) => handler.produce(&order).map(HostResponse::Produced),
+            HostRequest::Unproduce(order) => {
+                handler.unproduce(&order).map(HostResponse::Unproduced)
+            }
+            HostRequest::Scan => handler.scan().map(HostResponse::Scanned),
+        };
+        channel.send(&response.unwrap_or_else(|e| HostResponse::Failure(format!("{:?}", e))))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Both ends of a local connection
+    ///
+    fn channels() -> (HostChannel, HostChannel) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let platform = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (host, _) = listener.accept().unwrap();
+        (
+            HostChannel::new(platform).unwrap(),
+            HostChannel::new(host).unwrap(),
+        )
+    }
+
+    #[test]
+    fn messages_are_exchanged_one_per_line() {
+        let (mut platform, mut host) = channels();
+        platform.send(&HostRequest::Scan).unwrap();
+        platform.send(&HostRequest::Scan).unwrap();
+        assert!(matches!(
+            host.receive::<HostRequest>().unwrap(),
+            Some(HostRequest::Scan)
+        ));
+        assert!(matches!(
+            host.receive::<HostRequest>().unwrap(),
+            Some(HostRequest::Scan)
+        ));
+
+        host.send(&HostResponse::Failure("no".to_string())).unwrap();
+        assert!(matches!(
+            platform.receive::<HostResponse>().unwrap(),
+            Some(HostResponse::Failure(message)) if message == "no"
+        ));
+    }
+
+    #[test]
+    fn silent_host_times_out_and_closed_host_ends() {
+        let (mut platform, host) = channels();
+        platform
+            .set_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let error = platform.receive::<HostResponse>().unwrap_err();
+        assert!(format!("{:?}", error).contains("timeout"));
+
+        drop(host);
+        assert!(platform.receive::<HostResponse>().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn pushed_notifications_reach_the_pipeline() {
+        let (platform, mut host) = channels();
+        let (sender, receiver) = crate::notification_pipeline::pipeline(8);
+        let reader = std::thread::spawn(move || {
+            receive_notifications(platform, sender, PathBuf::from("libpza_a.so"))
+        });
+
+        let notification = Notification::State(panduza_platform_core::StateNotification {
+            topic: "pza/psu".to_string(),
+            state: panduza_platform_core::instance::State::Running,
+        });
+        host.send(&HostResponse::Notifications(vec![notification.clone()]))
+            .unwrap();
+        //
+        // A failed pull is only logged
+        host.send(&HostResponse::Failure("null pointer".to_string()))
+            .unwrap();
+        host.send(&HostResponse::Notifications(vec![notification]))
+            .unwrap();
+        drop(host);
+
+        let mut count = 0;
+        while count < 2 {
+            count += receiver.next_batch().await.unwrap().len();
+        }
+        tokio::task::spawn_blocking(move || reader.join().unwrap())
+            .await
+            .unwrap();
+    }
+}
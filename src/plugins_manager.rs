@@ -1,3 +1,10 @@
+use crate::config::{PluginsConfig, ProducerPrecedence};
+use crate::notification_pipeline::NotificationSender;
+use crate::plugin_host::{HostRequest, HostResponse, PluginHost};
+use crate::sys_info::PLATFORM_CORE_VERSION;
+use crate::underscore_device::plugins::data::{
+    store_producers, PluginInfo, PluginLoadError, ProducerConflict,
+};
 use panduza_platform_core::env;
 use panduza_platform_core::Error;
 use panduza_platform_core::Logger;
@@ -5,24 +12,114 @@ use panduza_platform_core::Notification;
 use panduza_platform_core::Plugin;
 use panduza_platform_core::ProductionOrder;
 use panduza_platform_core::Store;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_char;
 use std::ffi::CStr;
 use std::ffi::OsStr;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+///
+/// Version of the C interface between the platform and the plugins
+///
+/// Plugins export 'plugin_abi_version' returning this value, it must be
+/// incremented each time the 'Plugin' structure or its functions change.
+/// Plugins that do not export it are loaded as legacy plugins, with a warning.
+///
+/// The 'Plugin' structure belongs to panduza-platform-core, so this value belongs there
+/// too for the plugins to export it. The pinned core tag does not provide it yet,
+/// until then it must be kept equal to the one written in the plugins.
+///
+pub static PLUGIN_ABI_VERSION: u32 = 1;
+
+///
+/// Run a blocking call of the plugins outside of the async runtime threads
+///
+/// Plugin functions and plugin host requests block until they complete.
+///
+pub async fn run_blocking<T, F>(call: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(call)
+        .await
+        .map_err(|e| Error::PluginError(format!("Plugin call aborted ({:?})", e)))?
+}
+
+///
+/// True if a plugin built with this core version can be loaded by the platform
+///
+/// Core versions follow semver, before 1.0 the minor version breaks the compatibility.
+///
+fn core_version_compatible(plugin_core_version: &str, platform_core_version: &str) -> bool {
+    let significant = |version: &str| -> Vec<String> {
+        let numbers: Vec<String> = version
+            .trim_start_matches('v')
+            .split('.')
+            .map(|n| n.to_string())
+            .collect();
+        match numbers.first().map(|major| major.as_str()) {
+            Some("0") => numbers.into_iter().take(2).collect(),
+            _ => numbers.into_iter().take(1).collect(),
+        }
+    };
+    significant(plugin_core_version) == significant(platform_core_version)
+}
+
+///
+/// Informations exported by the plugin through 'plugin_metadata'
+///
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginMetadata {
+    pub name: String,
+    pub version: String,
+    pub core_version: String,
+    pub author: String,
+}
+
+///
+/// Where the plugin code is executed
+///
+enum PluginBackend {
+    ///
+    /// Binary object loaded inside the platform process
+    Local {
+        ///
+        /// Object must be kept alive as long as the interface live
+        _object: libloading::Library,
+        ///
+        /// C interface of the plugin
+        interface: Plugin,
+        ///
+        /// Optional entry point to destroy an instance produced by the plugin
+        unproduce: Option<extern "C" fn(order: *const c_char) -> u32>,
+    },
+    ///
+    /// Binary object loaded inside a child process
+    Isolated(PluginHost),
+}
 
 ///
 /// Gather all the objects required to make the plugin work
 ///
 pub struct PluginHandler {
     ///
-    /// Binary object loaded inside process
-    _object: libloading::Library,
-    ///
-    /// C interface of the plugin
-    interface: Plugin,
+    /// File from which the plugin has been loaded
+    filename: PathBuf,
     ///
     ///
     store: Store,
+    ///
+    /// Informations about the plugin
+    metadata: PluginMetadata,
+    ///
+    ///
+    backend: PluginBackend,
 }
 
 impl PluginHandler {
@@ -45,6 +142,67 @@ impl PluginHandler {
                 ))
             })?;
 
+            //
+            // The entry point must not be called if the interface is not the expected one.
+            // Plugins built before the ABI version existed do not export it, they are
+            // loaded as before and only checked through their core version.
+            match object.get::<extern "C" fn() -> u32>(b"plugin_abi_version") {
+                Ok(plugin_abi_version) => {
+                    let abi_version = plugin_abi_version();
+                    if abi_version != PLUGIN_ABI_VERSION {
+                        return Err(Error::PluginError(format!(
+                            "Plugin [{:?}] ABI version {} is not compatible with the platform ABI version {}",
+                            filename, abi_version, PLUGIN_ABI_VERSION
+                        )));
+                    }
+                }
+                Err(_) => {
+                    Logger::new_for_platform().warn(format!(
+                        "Plugin [{:?}] does not export its ABI version, loaded as a legacy plugin",
+                        filename
+                    ));
+                }
+            }
+
+            //
+            // Metadata are optional, the file name is used when missing
+            let metadata = match object.get::<extern "C" fn() -> *const c_char>(b"plugin_metadata")
+            {
+                Ok(plugin_metadata) => {
+                    let metadata_as_ptr = plugin_metadata();
+                    if metadata_as_ptr.is_null() {
+                        return Err(Error::InvalidArgument("Null C string pointer".to_string()));
+                    }
+                    let str = CStr::from_ptr(metadata_as_ptr).to_str().map_err(|e| {
+                        Error::InvalidArgument(format!("Invalid C string: {:?}", e))
+                    })?;
+                    serde_json::from_str(str).map_err(|e| {
+                        Error::InvalidArgument(format!(
+                            "Failed to deserialize 'PluginMetadata' from JSON string: {:?} {:?}",
+                            e, str
+                        ))
+                    })?
+                }
+                Err(_) => PluginMetadata {
+                    name: filename
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    ..Default::default()
+                },
+            };
+
+            //
+            // Structures exchanged with the plugin come from the core, they must match
+            if !metadata.core_version.is_empty()
+                && !core_version_compatible(&metadata.core_version, PLATFORM_CORE_VERSION)
+            {
+                return Err(Error::PluginError(format!(
+                    "Plugin [{:?}] is built with core {} which is not compatible with the platform core {}",
+                    filename, metadata.core_version, PLATFORM_CORE_VERSION
+                )));
+            }
+
             //
             // Get plugin interface from entry point
             let plugin_entry_point: libloading::Symbol<
@@ -61,17 +219,102 @@ impl PluginHandler {
             //
             let store = interface.store_as_obj().unwrap();
 
+            //
+            // Older plugins do not export it, their instances cannot be destroyed
+            let unproduce = object
+                .get::<extern "C" fn(order: *const c_char) -> u32>(b"plugin_unproduce")
+                .ok()
+                .map(|symbol| *symbol);
+
             //
             // Compose the handler
-            // Object must be kept alive as long as the interface live
             return Ok(PluginHandler {
-                _object: object,
-                interface: interface,
+                filename: filename,
                 store: store,
+                metadata: metadata,
+                backend: PluginBackend::Local {
+                    _object: object,
+                    interface: interface,
+                    unproduce: unproduce,
+                },
             });
         }
     }
 
+    ///
+    /// Load a plugin in its own host process
+    ///
+    pub fn isolated_from_filename(
+        filename: PathBuf,
+        notifications: NotificationSender,
+        enable_stdout: bool,
+        debug: bool,
+        trace: bool,
+    ) -> Result<PluginHandler, Error> {
+        let (host, metadata, store) =
+            PluginHost::start(filename.clone(), notifications, enable_stdout, debug, trace)?;
+        Ok(PluginHandler {
+            filename: filename,
+            store: store,
+            metadata: metadata,
+            backend: PluginBackend::Isolated(host),
+        })
+    }
+
+    ///
+    /// True if the plugin runs in its own host process
+    ///
+    pub fn is_isolated(&self) -> bool {
+        matches!(self.backend, PluginBackend::Isolated(_))
+    }
+
+    ///
+    /// True if the host process of the plugin died, never for a local plugin
+    ///
+    pub fn has_crashed(&self) -> bool {
+        match &self.backend {
+            PluginBackend::Local { .. } => false,
+            PluginBackend::Isolated(host) => host.has_crashed(),
+        }
+    }
+
+    ///
+    /// True if the instances of the plugin can be destroyed before its unload
+    ///
+    pub fn can_unproduce(&self) -> bool {
+        match &self.backend {
+            PluginBackend::Local { unproduce, .. } => unproduce.is_some(),
+            PluginBackend::Isolated(host) => host.can_unproduce(),
+        }
+    }
+
+    ///
+    /// Start a new host process for the plugin
+    ///
+    pub fn restart(&self) -> Result<(), Error> {
+        match &self.backend {
+            PluginBackend::Local { .. } => Err(Error::PluginError(format!(
+                "Plugin [{:?}] is not isolated, it cannot be restarted",
+                self.filename
+            ))),
+            PluginBackend::Isolated(host) => host.restart(),
+        }
+    }
+
+    ///
+    /// File from which the plugin has been loaded
+    ///
+    pub fn filename(&self) -> &PathBuf {
+        &self.filename
+    }
+
+    ///
+    /// Informations about the plugin
+    ///
+    pub fn metadata(&self) -> &PluginMetadata {
+        &self.metadata
+    }
+
     ///
     /// Const ref on store
     ///
@@ -85,25 +328,80 @@ impl PluginHandler {
     /// Return
     /// - True if the plugin successfuly build the device
     /// - False if it cannot build it
-    /// - Error if it can but failed to do it
+    /// - Error if it can but failed to do it (non-zero code from the plugin)
     ///
     pub fn produce(&self, order: &ProductionOrder) -> Result<bool, Error> {
-        unsafe {
-            if self.store.contains(&order.dref) {
+        if !self.store.contains(&order.dref) {
+            return Ok(false);
+        }
+        match &self.backend {
+            PluginBackend::Local { interface, .. } => unsafe {
                 let order_as_c_string = order.to_c_string()?;
-                let _ret = (self.interface.produce)(order_as_c_string.as_c_str().as_ptr());
-                return Ok(true);
+                match (interface.produce)(order_as_c_string.as_c_str().as_ptr()) {
+                    0 => Ok(true),
+                    code => Err(Error::PluginError(format!(
+                        "Plugin failed to produce '{}' ({})",
+                        order.name, code
+                    ))),
+                }
+            },
+            PluginBackend::Isolated(host) => {
+                match host.request(HostRequest::Produce(order.clone()))? {
+                    HostResponse::Produced(produced) => Ok(produced),
+                    other => Err(unexpected_response(other)),
+                }
             }
         }
-        Ok(false)
     }
 
     ///
+    /// Destroy the instance produced from this order
     ///
+    /// Return
+    /// - True if the plugin destroyed the instance
+    /// - False if the order does not come from this plugin
+    /// - Error if the plugin cannot destroy it
+    ///
+    pub fn unproduce(&self, order: &ProductionOrder) -> Result<bool, Error> {
+        if !self.store.contains(&order.dref) {
+            return Ok(false);
+        }
+        if !self.can_unproduce() {
+            return Err(Error::PluginError(format!(
+                "Plugin of '{}' does not support instance destruction",
+                order.dref
+            )));
+        }
+        let unproduce = match &self.backend {
+            PluginBackend::Local { unproduce, .. } => unproduce,
+            PluginBackend::Isolated(host) => {
+                return match host.request(HostRequest::Unproduce(order.clone()))? {
+                    HostResponse::Unproduced(unproduced) => Ok(unproduced),
+                    other => Err(unexpected_response(other)),
+                };
+            }
+        };
+        let unproduce = unproduce.ok_or(Error::Wtf)?;
+        let order_as_c_string = order.to_c_string()?;
+        match unproduce(order_as_c_string.as_c_str().as_ptr()) {
+            0 => Ok(true),
+            code => Err(Error::PluginError(format!(
+                "Plugin failed to destroy '{}' ({})",
+                order.name, code
+            ))),
+        }
+    }
+
+    ///
+    /// Notifications of the plugin, always empty for an isolated one: its host pushes them
     ///
     pub fn pull_notifications(&self) -> Result<Vec<Notification>, Error> {
+        let interface = match &self.backend {
+            PluginBackend::Local { interface, .. } => interface,
+            PluginBackend::Isolated(_) => return Ok(Vec::new()),
+        };
         unsafe {
-            let notifs_as_ptr = (self.interface.pull_notifications)();
+            let notifs_as_ptr = (interface.pull_notifications)();
 
             //
             //
@@ -135,8 +433,17 @@ impl PluginHandler {
     ///
     ///
     pub fn scan(&self) -> Result<Vec<ProductionOrder>, Error> {
+        let interface = match &self.backend {
+            PluginBackend::Local { interface, .. } => interface,
+            PluginBackend::Isolated(host) => {
+                return match host.request(HostRequest::Scan)? {
+                    HostResponse::Scanned(orders) => Ok(orders),
+                    other => Err(unexpected_response(other)),
+                };
+            }
+        };
         unsafe {
-            let scan_as_ptr = (self.interface.scan)();
+            let scan_as_ptr = (interface.scan)();
 
             //
             //
@@ -167,6 +474,191 @@ impl PluginHandler {
     }
 }
 
+///
+/// Producer of a driver
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProducerSource {
+    ///
+    /// Driver built in the platform, produced by the local runtime
+    BuiltIn,
+    ///
+    /// Driver of the plugin loaded from this file
+    Plugin(PathBuf),
+}
+
+impl ProducerSource {
+    ///
+    /// Name of the producer for the user
+    ///
+    pub fn label(&self) -> String {
+        match self {
+            ProducerSource::BuiltIn => "built-in".to_string(),
+            ProducerSource::Plugin(filename) => filename.display().to_string(),
+        }
+    }
+
+    ///
+    /// True if the pin ('built-in', plugin path or plugin file name) designates this producer
+    ///
+    pub fn matches_pin(&self, pin: &str) -> bool {
+        match self {
+            ProducerSource::BuiltIn => pin == "built-in",
+            ProducerSource::Plugin(filename) => {
+                filename == Path::new(pin)
+                    || filename
+                        .file_name()
+                        .map_or(false, |name| name.to_string_lossy() == pin)
+            }
+        }
+    }
+}
+
+///
+/// Plugin files the platform is allowed to load
+///
+#[derive(Clone, Default, Debug)]
+pub struct PluginSelection {
+    ///
+    /// Directories searched in addition to the system ones
+    paths: Vec<PathBuf>,
+    ///
+    /// Files loaded whatever the patterns
+    files: Vec<PathBuf>,
+    ///
+    /// File names allowed to load from the directories, all if empty
+    allow: Vec<Regex>,
+    ///
+    /// File names never loaded from the directories
+    deny: Vec<Regex>,
+}
+
+impl PluginSelection {
+    ///
+    /// Build the selection from the plugins section of the configuration
+    ///
+    pub fn from_config(config: Option<&PluginsConfig>) -> Result<PluginSelection, Error> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(PluginSelection::default()),
+        };
+        let to_paths = |list: &Option<Vec<String>>| {
+            list.iter()
+                .flatten()
+                .map(PathBuf::from)
+                .collect::<Vec<PathBuf>>()
+        };
+        let to_regexes = |list: &Option<Vec<String>>| {
+            list.iter()
+                .flatten()
+                .map(|pattern| pattern_to_regex(pattern))
+                .collect::<Result<Vec<Regex>, Error>>()
+        };
+        Ok(PluginSelection {
+            paths: to_paths(&config.paths),
+            files: to_paths(&config.files),
+            allow: to_regexes(&config.allow)?,
+            deny: to_regexes(&config.deny)?,
+        })
+    }
+
+    ///
+    /// System directories followed by the extra ones
+    ///
+    pub fn directories(&self) -> Vec<PathBuf> {
+        let mut directories = env::system_plugins_dir_paths();
+        directories.extend(self.paths.iter().cloned());
+        directories
+    }
+
+    ///
+    /// True if the plugin found in a directory can be loaded
+    ///
+    pub fn is_allowed(&self, path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        (self.allow.is_empty() || self.allow.iter().any(|r| r.is_match(&name)))
+            && !self.deny.iter().any(|r| r.is_match(&name))
+    }
+
+    ///
+    /// All the plugin files to load, found in the directories or explicitly listed
+    ///
+    pub fn plugin_files(&self, dyn_lib_ext: &str) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for dir in self.directories() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let mut found: Vec<PathBuf> = entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.is_file()
+                        && path.extension() == Some(OsStr::new(dyn_lib_ext))
+                        && self.is_allowed(path)
+                })
+                .collect();
+            found.sort();
+            files.extend(found);
+        }
+        for file in self.files.iter() {
+            if !files.contains(file) {
+                files.push(file.clone());
+            }
+        }
+        files
+    }
+}
+
+///
+/// Producer selected among the candidates of a driver, built-in first then plugins
+///
+fn pick_producer(
+    candidates: &Vec<ProducerSource>,
+    pin: Option<&String>,
+    precedence: ProducerPrecedence,
+) -> Option<ProducerSource> {
+    //
+    // A pin that matches none of the producers is reported by merge_stores
+    if let Some(pin) = pin {
+        if let Some(pinned) = candidates.iter().find(|c| c.matches_pin(pin)) {
+            return Some(pinned.clone());
+        }
+    }
+
+    match precedence {
+        ProducerPrecedence::PreferBuiltIn => candidates.first().cloned(),
+        ProducerPrecedence::PreferPlugin => candidates
+            .iter()
+            .find(|c| **c != ProducerSource::BuiltIn)
+            .or(candidates.first())
+            .cloned(),
+    }
+}
+
+///
+/// Convert a file name pattern with '*' and '?' into a regex
+///
+fn pattern_to_regex(pattern: &str) -> Result<Regex, Error> {
+    let expression = regex::escape(pattern)
+        .replace("\\*", ".*")
+        .replace("\\?", ".");
+    Regex::new(&format!("^{}$", expression)).map_err(|e| {
+        Error::InvalidArgument(format!("Invalid plugin pattern '{}' ({:?})", pattern, e))
+    })
+}
+
+///
+/// Error for a host response that does not match the request
+///
+fn unexpected_response(response: HostResponse) -> Error {
+    Error::PluginError(format!("Unexpected plugin host response {:?}", response))
+}
+
 ///
 ///
 ///
@@ -177,7 +669,47 @@ pub struct PluginsManager {
     ///
     /// Plugin handlers
     ///
-    handlers: Vec<PluginHandler>,
+    handlers: Vec<Arc<PluginHandler>>,
+
+    ///
+    /// Plugin files that failed to load
+    ///
+    load_errors: Vec<PluginLoadError>,
+
+    ///
+    /// Load each plugin in its own host process
+    ///
+    isolation: bool,
+
+    ///
+    /// Plugin files to load
+    ///
+    selection: PluginSelection,
+
+    ///
+    /// Rule to select a producer provided several times
+    ///
+    precedence: ProducerPrecedence,
+
+    ///
+    /// Driver references pinned to a producer
+    ///
+    pins: HashMap<String, String>,
+
+    ///
+    /// Conflicts found by the last merge of the stores
+    ///
+    conflicts: Vec<ProducerConflict>,
+
+    ///
+    /// Pinned driver references whose pin matches none of their producers
+    ///
+    unmatched_pins: HashSet<String>,
+
+    ///
+    /// Pipeline where the hosts of the isolated plugins push their notifications
+    ///
+    notifications: NotificationSender,
 
     enable_stdout: bool,
     debug: bool,
@@ -188,11 +720,27 @@ impl PluginsManager {
     ///
     /// Create a new object
     ///
-    pub fn new(enable_stdout: bool, debug: bool, trace: bool) -> Self {
+    pub fn new(
+        notifications: NotificationSender,
+        enable_stdout: bool,
+        debug: bool,
+        trace: bool,
+    ) -> Self {
         Self {
             logger: Logger::new_for_platform(),
 
             handlers: Vec::new(),
+            load_errors: Vec::new(),
+
+            isolation: false,
+            selection: PluginSelection::default(),
+            precedence: ProducerPrecedence::PreferBuiltIn,
+            pins: HashMap::new(),
+
+            conflicts: Vec::new(),
+            unmatched_pins: HashSet::new(),
+
+            notifications: notifications,
 
             enable_stdout: enable_stdout,
             debug: debug,
@@ -201,8 +749,32 @@ impl PluginsManager {
     }
 
     ///
+    /// Select how the next plugins will be loaded
+    ///
+    pub fn set_isolation(&mut self, isolation: bool) {
+        self.isolation = isolation;
+    }
+
+    ///
+    /// Select the plugin files to load
+    ///
+    pub fn set_selection(&mut self, selection: PluginSelection) {
+        self.selection = selection;
+    }
+
+    ///
+    /// Plugin files to load
+    ///
+    pub fn selection(&self) -> PluginSelection {
+        self.selection.clone()
+    }
+
+    ///
+    /// Load the selected plugins
+    ///
+    /// With 'fail_on_error' the first plugin that fails to load stops the loading
     ///
-    pub fn load_system_plugins(&mut self) -> Result<u32, Error> {
+    pub fn load_system_plugins(&mut self, fail_on_error: bool) -> Result<u32, Error> {
         let mut count = 0;
 
         //
@@ -210,28 +782,24 @@ impl PluginsManager {
         let dyn_lib_ext =
             env::system_dyn_lib_extension().map_err(|e| Error::Generic(format!("{:?}", e)))?;
 
-        for path in env::system_plugins_dir_paths() {
+        for path in self.selection.directories() {
             // User information
             self.logger
                 .info(format!("? SEARCH PUGINS in ({})", path.display()));
+        }
 
-            // Ensure the path is a directory
-            if path.is_dir() {
-                // Iterate over directory entries
-                for entry in fs::read_dir(path).unwrap() {
-                    let entry = entry.unwrap();
-                    let path = entry.path();
-
-                    // Check if the entry is a file and has a DLL extension
-                    if path.is_file() && path.extension() == Some(OsStr::new(dyn_lib_ext.as_str()))
-                    {
-                        // Print or process the DLL file path
-                        self.logger
-                            .info(format!("!  Found PUGIN file: {:?}", path.display()));
-
-                        self.register_plugin(path)?;
-                        count += 1;
-                        // Add the DLL file path to a list or perform other actions as needed
+        for path in self.selection.plugin_files(&dyn_lib_ext) {
+            // Print or process the DLL file path
+            self.logger
+                .info(format!("!  Found PUGIN file: {:?}", path.display()));
+
+            //
+            // An incompatible plugin must not prevent the others to load, unless requested
+            match self.load_plugin(path) {
+                Ok(_) => count += 1,
+                Err(e) => {
+                    if fail_on_error {
+                        return Err(e);
                     }
                 }
             }
@@ -239,33 +807,110 @@ impl PluginsManager {
         Ok(count)
     }
 
+    ///
+    /// Load a plugin file, the failure is kept to be reported to the user
+    ///
+    pub fn load_plugin(&mut self, filename: PathBuf) -> Result<(), Error> {
+        if self.handler(&filename).is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "Plugin [{:?}] is already loaded",
+                filename
+            )));
+        }
+
+        let path = filename.display().to_string();
+        self.load_errors.retain(|e| e.path != path);
+        self.register_plugin(filename).map_err(|e| {
+            self.logger.error(format!("Plugin refused: {:?}", e));
+            self.load_errors.push(PluginLoadError {
+                path: path,
+                error: format!("{:?}", e),
+            });
+            e
+        })
+    }
+
+    ///
+    /// Remove the plugin loaded from this file
+    ///
+    /// The library is released when the last user of the handler drops it,
+    /// instances produced by the plugin must be destroyed before.
+    /// Only isolated plugins can be unloaded, a local library stays in the process.
+    ///
+    pub fn unload_plugin(&mut self, filename: &PathBuf) -> Result<Arc<PluginHandler>, Error> {
+        let path = filename.display().to_string();
+        self.load_errors.retain(|e| e.path != path);
+
+        let position = self
+            .handlers
+            .iter()
+            .position(|ph| &ph.filename == filename)
+            .ok_or(Error::InvalidArgument(format!(
+                "Plugin [{:?}] is not loaded",
+                filename
+            )))?;
+        if !self.handlers[position].is_isolated() {
+            return Err(Error::PluginError(format!(
+                "Plugin [{:?}] is not isolated, it cannot be unloaded",
+                filename
+            )));
+        }
+        Ok(self.handlers.remove(position))
+    }
+
+    ///
+    /// Handler of the plugin loaded from this file
+    ///
+    pub fn handler(&self, filename: &PathBuf) -> Option<Arc<PluginHandler>> {
+        self.handlers
+            .iter()
+            .find(|ph| &ph.filename == filename)
+            .cloned()
+    }
+
     ///
     /// To register a new plugin
     ///
     pub fn register_plugin(&mut self, filename: PathBuf) -> Result<(), Error> {
         //
-        let handler =
-            PluginHandler::from_filename(filename, self.enable_stdout, self.debug, self.trace)?;
+        let handler = match self.isolation {
+            true => PluginHandler::isolated_from_filename(
+                filename,
+                self.notifications.clone(),
+                self.enable_stdout,
+                self.debug,
+                self.trace,
+            )?,
+            false => {
+                PluginHandler::from_filename(filename, self.enable_stdout, self.debug, self.trace)?
+            }
+        };
 
         // Info
+        self.logger
+            .info(format!("         ISOLATED : {:?}", handler.is_isolated()));
+        self.logger
+            .info(format!("         METADATA : {:?}", handler.metadata()));
         self.logger
             .info(format!("         PRODUCERS : {:?}", handler.store()));
 
         //
         // Append the plugin
-        self.handlers.push(handler);
+        self.handlers.push(Arc::new(handler));
         Ok(())
     }
 
     ///
-    /// True when a plugin was able to build the order, false else
+    /// True when the plugin loaded from this file was able to build the order, false else
     ///
-    pub fn produce(&mut self, order: &ProductionOrder) -> Result<bool, Error> {
-        for ph in (&self.handlers).into_iter() {
-            match ph.produce(order)? {
-                true => return Ok(true),
-                false => {}
-            }
+    pub async fn produce(
+        &mut self,
+        filename: &PathBuf,
+        order: &ProductionOrder,
+    ) -> Result<bool, Error> {
+        if let Some(ph) = self.handler(filename) {
+            let order = order.clone();
+            return run_blocking(move || ph.produce(&order)).await;
         }
 
         //
@@ -276,39 +921,351 @@ impl PluginsManager {
     }
 
     ///
+    /// True when the plugin loaded from this file destroyed the instance of this order, false else
     ///
-    ///
-    pub fn pull_notifications(&self) -> Result<Vec<Notification>, Error> {
-        //
+    pub async fn unproduce(
+        &mut self,
+        filename: &PathBuf,
+        order: &ProductionOrder,
+    ) -> Result<bool, Error> {
+        if let Some(ph) = self.handler(filename) {
+            let order = order.clone();
+            return run_blocking(move || ph.unproduce(&order)).await;
+        }
+
         //
-        let mut results: Vec<Notification> = Vec::new();
+        // Log failure
+        self.logger
+            .info(format!("No plugin found to destroy this order"));
+        Ok(false)
+    }
 
-        for ph in (&self.handlers).into_iter() {
-            results.extend(ph.pull_notifications()?);
+    ///
+    /// Define how a producer is selected when several provide the same driver
+    ///
+    pub fn set_precedence(
+        &mut self,
+        precedence: ProducerPrecedence,
+        pins: HashMap<String, String>,
+    ) {
+        self.precedence = precedence;
+        self.pins = pins;
+    }
+
+    ///
+    /// All the producers that provide this driver, built-in first then plugins in loading order
+    ///
+    pub fn producer_candidates(&self, dref: &String, built_in: &Store) -> Vec<ProducerSource> {
+        let mut candidates = Vec::new();
+        if built_in.contains(dref) {
+            candidates.push(ProducerSource::BuiltIn);
+        }
+        for ph in self.handlers.iter() {
+            if ph.store.contains(dref) {
+                candidates.push(ProducerSource::Plugin(ph.filename.clone()));
+            }
         }
+        candidates
+    }
 
-        Ok(results)
+    ///
+    /// Producer to use for this driver
+    ///
+    /// A pinned producer is used if it provides the driver, else the precedence rule applies
+    ///
+    pub fn select_producer(&self, dref: &String, built_in: &Store) -> Option<ProducerSource> {
+        pick_producer(
+            &self.producer_candidates(dref, built_in),
+            self.pins.get(dref),
+            self.precedence,
+        )
+    }
+
+    ///
+    /// Drivers provided by several producers, found by the last merge of the stores
+    ///
+    pub fn producer_conflicts(&self) -> Vec<ProducerConflict> {
+        self.conflicts.clone()
     }
 
     ///
-    /// Merge all the stores from plugins into a single one
+    /// Drivers provided by several producers, with the selected one
     ///
-    pub fn merge_stores(&mut self) -> Store {
+    fn detect_conflicts(&self, built_in: &Store) -> Result<Vec<ProducerConflict>, Error> {
+        let mut drefs = store_producers(built_in)?;
+        for ph in self.handlers.iter() {
+            drefs.extend(store_producers(&ph.store)?);
+        }
+        drefs.sort();
+        drefs.dedup();
+
+        let mut conflicts = Vec::new();
+        for dref in drefs {
+            let candidates = self.producer_candidates(&dref, built_in);
+            if candidates.len() > 1 {
+                conflicts.push(ProducerConflict {
+                    selected: self
+                        .select_producer(&dref, built_in)
+                        .map(|source| source.label())
+                        .unwrap_or_default(),
+                    providers: candidates.iter().map(|source| source.label()).collect(),
+                    dref: dref,
+                });
+            }
+        }
+        Ok(conflicts)
+    }
+
+    ///
+    /// Notifications of the plugins loaded in the platform process
+    ///
+    /// Their interface can only be pulled. The hosts of the isolated plugins push
+    /// their notifications to the pipeline themselves.
+    ///
+    pub async fn pull_notifications(&self) -> Result<Vec<Notification>, Error> {
+        let handlers: Vec<Arc<PluginHandler>> = self
+            .handlers
+            .iter()
+            .filter(|ph| !ph.is_isolated())
+            .cloned()
+            .collect();
+        if handlers.is_empty() {
+            return Ok(Vec::new());
+        }
+        run_blocking(move || {
+            //
+            //
+            let mut results: Vec<Notification> = Vec::new();
+
+            for ph in handlers.iter() {
+                results.extend(ph.pull_notifications()?);
+            }
+
+            Ok(results)
+        })
+        .await
+    }
+
+    ///
+    /// Merge all the stores from plugins and the built-in store into a single one
+    ///
+    /// Drivers provided by several producers (plugins or built-in) are detected here,
+    /// each conflict and each pin without producer is logged once, when it appears.
+    ///
+    pub fn merge_stores(&mut self, built_in: &Store) -> Store {
         let mut store = Store::default();
         for ph in (&self.handlers).into_iter() {
             store.extend_by_copy(&ph.store);
         }
+        store.extend_by_copy(built_in);
+
+        match self.detect_conflicts(built_in) {
+            Ok(conflicts) => {
+                for conflict in conflicts.iter() {
+                    if !self.conflicts.contains(conflict) {
+                        self.logger.warn(format!(
+                            "Producer conflict on '{}': {:?}, '{}' selected",
+                            conflict.dref, conflict.providers, conflict.selected
+                        ));
+                    }
+                }
+                self.conflicts = conflicts;
+            }
+            Err(e) => {
+                self.logger
+                    .error(format!("Unable to detect producer conflicts: {:?}", e));
+            }
+        }
+
+        let mut unmatched_pins = HashSet::new();
+        for (dref, pin) in self.pins.iter() {
+            let candidates = self.producer_candidates(dref, built_in);
+            if candidates.is_empty() || candidates.iter().any(|c| c.matches_pin(pin)) {
+                continue;
+            }
+            if !self.unmatched_pins.contains(dref) {
+                self.logger.warn(format!(
+                    "Producer '{}' pinned for '{}' does not provide it",
+                    pin, dref
+                ));
+            }
+            unmatched_pins.insert(dref.clone());
+        }
+        self.unmatched_pins = unmatched_pins;
+
         store
     }
 
     ///
+    /// Description of the loaded plugins
+    ///
+    pub fn plugins_info(&self) -> Result<Vec<PluginInfo>, Error> {
+        let mut infos = Vec::new();
+        for ph in self.handlers.iter() {
+            infos.push(PluginInfo {
+                path: ph.filename.display().to_string(),
+                directory: ph
+                    .filename
+                    .parent()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default(),
+                metadata: ph.metadata.clone(),
+                isolated: ph.is_isolated(),
+                producers: store_producers(&ph.store)?,
+                unproduce: ph.can_unproduce(),
+            });
+        }
+        Ok(infos)
+    }
+
     ///
+    /// Plugin files that failed to load
     ///
-    pub fn scan(&self) -> Result<Vec<ProductionOrder>, Error> {
-        let mut v = Vec::new();
-        for ph in (&self.handlers).into_iter() {
-            v.extend(ph.scan()?);
+    pub fn load_errors(&self) -> Vec<PluginLoadError> {
+        self.load_errors.clone()
+    }
+
+    ///
+    /// Handlers whose host process died and must be restarted
+    ///
+    pub fn crashed_handlers(&self) -> Vec<Arc<PluginHandler>> {
+        self.handlers
+            .iter()
+            .filter(|ph| ph.has_crashed())
+            .cloned()
+            .collect()
+    }
+
+    ///
+    /// Shared handlers, to run their blocking scan outside of the platform loop
+    ///
+    pub fn handlers(&self) -> Vec<Arc<PluginHandler>> {
+        self.handlers.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core_versions_before_1_0_must_have_the_same_minor() {
+        assert!(core_version_compatible("0.2.1", "0.2.4"));
+        assert!(core_version_compatible("v0.2.1", "0.2.0"));
+        assert!(!core_version_compatible("0.3.0", "0.2.4"));
+        assert!(!core_version_compatible("1.0.0", "0.2.4"));
+    }
+
+    #[test]
+    fn core_versions_after_1_0_must_have_the_same_major() {
+        assert!(core_version_compatible("1.2.0", "1.5.3"));
+        assert!(core_version_compatible("v2.0.0", "2.1.0"));
+        assert!(!core_version_compatible("2.0.0", "1.5.3"));
+    }
+
+    fn selection(config: serde_json::Value) -> PluginSelection {
+        let config: PluginsConfig = serde_json::from_value(config).unwrap();
+        PluginSelection::from_config(Some(&config)).unwrap()
+    }
+
+    #[test]
+    fn selection_applies_allow_then_deny_patterns() {
+        let all = PluginSelection::from_config(None).unwrap();
+        assert!(all.is_allowed(Path::new("/plugins/libpza_anything.so")));
+
+        let selection = selection(serde_json::json!({
+            "allow": ["libpza_*.so"],
+            "deny": ["libpza_test?.so"]
+        }));
+        assert!(selection.is_allowed(Path::new("/plugins/libpza_psu.so")));
+        assert!(!selection.is_allowed(Path::new("/plugins/libother.so")));
+        assert!(!selection.is_allowed(Path::new("/plugins/libpza_test1.so")));
+        assert!(selection.is_allowed(Path::new("/plugins/libpza_test12.so")));
+    }
+
+    #[test]
+    fn selection_patterns_are_not_regexes() {
+        let selection = selection(serde_json::json!({ "allow": ["lib.so"] }));
+        assert!(selection.is_allowed(Path::new("lib.so")));
+        assert!(!selection.is_allowed(Path::new("libxso")));
+    }
+
+    #[test]
+    fn selection_loads_listed_files_whatever_the_patterns() {
+        let dir = std::env::temp_dir().join(format!("pza-plugins-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["libpza_a.so", "libpza_b.so", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
         }
-        Ok(v)
+        let listed = dir.join("libpza_b.so");
+
+        let selection = selection(serde_json::json!({
+            "paths": [dir.display().to_string()],
+            "files": [listed.display().to_string()],
+            "deny": ["libpza_b.so"]
+        }));
+        let files = selection.plugin_files("so");
+        let _ = fs::remove_dir_all(&dir);
+        assert!(files.contains(&dir.join("libpza_a.so")));
+        assert!(files.contains(&listed));
+        assert!(!files.contains(&dir.join("notes.txt")));
+    }
+
+    #[test]
+    fn producer_selection_follows_the_precedence() {
+        let plugin_a = ProducerSource::Plugin(PathBuf::from("/plugins/libpza_a.so"));
+        let plugin_b = ProducerSource::Plugin(PathBuf::from("/plugins/libpza_b.so"));
+        let candidates = vec![ProducerSource::BuiltIn, plugin_a.clone(), plugin_b.clone()];
+
+        assert_eq!(
+            pick_producer(&candidates, None, ProducerPrecedence::PreferBuiltIn),
+            Some(ProducerSource::BuiltIn)
+        );
+        assert_eq!(
+            pick_producer(&candidates, None, ProducerPrecedence::PreferPlugin),
+            Some(plugin_a.clone())
+        );
+        assert_eq!(
+            pick_producer(
+                &vec![ProducerSource::BuiltIn],
+                None,
+                ProducerPrecedence::PreferPlugin
+            ),
+            Some(ProducerSource::BuiltIn)
+        );
+        assert_eq!(
+            pick_producer(&Vec::new(), None, ProducerPrecedence::PreferBuiltIn),
+            None
+        );
+    }
+
+    #[test]
+    fn producer_pins_win_over_the_precedence() {
+        let plugin_a = ProducerSource::Plugin(PathBuf::from("/plugins/libpza_a.so"));
+        let plugin_b = ProducerSource::Plugin(PathBuf::from("/plugins/libpza_b.so"));
+        let candidates = vec![ProducerSource::BuiltIn, plugin_a.clone(), plugin_b.clone()];
+        let pick =
+            |pin: &str, precedence| pick_producer(&candidates, Some(&pin.to_string()), precedence);
+
+        assert_eq!(
+            pick("libpza_b.so", ProducerPrecedence::PreferBuiltIn),
+            Some(plugin_b.clone())
+        );
+        assert_eq!(
+            pick("/plugins/libpza_b.so", ProducerPrecedence::PreferPlugin),
+            Some(plugin_b.clone())
+        );
+        assert_eq!(
+            pick("built-in", ProducerPrecedence::PreferPlugin),
+            Some(ProducerSource::BuiltIn)
+        );
+
+        //
+        // A pin on a missing producer falls back to the precedence
+        assert_eq!(
+            pick("libpza_c.so", ProducerPrecedence::PreferPlugin),
+            Some(plugin_a)
+        );
     }
 }
@@ -0,0 +1,100 @@
+use crate::mqtt_pump::EventPump;
+use panduza_platform_core::Error;
+use rumqttc::{AsyncClient, MqttOptions, Packet, QoS};
+use std::collections::HashSet;
+use std::time::Duration;
+
+///
+/// Time without new retained message after which all of them are considered received
+///
+pub static RETAINED_QUIET_DELAY: Duration = Duration::from_millis(300);
+
+///
+/// Maximum time to wait for the broker at each step of the cleaning
+///
+static BROKER_TIMEOUT: Duration = Duration::from_secs(3);
+
+///
+/// Requests waiting for the event loop, the pump keeps it moving whatever the number of topics
+///
+static CLIENT_CHANNEL_SIZE: usize = 10;
+
+/// Clear all the retained messages matching the given topic filters
+///
+/// The broker does not provide a way to list retained messages, so the
+/// cleaner subscribes to the filters, collects the retained topics it receives
+/// and publishes an empty retained payload on each of them.
+///
+/// Return the number of cleared topics
+///
+pub async fn clear(
+    client_id: String,
+    addr: String,
+    port: u16,
+    filters: Vec<String>,
+) -> Result<usize, Error> {
+    let mut options = MqttOptions::new(client_id, addr, port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, event_loop) = AsyncClient::new(options, CLIENT_CHANNEL_SIZE);
+    let mut pump = EventPump::start(event_loop);
+
+    //
+    // Subscribe and wait for the broker to acknowledge all the filters
+    for filter in filters.iter() {
+        client
+            .subscribe(filter.clone(), QoS::AtMostOnce)
+            .await
+            .map_err(|e| Error::Generic(format!("Cleaner subscribe failure ({:?})", e)))?;
+    }
+    let mut topics = HashSet::new();
+    let mut pending_acks = filters.len();
+    while pending_acks > 0 {
+        match pump.next(BROKER_TIMEOUT).await? {
+            Some(Packet::SubAck(_)) => pending_acks -= 1,
+            Some(Packet::Publish(p)) => {
+                if p.retain && !p.payload.is_empty() {
+                    topics.insert(p.topic);
+                }
+            }
+            Some(_) => {}
+            None => return Err(Error::Generic("Cleaner subscription timeout".to_string())),
+        }
+    }
+
+    //
+    // Retained messages are sent just after the subscription
+    while let Some(packet) = pump.next(RETAINED_QUIET_DELAY).await? {
+        if let Packet::Publish(p) = packet {
+            if p.retain && !p.payload.is_empty() {
+                topics.insert(p.topic);
+            }
+        }
+    }
+    for filter in filters.iter() {
+        client
+            .unsubscribe(filter.clone())
+            .await
+            .map_err(|e| Error::Generic(format!("Cleaner unsubscribe failure ({:?})", e)))?;
+    }
+
+    //
+    // An empty retained payload removes the retained message of the topic,
+    // the acknowledgements are counted while the pump drains the requests
+    for topic in topics.iter() {
+        client
+            .publish(topic.clone(), QoS::AtLeastOnce, true, Vec::new())
+            .await
+            .map_err(|e| Error::Generic(format!("Cleaner publish failure ({:?})", e)))?;
+    }
+    let mut pending_acks = topics.len();
+    while pending_acks > 0 {
+        match pump.next(BROKER_TIMEOUT).await? {
+            Some(Packet::PubAck(_)) => pending_acks -= 1,
+            Some(_) => {}
+            None => return Err(Error::Generic("Cleaner publication timeout".to_string())),
+        }
+    }
+
+    let _ = client.disconnect().await;
+    Ok(topics.len())
+}
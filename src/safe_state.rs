@@ -0,0 +1,109 @@
+use crate::mqtt_pump::EventPump;
+use crate::retained_cleaner::RETAINED_QUIET_DELAY;
+use panduza_platform_core::Error;
+use rumqttc::{AsyncClient, MqttOptions, Packet, QoS};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+///
+/// Maximum time to wait for the broker to acknowledge the subscriptions
+///
+static BROKER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Put the attributes of an instance in their safe values
+///
+/// Each value is sent as a command on 'pza/<instance>/<attribute>/cmd', then the
+/// attribute is considered safe when its 'att' topic publishes the same value.
+/// An attribute that is already in its safe value is not commanded again.
+///
+/// The caller is in charge of the timeout, the function waits for all the confirmations.
+///
+/// Return the number of attributes in their safe value
+///
+pub async fn apply(
+    client_id: String,
+    addr: String,
+    port: u16,
+    instance: &str,
+    values: &BTreeMap<String, JsonValue>,
+) -> Result<usize, Error> {
+    let mut options = MqttOptions::new(client_id, addr, port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, event_loop) = AsyncClient::new(options, 100);
+    let mut pump = EventPump::start(event_loop);
+
+    //
+    // Attributes not confirmed yet, by 'att' topic
+    let mut pending: HashMap<String, (String, &JsonValue)> = values
+        .iter()
+        .map(|(attribute, value)| {
+            let topic = format!("pza/{}/{}", instance, attribute.trim_matches('/'));
+            (format!("{}/att", topic), (format!("{}/cmd", topic), value))
+        })
+        .collect();
+
+    //
+    // Subscribe first, the retained value tells if a command is needed
+    for topic in pending.keys() {
+        client
+            .subscribe(topic.clone(), QoS::AtMostOnce)
+            .await
+            .map_err(|e| Error::Generic(format!("Safe state subscribe failure ({:?})", e)))?;
+    }
+    let mut pending_acks = pending.len();
+    while pending_acks > 0 {
+        match pump.next(BROKER_TIMEOUT).await? {
+            Some(Packet::SubAck(_)) => pending_acks -= 1,
+            Some(Packet::Publish(p)) => confirm(&mut pending, &p.topic, &p.payload),
+            Some(_) => {}
+            None => {
+                return Err(Error::Generic(
+                    "Safe state subscription timeout".to_string(),
+                ))
+            }
+        }
+    }
+
+    //
+    // Retained values can still arrive after the acknowledgements
+    while let Some(packet) = pump.next(RETAINED_QUIET_DELAY).await? {
+        if let Packet::Publish(p) = packet {
+            confirm(&mut pending, &p.topic, &p.payload);
+        }
+    }
+
+    //
+    // Command the attributes that are not safe yet
+    for (cmd_topic, value) in pending.values() {
+        let payload =
+            serde_json::to_vec(value).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))?;
+        client
+            .publish(cmd_topic.clone(), QoS::AtLeastOnce, false, payload)
+            .await
+            .map_err(|e| Error::Generic(format!("Safe state publish failure ({:?})", e)))?;
+    }
+    while !pending.is_empty() {
+        if let Some(Packet::Publish(p)) = pump.next(BROKER_TIMEOUT).await? {
+            confirm(&mut pending, &p.topic, &p.payload);
+        }
+    }
+
+    let _ = client.disconnect().await;
+    Ok(values.len())
+}
+
+/// Forget the attribute if the published value is its safe value
+///
+fn confirm(pending: &mut HashMap<String, (String, &JsonValue)>, topic: &str, payload: &[u8]) {
+    let reached = match (
+        pending.get(topic),
+        serde_json::from_slice::<JsonValue>(payload),
+    ) {
+        (Some((_, expected)), Ok(value)) => *expected == &value,
+        _ => false,
+    };
+    if reached {
+        pending.remove(topic);
+    }
+}
@@ -0,0 +1,297 @@
+use futures::future::BoxFuture;
+use panduza_platform_core::TaskResult;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+///
+/// A task that runs longer than this is considered healthy again, its backoff restarts from the beginning
+///
+static STABLE_RUN_TIME: Duration = Duration::from_secs(60);
+
+///
+/// Build a new future of a task each time it must be started
+///
+pub type TaskFactory = Box<dyn Fn() -> BoxFuture<'static, TaskResult> + Send>;
+
+/// Delay before the restart of a failed task, doubled after each consecutive failure
+///
+/// Without 'max_retries' the task is restarted as long as the platform runs.
+///
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Number of restarts after consecutive failures, None for no limit
+    pub max_retries: Option<u32>,
+    /// Delay before the first restart
+    pub initial_delay: Duration,
+    /// Maximum delay between two restarts
+    pub max_delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    ///
+    /// Delay before the restart that follows 'failures' consecutive failures,
+    /// None once the retries are exhausted
+    ///
+    pub fn delay(&self, failures: u32) -> Option<Duration> {
+        if self.max_retries.map_or(false, |max| failures > max) {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        Some(
+            self.initial_delay
+                .saturating_mul(factor)
+                .min(self.max_delay),
+        )
+    }
+}
+
+/// What the platform does when a task ends
+///
+/// Only the tasks of the platform have a policy. The tasks of the instances are
+/// spawned by the core and the plugins, which cannot give a policy, so they are
+/// never restarted: a failed instance task puts its instance in error instead.
+///
+#[derive(Debug, Clone)]
+pub enum RestartPolicy {
+    /// The task is not started again
+    Never,
+    /// The task is started again after an error or a panic, with a backoff
+    OnFailure(Backoff),
+    /// The task is always started again, with a backoff after errors
+    Always(Backoff),
+}
+
+impl RestartPolicy {
+    ///
+    /// Restart on failure with the default backoff, without limit
+    ///
+    pub fn on_failure() -> Self {
+        RestartPolicy::OnFailure(Backoff::default())
+    }
+
+    ///
+    /// Always restart with the default backoff, for tasks that must live as long as the platform
+    ///
+    pub fn always() -> Self {
+        RestartPolicy::Always(Backoff::default())
+    }
+
+    ///
+    /// Name of the policy, for the logs and the underscore device
+    ///
+    pub fn label(&self) -> &'static str {
+        match self {
+            RestartPolicy::Never => "never",
+            RestartPolicy::OnFailure(_) => "on_failure",
+            RestartPolicy::Always(_) => "always",
+        }
+    }
+
+    ///
+    /// Delay before starting again a task that ended, None if it must not be started again
+    ///
+    pub fn restart_delay(&self, failed: bool, consecutive_failures: u32) -> Option<Duration> {
+        let backoff = match self {
+            RestartPolicy::Never => return None,
+            RestartPolicy::OnFailure(_) if !failed => return None,
+            RestartPolicy::OnFailure(backoff) | RestartPolicy::Always(backoff) => backoff,
+        };
+        backoff.delay(consecutive_failures.max(1))
+    }
+}
+
+/// Task started again by the platform when it ends
+///
+pub struct SupervisedTask {
+    pub policy: RestartPolicy,
+    pub factory: TaskFactory,
+}
+
+/// Where a named task is in its life
+///
+/// There is no stuck state, a task blocked on a never ending wait stays running.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    /// Started or waiting for its restart delay
+    #[default]
+    Running,
+    /// Ended without error
+    Completed,
+    /// Ended with an error or a panic
+    Failed,
+    /// Aborted by the platform or by a user
+    Cancelled,
+}
+
+/// Life of a named task
+///
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskStats {
+    /// Instance that owns the task, None for the tasks of the platform
+    pub instance: Option<String>,
+    /// Current state of the task
+    pub state: TaskState,
+    /// Time of the start of the current run
+    pub start_time: Option<String>,
+    /// Time of the end of the last run, None while it runs
+    pub end_time: Option<String>,
+    /// Restart policy of the task
+    pub policy: String,
+    /// Errors and panics since the start of the platform
+    pub failures: u32,
+    /// Failures without a stable run between them, used for the backoff
+    pub consecutive_failures: u32,
+    /// Number of times the platform started the task again
+    pub restarts: u32,
+    /// Last error or panic message
+    pub last_error: Option<String>,
+    /// Start of the current run
+    #[serde(skip)]
+    pub started: Option<Instant>,
+    /// End of the last run
+    #[serde(skip)]
+    pub ended: Option<Instant>,
+}
+
+impl TaskStats {
+    ///
+    /// Count the start of a new run
+    ///
+    pub fn record_start(&mut self, name: &str, policy: &RestartPolicy) {
+        //
+        // Instance tasks are named "<instance>/<task>"
+        self.instance = name
+            .split_once('/')
+            .map(|(instance, _)| instance.to_string());
+        self.state = TaskState::Running;
+        self.start_time = Some(chrono::Local::now().to_rfc3339());
+        self.end_time = None;
+        self.policy = policy.label().to_string();
+        self.started = Some(Instant::now());
+        self.ended = None;
+    }
+
+    ///
+    /// Count the end of the current run, 'error' is None if the task ended well
+    ///
+    pub fn record_end(&mut self, error: Option<String>) {
+        let stable = self
+            .started
+            .map_or(false, |started| started.elapsed() > STABLE_RUN_TIME);
+        if stable {
+            self.consecutive_failures = 0;
+        }
+        self.state = match error {
+            Some(_) => TaskState::Failed,
+            None => TaskState::Completed,
+        };
+        if let Some(error) = error {
+            self.failures += 1;
+            self.consecutive_failures += 1;
+            self.last_error = Some(error);
+        }
+        self.record_stop();
+    }
+
+    ///
+    /// Count the abort of the current run, an aborted task is not restarted
+    ///
+    pub fn record_cancel(&mut self) {
+        self.state = TaskState::Cancelled;
+        self.record_stop();
+    }
+
+    ///
+    ///
+    ///
+    fn record_stop(&mut self) {
+        self.end_time = Some(chrono::Local::now().to_rfc3339());
+        self.started = None;
+        self.ended = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_delay() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.delay(2), Some(Duration::from_secs(2)));
+        assert_eq!(backoff.delay(4), Some(Duration::from_secs(8)));
+        assert_eq!(backoff.delay(6), Some(Duration::from_secs(30)));
+        assert_eq!(backoff.delay(1000), Some(Duration::from_secs(30)));
+
+        let backoff = Backoff {
+            max_retries: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(backoff.delay(2), Some(Duration::from_secs(2)));
+        assert_eq!(backoff.delay(3), None);
+    }
+
+    #[test]
+    fn restart_follows_the_policy() {
+        assert_eq!(RestartPolicy::Never.restart_delay(true, 1), None);
+        assert_eq!(RestartPolicy::on_failure().restart_delay(false, 0), None);
+        assert_eq!(
+            RestartPolicy::on_failure().restart_delay(true, 3),
+            Some(Duration::from_secs(4))
+        );
+
+        //
+        // A task that ended well is started again without waiting for long
+        assert_eq!(
+            RestartPolicy::always().restart_delay(false, 0),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn task_stats_follow_the_runs_of_the_task() {
+        let mut stats = TaskStats::default();
+        stats.record_start("psu/poll", &RestartPolicy::Never);
+        assert_eq!(stats.instance.as_deref(), Some("psu"));
+        assert_eq!(stats.policy, "never");
+        assert_eq!(stats.state, TaskState::Running);
+
+        stats.record_end(Some("no device".to_string()));
+        assert_eq!(stats.state, TaskState::Failed);
+        assert_eq!(stats.failures, 1);
+        assert_eq!(stats.consecutive_failures, 1);
+        assert_eq!(stats.last_error.as_deref(), Some("no device"));
+        assert!(stats.end_time.is_some());
+
+        stats.record_start("psu/poll", &RestartPolicy::Never);
+        assert!(stats.end_time.is_none());
+        stats.record_end(Some("no device".to_string()));
+        assert_eq!(stats.consecutive_failures, 2);
+
+        stats.record_start("psu/poll", &RestartPolicy::Never);
+        stats.record_cancel();
+        assert_eq!(stats.state, TaskState::Cancelled);
+        assert_eq!(stats.failures, 2);
+        assert!(stats.end_time.is_some());
+
+        let mut stats = TaskStats::default();
+        stats.record_start("notifications", &RestartPolicy::on_failure());
+        stats.record_end(None);
+        assert_eq!(stats.instance, None);
+        assert_eq!(stats.state, TaskState::Completed);
+        assert_eq!(stats.failures, 0);
+    }
+}
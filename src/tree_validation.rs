@@ -0,0 +1,437 @@
+use crate::config;
+use crate::device_tree::{default_tree_file, DeviceTree};
+use crate::notification_pipeline;
+use crate::plugins_manager::{PluginSelection, PluginsManager};
+use panduza_platform_core::Error;
+use panduza_platform_core::Logger;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+///
+/// Kind of problem found on a device of the tree
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeIssueKind {
+    /// No producer provides the driver reference
+    UnknownDriver,
+    /// A setting declared as required is not defined
+    MissingSetting,
+    /// A setting does not have the type declared by the driver
+    WrongType,
+    /// A setting is not declared by the driver
+    UnknownSetting,
+    /// A dependency is not in the tree or the dependencies form a cycle
+    InvalidDependency,
+    /// Limits cannot be satisfied or a safe value is out of its limits
+    InvalidLimits,
+}
+
+impl TreeIssueKind {
+    ///
+    /// True if the device must not be produced, the other problems are only warnings
+    ///
+    pub fn blocks_production(&self) -> bool {
+        matches!(
+            self,
+            TreeIssueKind::UnknownDriver | TreeIssueKind::MissingSetting
+        )
+    }
+}
+
+///
+/// Problem found on a device of the tree
+///
+#[derive(Debug, Clone)]
+pub struct TreeIssue {
+    /// Instance name of the device
+    pub instance: String,
+    /// "file:line" of the device in the tree file
+    pub location: String,
+    ///
+    pub kind: TreeIssueKind,
+    /// Explanation for the user
+    pub message: String,
+}
+
+impl std::fmt::Display for TreeIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: '{}' {}", self.location, self.instance, self.message)
+    }
+}
+
+/// Check the devices of the tree against the store of the producers
+///
+/// The store describes each driver as { "description", "settings": { key: description } }
+/// where the description of a setting can give its "type" and if it is "required".
+///
+/// Devices are located in the tree file where they are declared.
+///
+pub fn validate(tree: &DeviceTree, store: &JsonValue) -> Vec<TreeIssue> {
+    let mut issues = Vec::new();
+    let cycles = tree.dependency_cycles();
+    let mut sources: HashMap<PathBuf, String> = HashMap::new();
+
+    for entry in tree.devices.iter() {
+        let po = &entry.order;
+        let location = match entry.source.as_ref() {
+            Some(path) => {
+                let source = sources
+                    .entry(path.clone())
+                    .or_insert_with(|| std::fs::read_to_string(path).unwrap_or_default());
+                match entry_line(source, &po.name) {
+                    Some(line) => format!("{}:{}", path.display(), line),
+                    None => path.display().to_string(),
+                }
+            }
+            None => String::from("tree"),
+        };
+        let mut issue = |kind: TreeIssueKind, message: String| {
+            issues.push(TreeIssue {
+                instance: po.name.clone(),
+                location: location.clone(),
+                kind: kind,
+                message: message,
+            })
+        };
+
+        for dependency in entry.depends_on.iter() {
+            if !tree.devices.iter().any(|d| &d.order.name == dependency) {
+                issue(
+                    TreeIssueKind::InvalidDependency,
+                    format!("depends on '{}' which is not in the tree", dependency),
+                );
+            }
+        }
+        if cycles.contains(&po.name) {
+            issue(
+                TreeIssueKind::InvalidDependency,
+                "depends on itself through its dependencies".to_string(),
+            );
+        }
+
+        for (attribute, limits) in entry.limits.iter() {
+            if let (Some(min), Some(max)) = (limits.min, limits.max) {
+                if min > max {
+                    issue(
+                        TreeIssueKind::InvalidLimits,
+                        format!("limits of '{}' have a minimum above the maximum", attribute),
+                    );
+                }
+            }
+            if let Some(value) = entry.safe_values.get(attribute) {
+                if let Err(reason) = limits.check(value, None) {
+                    issue(
+                        TreeIssueKind::InvalidLimits,
+                        format!("safe value of '{}' is refused: {}", attribute, reason),
+                    );
+                }
+            }
+        }
+
+        let driver = match store.get(&po.dref) {
+            Some(driver) => driver,
+            None => {
+                issue(
+                    TreeIssueKind::UnknownDriver,
+                    format!("uses the unknown driver '{}'", po.dref),
+                );
+                continue;
+            }
+        };
+
+        //
+        // Settings of a device with an identity come from the scanned unit
+        if entry.identity.is_some() {
+            continue;
+        }
+        let schema = match driver.get("settings").and_then(|s| s.as_object()) {
+            Some(schema) => schema,
+            None => continue,
+        };
+        let empty = serde_json::Map::new();
+        let settings = po
+            .settings
+            .as_ref()
+            .and_then(|s| s.as_object())
+            .unwrap_or(&empty);
+
+        for (key, description) in schema.iter() {
+            let required = description
+                .get("required")
+                .and_then(|r| r.as_bool())
+                .unwrap_or(false);
+            match settings.get(key) {
+                None if required => issue(
+                    TreeIssueKind::MissingSetting,
+                    format!("misses the required setting '{}'", key),
+                ),
+                None => {}
+                Some(value) => {
+                    if let Some(expected) = description.get("type").and_then(|t| t.as_str()) {
+                        if !has_type(value, expected) {
+                            issue(
+                                TreeIssueKind::WrongType,
+                                format!(
+                                    "setting '{}' must be of type '{}', found {}",
+                                    key, expected, value
+                                ),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        for key in settings.keys() {
+            if !schema.contains_key(key) {
+                issue(
+                    TreeIssueKind::UnknownSetting,
+                    format!("defines the unknown setting '{}'", key),
+                );
+            }
+        }
+    }
+
+    issues
+}
+
+///
+/// True if the value matches the type name given by the driver, unknown type names accept all values
+///
+fn has_type(value: &JsonValue, expected: &str) -> bool {
+    match expected {
+        "string" => value.is_string(),
+        "number" | "float" => value.is_number(),
+        "integer" | "int" => value.is_i64() || value.is_u64(),
+        "boolean" | "bool" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" | "list" => value.is_array(),
+        _ => true,
+    }
+}
+
+///
+/// Line (from 1) where the device with this name is declared in the tree file
+///
+/// Match 'name' keys of json ("name": "x"), toml (name = "x") and yaml (- name: x) files.
+///
+fn entry_line(source: &str, name: &str) -> Option<usize> {
+    source
+        .lines()
+        .position(|line| {
+            let key = line.trim_start().trim_start_matches(['-', ' ']);
+            let rest = match line
+                .split_once("\"name\"")
+                .map(|(_, rest)| rest)
+                .or_else(|| key.strip_prefix("name"))
+            {
+                Some(rest) => rest.trim_start(),
+                None => return false,
+            };
+            let value = match rest.strip_prefix([':', '=']) {
+                Some(value) => value.trim_start(),
+                None => return false,
+            };
+            match value.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => value[1..]
+                    .split(quote)
+                    .next()
+                    .map_or(false, |value| value == name),
+                _ => value
+                    .split([',', '}', ' ', '#'])
+                    .next()
+                    .map_or(false, |value| value.trim_end() == name),
+            }
+        })
+        .map(|index| index + 1)
+}
+
+/// Check a tree file without starting the platform ('--check-tree')
+///
+/// Load the plugins selected by the configuration and the built-in drivers to build the store,
+/// print each issue and return the exit code of the process, 1 if a device cannot be produced.
+///
+pub fn check_tree_file(tree_path: Option<PathBuf>, profile: Option<&str>) -> i32 {
+    let logger = Logger::new_for_platform();
+
+    let tree_path = match tree_path.map_or_else(default_tree_file, Ok) {
+        Ok(tree_path) => tree_path,
+        Err(e) => {
+            eprintln!("Unable to find the tree file: {:?}", e);
+            return 2;
+        }
+    };
+    let tree = match DeviceTree::from_file(&tree_path, profile) {
+        Ok(tree) => tree,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return 2;
+        }
+    };
+
+    let store = match load_store(logger) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Unable to load the drivers: {:?}", e);
+            return 2;
+        }
+    };
+
+    let issues = validate(&tree, &store);
+    for issue in issues.iter() {
+        match issue.kind.blocks_production() {
+            true => println!("error: {}", issue),
+            false => println!("warning: {}", issue),
+        }
+    }
+    let errors = issues.iter().filter(|i| i.kind.blocks_production()).count();
+    println!(
+        "{} device(s) checked, {} error(s) and {} warning(s) found",
+        tree.devices.len(),
+        errors,
+        issues.len() - errors
+    );
+    match errors {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+///
+/// Store of all the drivers the platform would provide
+///
+fn load_store(logger: Logger) -> Result<JsonValue, Error> {
+    let config = config::get_platform_config(logger);
+    //
+    // Only the stores are read, the notifications are not followed
+    let (notifications, _) = notification_pipeline::pipeline(1);
+    let mut plugin_manager = PluginsManager::new(notifications, false, false, false);
+    plugin_manager.set_selection(PluginSelection::from_config(config.plugins.as_ref())?);
+
+    //
+    // The check is meant to report all the problems, never stop on a plugin
+    plugin_manager.load_system_plugins(false)?;
+
+    #[cfg(feature = "built-in-drivers")]
+    let built_in = {
+        let mut factory = panduza_platform_core::Factory::new();
+        factory.add_producers(crate::built_in::plugin_producers());
+        factory.store()
+    };
+    #[cfg(not(feature = "built-in-drivers"))]
+    let built_in = panduza_platform_core::Store::default();
+
+    plugin_manager.merge_stores(&built_in).into_json_value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_tree::DeviceEntry;
+    use panduza_platform_core::ProductionOrder;
+    use serde_json::json;
+
+    fn store() -> JsonValue {
+        json!({
+            "manuf.model": {
+                "description": "power supply",
+                "settings": {
+                    "port": { "type": "string", "required": true },
+                    "baudrate": { "type": "integer" }
+                }
+            }
+        })
+    }
+
+    fn tree(devices: JsonValue) -> DeviceTree {
+        let mut tree = DeviceTree::default();
+        for device in devices.as_array().unwrap() {
+            let order: ProductionOrder = serde_json::from_value(device.clone()).unwrap();
+            tree.devices.push(DeviceEntry::from(order));
+        }
+        tree
+    }
+
+    fn kinds(issues: &Vec<TreeIssue>) -> Vec<TreeIssueKind> {
+        issues.iter().map(|i| i.kind.clone()).collect()
+    }
+
+    #[test]
+    fn valid_device_has_no_issue() {
+        let tree = tree(json!([
+            { "name": "psu", "dref": "manuf.model", "settings": { "port": "/dev/ttyUSB0", "baudrate": 9600 } }
+        ]));
+        assert!(validate(&tree, &store()).is_empty());
+    }
+
+    #[test]
+    fn unknown_driver_and_missing_setting_block_the_production() {
+        let tree = tree(json!([
+            { "name": "psu_a", "dref": "manuf.other", "settings": {} },
+            { "name": "psu_b", "dref": "manuf.model", "settings": { "baudrate": 9600 } }
+        ]));
+        let issues = validate(&tree, &store());
+        assert_eq!(
+            kinds(&issues),
+            vec![TreeIssueKind::UnknownDriver, TreeIssueKind::MissingSetting]
+        );
+        assert_eq!(issues[0].instance, "psu_a");
+        assert_eq!(issues[1].instance, "psu_b");
+        assert!(issues.iter().all(|i| i.kind.blocks_production()));
+    }
+
+    #[test]
+    fn wrong_type_and_unknown_setting_are_warnings() {
+        let tree = tree(json!([
+            { "name": "psu", "dref": "manuf.model", "settings": { "port": "/dev/ttyUSB0", "baudrate": "fast", "parity": "none" } }
+        ]));
+        let issues = validate(&tree, &store());
+        assert_eq!(
+            kinds(&issues),
+            vec![TreeIssueKind::WrongType, TreeIssueKind::UnknownSetting]
+        );
+        assert!(issues.iter().all(|i| !i.kind.blocks_production()));
+    }
+
+    #[test]
+    fn dependency_and_limit_problems_are_warnings() {
+        let mut tree = tree(json!([
+            { "name": "psu", "dref": "manuf.model", "settings": { "port": "/dev/ttyUSB0" } }
+        ]));
+        tree.devices[0].depends_on = vec!["relay".to_string()];
+        tree.devices[0].limits.insert(
+            "voltage".to_string(),
+            serde_json::from_value(json!({ "min": 5.0, "max": 1.0 })).unwrap(),
+        );
+        let issues = validate(&tree, &store());
+        assert_eq!(
+            kinds(&issues),
+            vec![
+                TreeIssueKind::InvalidDependency,
+                TreeIssueKind::InvalidLimits
+            ]
+        );
+        assert!(issues.iter().all(|i| !i.kind.blocks_production()));
+    }
+
+    #[test]
+    fn entry_line_finds_the_device_in_each_format() {
+        let json = "{\n  \"devices\": [\n    { \"name\": \"psu\", \"dref\": \"manuf.model\" },\n    {\n      \"name\": \"relay\"\n    }\n  ]\n}";
+        assert_eq!(entry_line(json, "psu"), Some(3));
+        assert_eq!(entry_line(json, "relay"), Some(5));
+
+        let toml = "[[devices]]\nname = \"psu\"\n\n[[devices]]\nname = 'relay' # comment\n";
+        assert_eq!(entry_line(toml, "psu"), Some(2));
+        assert_eq!(entry_line(toml, "relay"), Some(5));
+
+        let yaml = "devices:\n  - name: psu\n    dref: manuf.model\n  - name: relay\n";
+        assert_eq!(entry_line(yaml, "psu"), Some(2));
+        assert_eq!(entry_line(yaml, "relay"), Some(4));
+
+        //
+        // Names that only start like the device are not matched
+        assert_eq!(entry_line(yaml, "ps"), None);
+        assert_eq!(entry_line(json, "other"), None);
+    }
+}
@@ -0,0 +1,250 @@
+use panduza_platform_core::env::system_default_config_dir;
+use panduza_platform_core::Error;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+///
+/// Name of the variables file, next to 'platform.toml'
+///
+static TREE_VARS_FILE_NAME: &str = "tree_vars.toml";
+
+/// Variables that can be used in the device tree with '${NAME}'
+///
+/// They come from the environment and from the variables file of the host.
+/// The environment takes precedence, so a value of the file can be overridden for a run.
+/// '$${' is written as a literal '${'.
+///
+#[derive(Default, Debug, Clone)]
+pub struct TreeVariables {
+    ///
+    /// Values of the variables file, they can be any toml value
+    ///
+    values: HashMap<String, JsonValue>,
+
+    ///
+    /// Variables file of the host
+    ///
+    file: PathBuf,
+}
+
+impl TreeVariables {
+    ///
+    /// Path of the variables file of the host
+    ///
+    pub fn default_file() -> Result<PathBuf, Error> {
+        system_default_config_dir()
+            .map(|dir| dir.join(TREE_VARS_FILE_NAME))
+            .map_err(|e| Error::Generic(format!("{:?}", e)))
+    }
+
+    ///
+    /// Read the variables file, a missing file defines no variable
+    ///
+    pub fn from_file(path: &Path) -> Result<TreeVariables, Error> {
+        let values = match path.exists() {
+            true => {
+                let content = std::fs::read_to_string(path).map_err(|e| {
+                    Error::Generic(format!(
+                        "Unable to read variables file {:?} - ({:?})",
+                        path, e
+                    ))
+                })?;
+                toml::from_str(&content).map_err(|e| {
+                    Error::Generic(format!(
+                        "Unable to parse variables file {:?} - ({})",
+                        path, e
+                    ))
+                })?
+            }
+            false => HashMap::new(),
+        };
+        Ok(TreeVariables {
+            values: values,
+            file: path.to_path_buf(),
+        })
+    }
+
+    ///
+    /// Variables file of the host
+    ///
+    pub fn file(&self) -> &PathBuf {
+        &self.file
+    }
+
+    ///
+    /// Value of a variable, None if it is not defined
+    ///
+    fn lookup(&self, name: &str) -> Option<JsonValue> {
+        match std::env::var(name) {
+            Ok(value) => Some(JsonValue::String(value)),
+            Err(_) => self.values.get(name).cloned(),
+        }
+    }
+
+    ///
+    /// Replace the variables in all the strings of the value
+    ///
+    /// A string that is only a variable takes the value with its type ("${BAUDRATE}" can be a number),
+    /// the names of the undefined variables are added to 'undefined'.
+    ///
+    pub fn substitute(&self, value: &mut JsonValue, undefined: &mut BTreeSet<String>) {
+        match value {
+            JsonValue::String(text) => {
+                if let Some(name) = whole_variable(text) {
+                    if let Some(replacement) = self.lookup(name) {
+                        *value = replacement;
+                        return;
+                    }
+                }
+                *text = self.substitute_text(text, undefined);
+            }
+            JsonValue::Array(items) => {
+                for item in items.iter_mut() {
+                    self.substitute(item, undefined);
+                }
+            }
+            JsonValue::Object(object) => {
+                for (_, item) in object.iter_mut() {
+                    self.substitute(item, undefined);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    ///
+    /// Replace the variables inside a string
+    ///
+    fn substitute_text(&self, text: &str, undefined: &mut BTreeSet<String>) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('$') {
+            result.push_str(&rest[..start]);
+            let after = &rest[start..];
+
+            if let Some(escaped) = after.strip_prefix("$${") {
+                result.push_str("${");
+                rest = escaped;
+                continue;
+            }
+
+            let end = match after.strip_prefix("${").and_then(|inner| inner.find('}')) {
+                Some(end) => end,
+                None => {
+                    result.push('$');
+                    rest = &after[1..];
+                    continue;
+                }
+            };
+            let name = &after[2..2 + end];
+            match self.lookup(name) {
+                Some(JsonValue::String(value)) => result.push_str(&value),
+                Some(value) => result.push_str(&value.to_string()),
+                None => {
+                    undefined.insert(name.to_string());
+                    result.push_str(&after[..end + 3]);
+                }
+            }
+            rest = &after[end + 3..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+///
+/// Name of the variable if the text is only '${NAME}'
+///
+fn whole_variable(text: &str) -> Option<&str> {
+    text.strip_prefix("${")
+        .and_then(|inner| inner.strip_suffix('}'))
+        .filter(|name| !name.contains('}') && !name.contains('$'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn variables() -> TreeVariables {
+        TreeVariables {
+            values: HashMap::from([
+                ("PZA_TEST_PORT".to_string(), json!("ttyUSB0")),
+                ("PZA_TEST_BAUDRATE".to_string(), json!(115200)),
+            ]),
+            file: PathBuf::from("tree_vars.toml"),
+        }
+    }
+
+    fn substitute(value: JsonValue) -> (JsonValue, BTreeSet<String>) {
+        let mut value = value;
+        let mut undefined = BTreeSet::new();
+        variables().substitute(&mut value, &mut undefined);
+        (value, undefined)
+    }
+
+    #[test]
+    fn whole_variable_keeps_the_type_of_its_value() {
+        let (value, undefined) = substitute(json!({ "baudrate": "${PZA_TEST_BAUDRATE}" }));
+        assert_eq!(value, json!({ "baudrate": 115200 }));
+        assert!(undefined.is_empty());
+    }
+
+    #[test]
+    fn variables_are_replaced_inside_strings() {
+        let (value, _) = substitute(json!([
+            "/dev/${PZA_TEST_PORT}",
+            "${PZA_TEST_PORT}@${PZA_TEST_BAUDRATE}"
+        ]));
+        assert_eq!(value, json!(["/dev/ttyUSB0", "ttyUSB0@115200"]));
+    }
+
+    #[test]
+    fn escaped_variables_are_written_literally() {
+        let (value, undefined) = substitute(json!("$${PZA_TEST_PORT} is ${PZA_TEST_PORT}"));
+        assert_eq!(value, json!("${PZA_TEST_PORT} is ttyUSB0"));
+        assert!(undefined.is_empty());
+
+        let (value, _) = substitute(json!("$${PZA_TEST_UNDEFINED}"));
+        assert_eq!(value, json!("${PZA_TEST_UNDEFINED}"));
+    }
+
+    #[test]
+    fn undefined_variables_are_reported_and_kept() {
+        let (value, undefined) = substitute(json!({
+            "port": "${PZA_TEST_UNDEFINED}",
+            "path": "/dev/${PZA_TEST_OTHER_UNDEFINED}"
+        }));
+        assert_eq!(
+            value,
+            json!({
+                "port": "${PZA_TEST_UNDEFINED}",
+                "path": "/dev/${PZA_TEST_OTHER_UNDEFINED}"
+            })
+        );
+        assert_eq!(
+            undefined.into_iter().collect::<Vec<_>>(),
+            vec!["PZA_TEST_OTHER_UNDEFINED", "PZA_TEST_UNDEFINED"]
+        );
+    }
+
+    #[test]
+    fn lone_dollars_are_not_variables() {
+        let (value, undefined) = substitute(json!("5$ or ${unclosed"));
+        assert_eq!(value, json!("5$ or ${unclosed"));
+        assert!(undefined.is_empty());
+    }
+
+    #[test]
+    fn environment_overrides_the_variables_file() {
+        std::env::set_var("PZA_TEST_ENV_PORT", "ttyACM0");
+        let mut variables = variables();
+        variables
+            .values
+            .insert("PZA_TEST_ENV_PORT".to_string(), json!("ttyUSB1"));
+        let mut value = json!("${PZA_TEST_ENV_PORT}");
+        variables.substitute(&mut value, &mut BTreeSet::new());
+        assert_eq!(value, json!("ttyACM0"));
+    }
+}
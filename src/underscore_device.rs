@@ -1,20 +1,30 @@
 pub mod att;
 mod devices;
+pub mod estop;
+pub mod notifications;
 pub mod pack;
 pub mod pack_inner;
+pub mod plugins;
 pub mod scanner;
 pub mod store;
 pub mod structure;
+pub mod tasks;
 pub mod topic;
+pub mod tree;
 
+use crate::notification_pipeline::PipelineMetrics;
 use async_trait::async_trait;
+use estop::data::EstopDriver;
 use pack::InfoPack;
 use panduza_platform_core::{DriverOperations, Error, Instance};
+use plugins::data::PluginsDriver;
 use scanner::data::ScannerDriver;
 use std::time::Duration;
 use store::data::SharedStore;
+use tasks::data::TasksDriver;
 use tokio::time::sleep;
 pub use topic::Topic;
+use tree::data::TreeDriver;
 
 ///
 /// Main device of the platform
@@ -32,19 +42,42 @@ pub struct UnderscoreDevice {
     store: SharedStore,
 
     scanner_driver: ScannerDriver,
+
+    tree_driver: TreeDriver,
+
+    plugins_driver: PluginsDriver,
+
+    estop_driver: EstopDriver,
+
+    tasks_driver: TasksDriver,
+
+    notification_metrics: PipelineMetrics,
 }
 
 impl UnderscoreDevice {
     ///
     /// Constructor
     ///
-    pub fn new(store: SharedStore, scanner_driver: ScannerDriver) -> (UnderscoreDevice, InfoPack) {
+    pub fn new(
+        store: SharedStore,
+        scanner_driver: ScannerDriver,
+        tree_driver: TreeDriver,
+        plugins_driver: PluginsDriver,
+        estop_driver: EstopDriver,
+        tasks_driver: TasksDriver,
+        notification_metrics: PipelineMetrics,
+    ) -> (UnderscoreDevice, InfoPack) {
         let pack = InfoPack::new();
 
         let device = UnderscoreDevice {
             pack: pack.clone(),
             store: store,
             scanner_driver: scanner_driver,
+            tree_driver: tree_driver,
+            plugins_driver: plugins_driver,
+            estop_driver: estop_driver,
+            tasks_driver: tasks_driver,
+            notification_metrics: notification_metrics,
         };
 
         (device, pack)
@@ -62,8 +95,33 @@ impl DriverOperations for UnderscoreDevice {
         store::mount(instance.clone(), self.store.clone()).await?;
 
         //
+        // Mount the loaded plugins
+        plugins::mount(instance.clone(), self.plugins_driver.clone()).await?;
+
+        //
+        //
+        scanner::mount(
+            instance.clone(),
+            self.scanner_driver.clone(),
+            self.tree_driver.clone(),
+        )
+        .await?;
+
+        //
+        // Mount the device tree
+        tree::mount(instance.clone(), self.tree_driver.clone()).await?;
+
+        //
+        // Mount the emergency stop
+        estop::mount(instance.clone(), self.estop_driver.clone()).await?;
+
+        //
+        // Mount the task table
+        tasks::mount(instance.clone(), self.tasks_driver.clone()).await?;
+
         //
-        scanner::mount(instance.clone(), self.scanner_driver.clone()).await?;
+        // Mount the counters of the notification pipeline
+        notifications::mount(instance.clone(), self.notification_metrics.clone()).await?;
 
         //
         // Mount devices
@@ -18,6 +18,30 @@ pub async fn mount(mut instance: Instance, pack: InfoPack) -> Result<(), Error>
     // instance_attributes: Arc<Mutex<HashMap<String, JsonAttServer>>>,
     let instance_attributes = Arc::new(Mutex::new(HashMap::new()));
 
+    //
+    // Last productions of the platform, to explain why a device is missing
+    let att_history = instance
+        .create_attribute("production_history")
+        .with_ro()
+        .finish_as_json()
+        .await?;
+    att_history
+        .set(pack.production_history_as_json_value()?)
+        .await?;
+
+    let pack_clone3 = pack.clone();
+    instance
+        .spawn("production_history/watcher", async move {
+            let history_change = pack_clone3.production_history_change_notifier();
+            loop {
+                history_change.notified().await;
+                att_history
+                    .set(pack_clone3.production_history_as_json_value()?)
+                    .await?;
+            }
+        })
+        .await;
+
     //
     // state of each devices
     let mut interface_devices = instance.create_class("devices").finish().await;
@@ -43,6 +67,11 @@ pub async fn mount(mut instance: Instance, pack: InfoPack) -> Result<(), Error>
                 log_trace!(logger, "{:?}", pack_status);
 
                 let mut lock = instance_attributes_clone.lock().await;
+
+                //
+                // Drop the attributes of the destroyed instances
+                lock.retain(|name, _| pack_status.iter().any(|status| &status.0 == name));
+
                 for status in pack_status {
                     if !lock.contains_key(&status.0) {
                         let att = interface_devices
@@ -58,7 +87,8 @@ pub async fn mount(mut instance: Instance, pack: InfoPack) -> Result<(), Error>
                         .unwrap()
                         .set(json!({
                             "state": status.1.to_string(),
-                            "alerts": status.2
+                            "alerts": status.2,
+                            "production": status.3
                         }))
                         .await?;
                 }
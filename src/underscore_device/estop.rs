@@ -0,0 +1,102 @@
+pub mod data;
+
+use data::{EstopCommand, EstopDriver};
+use panduza_platform_core::{log_debug, log_warn, Container, JsonAttServer, Logger};
+use panduza_platform_core::{spawn_on_command, Error, Instance};
+
+///
+/// Mount the emergency stop attributes
+///
+/// '_/estop' commands:
+/// "trigger" => every produced instance is driven to the safe values declared in the tree,
+///              its commands are refused and no production is done until the re-arm
+/// "rearm"   => the commands are accepted again and the pending productions resume
+///
+/// '_/estop_status' json with the result of the last trigger
+/// {
+///     "engaged": true,
+///     "time": "...",
+///     "devices": { "psu_1": { "outcome": "safe" }, "psu_2": { "outcome": "failed", "reason": "..." } }
+/// }
+///
+pub async fn mount(mut instance: Instance, driver: EstopDriver) -> Result<(), Error> {
+    //
+    // Create the attributes
+    let att_estop = instance
+        .create_attribute("estop")
+        .with_wo()
+        .finish_as_json()
+        .await?;
+    let att_status = instance
+        .create_attribute("estop_status")
+        .with_ro()
+        .finish_as_json()
+        .await?;
+
+    //
+    //
+    att_status.set(driver.into_json_value().await?).await?;
+
+    //
+    //
+    let status_has_changed = driver.change_notifier.clone();
+
+    //
+    //
+    let driver_2 = driver.clone();
+    instance
+        .spawn("estop_watcher", async move {
+            //
+            loop {
+                //
+                // Wait for status change
+                status_has_changed.notified().await;
+
+                let value = driver_2.into_json_value().await?;
+                att_status.set(value).await?;
+            }
+        })
+        .await;
+
+    //
+    // Execute action on each command received
+    let logger_2 = instance.logger.clone();
+    let att_estop_2 = att_estop.clone();
+    spawn_on_command!(
+        "on_command => _/estop",
+        instance,
+        att_estop_2,
+        on_command(logger_2.clone(), att_estop_2.clone(), driver.clone())
+    );
+
+    //
+    //
+    Ok(())
+}
+
+///
+///
+///
+async fn on_command(
+    logger: Logger,
+    mut att_estop: JsonAttServer,
+    mut driver: EstopDriver,
+) -> Result<(), Error> {
+    while let Some(command) = att_estop.pop_cmd().await {
+        //
+        // Log
+        log_debug!(logger, "Estop command received '{:?}'", command);
+
+        //
+        // A bad command must not stop the attribute
+        match serde_json::from_value::<EstopCommand>(command) {
+            Ok(estop_command) => {
+                driver.request_command(estop_command).await;
+            }
+            Err(e) => {
+                log_warn!(logger, "Invalid estop command: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use panduza_platform_core::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+
+///
+/// Commands of the emergency stop
+///
+/// "trigger"
+/// "rearm"
+///
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EstopCommand {
+    Trigger,
+    Rearm,
+}
+
+///
+/// Result of the emergency stop on an instance
+///
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum EstopOutcome {
+    /// The safe values are reached, the commands are held
+    Safe,
+    /// The tree declares no safe value for the instance, its commands are held
+    Held,
+    /// The safe values are not confirmed, the commands are held anyway
+    Failed(String),
+}
+
+///
+/// Content of the estop status attribute
+///
+#[derive(Serialize, Debug, Clone, Default)]
+struct EstopStatus {
+    /// True until the re-arm
+    engaged: bool,
+    /// Time of the last trigger
+    time: Option<String>,
+    /// Result for each instance held by the last trigger
+    devices: BTreeMap<String, EstopOutcome>,
+}
+
+#[derive(Clone)]
+///
+///
+///
+pub struct EstopDriver {
+    ///
+    /// When user triggered or re-armed the emergency stop
+    ///
+    pub request_notifier: Arc<Notify>,
+
+    ///
+    /// When the status changed
+    ///
+    pub change_notifier: Arc<Notify>,
+
+    ///
+    /// Commands waiting for the platform
+    ///
+    requests: Arc<Mutex<Vec<EstopCommand>>>,
+
+    ///
+    ///
+    ///
+    status: Arc<Mutex<EstopStatus>>,
+}
+
+impl EstopDriver {
+    ///
+    ///
+    ///
+    pub fn new() -> Self {
+        Self {
+            request_notifier: Arc::new(Notify::new()),
+            change_notifier: Arc::new(Notify::new()),
+            requests: Arc::new(Mutex::new(Vec::new())),
+            status: Arc::new(Mutex::new(EstopStatus::default())),
+        }
+    }
+
+    ///
+    /// Queue a command for the platform
+    ///
+    pub async fn request_command(&mut self, command: EstopCommand) {
+        self.requests.lock().await.push(command);
+        //
+        // The processor may be busy, keep the request for it
+        self.request_notifier.notify_one();
+    }
+
+    ///
+    /// Commands requested since the last call
+    ///
+    pub async fn take_requests(&self) -> Vec<EstopCommand> {
+        std::mem::take(&mut *self.requests.lock().await)
+    }
+
+    ///
+    /// Publish the result of a trigger
+    ///
+    pub async fn set_engaged(&mut self, devices: BTreeMap<String, EstopOutcome>) {
+        let mut status = self.status.lock().await;
+        status.engaged = true;
+        status.time = Some(chrono::Local::now().to_rfc3339());
+        status.devices = devices;
+        drop(status);
+        self.change_notifier.notify_waiters();
+    }
+
+    ///
+    /// Publish the re-arm, the results of the last trigger are kept
+    ///
+    pub async fn set_rearmed(&mut self) {
+        self.status.lock().await.engaged = false;
+        self.change_notifier.notify_waiters();
+    }
+
+    ///
+    ///
+    ///
+    pub async fn into_json_value(&self) -> Result<JsonValue, Error> {
+        let status = self.status.lock().await;
+        serde_json::to_value(&*status).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+}
@@ -0,0 +1,51 @@
+use crate::notification_pipeline::PipelineMetrics;
+use panduza_platform_core::{Container, Error, Instance};
+use std::time::Duration;
+
+///
+/// The counters change with each notification, they are published at most once per period
+///
+static METRICS_PUBLISH_PERIOD: Duration = Duration::from_secs(1);
+
+///
+/// Mount the notifications attribute
+///
+/// '_/notifications' json with the counters of the notification pipeline
+/// {
+///     "capacity": 1024,
+///     "pending": 0,
+///     "delivered": 42,
+///     "dropped": 0
+/// }
+///
+pub async fn mount(mut instance: Instance, metrics: PipelineMetrics) -> Result<(), Error> {
+    //
+    // Create the attribute
+    let att_notifications = instance
+        .create_attribute("notifications")
+        .with_ro()
+        .finish_as_json()
+        .await?;
+    att_notifications.set(metrics.into_json_value()?).await?;
+
+    //
+    //
+    let metrics_have_changed = metrics.change_notifier.clone();
+    instance
+        .spawn("notifications_watcher", async move {
+            //
+            loop {
+                //
+                // Wait for counter change
+                metrics_have_changed.notified().await;
+                tokio::time::sleep(METRICS_PUBLISH_PERIOD).await;
+
+                att_notifications.set(metrics.into_json_value()?).await?;
+            }
+        })
+        .await;
+
+    //
+    //
+    Ok(())
+}
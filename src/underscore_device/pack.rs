@@ -3,7 +3,10 @@ use std::sync::Arc;
 use panduza_platform_core::{instance::State, Error, Notification};
 use tokio::sync::Notify;
 
-use super::{pack_inner::InfoPackInner, structure::instance::Alert};
+use super::{
+    pack_inner::InfoPackInner,
+    structure::instance::{Alert, ProductionRecord},
+};
 
 #[derive(Clone)]
 pub struct InfoPack {
@@ -57,10 +60,73 @@ impl InfoPack {
         }
     }
 
-    pub fn pack_instance_status(&self) -> Vec<(String, State, Vec<Alert>)> {
+    /// Forget an instance that has been destroyed
+    ///
+    pub fn remove_instance(&self, instance_name: &String) {
+        self.inner.lock().unwrap().remove_instance(instance_name);
+    }
+
+    /// Raise an alert from the platform on an instance
+    ///
+    pub fn raise_alert(&self, instance_name: &String, alert: Alert) {
+        self.inner.lock().unwrap().raise_alert(instance_name, alert);
+    }
+
+    /// Raise an alert on an instance only if it is known, a client can use any topic
+    ///
+    pub fn raise_alert_if_known(&self, instance_name: &String, alert: Alert) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .raise_alert_if_known(instance_name, alert)
+    }
+
+    /// Put an instance in error from the platform
+    ///
+    pub fn set_instance_error(&self, instance_name: &String, alert: Alert) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_instance_error(instance_name, alert);
+    }
+
+    /// Record the result of a production order
+    ///
+    pub fn record_production(&self, record: ProductionRecord) {
+        self.inner.lock().unwrap().record_production(record);
+    }
+
+    /// Remove an alert raised by the platform on an instance
+    ///
+    pub fn remove_alert(&self, instance_name: &String, alert: &Alert) {
+        self.inner
+            .lock()
+            .unwrap()
+            .remove_alert(instance_name, alert);
+    }
+
+    pub fn pack_instance_status(
+        &self,
+    ) -> Vec<(String, State, Vec<Alert>, Option<ProductionRecord>)> {
         self.inner.lock().unwrap().pack_instance_status()
     }
 
+    ///
+    ///
+    pub fn production_history_change_notifier(&self) -> Arc<Notify> {
+        self.inner
+            .lock()
+            .unwrap()
+            .production_history_change_notifier()
+    }
+
+    pub fn production_history_as_json_value(&self) -> Result<serde_json::Value, Error> {
+        self.inner
+            .lock()
+            .unwrap()
+            .production_history_into_json_value()
+    }
+
     ///
     ///
     pub fn instance_status_change_notifier(&self) -> Arc<Notify> {
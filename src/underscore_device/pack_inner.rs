@@ -1,7 +1,7 @@
 use super::{
     structure::{
         attribute::AttributElement,
-        instance::{Alert, InstanceElement},
+        instance::{Alert, InstanceElement, ProductionRecord},
         Structure,
     },
     Topic,
@@ -11,9 +11,15 @@ use panduza_platform_core::{
     instance::State, runtime::notification::EnablementNotification, AlertNotification,
     AttributeNotification, ClassNotification, Error, StateNotification,
 };
+use std::collections::VecDeque;
 use std::sync::Arc;
 use tokio::sync::Notify;
 
+///
+/// Number of productions kept in the history
+///
+static PRODUCTION_HISTORY_SIZE: usize = 100;
+
 pub struct InfoPackInner {
     ///
     ///
@@ -29,6 +35,16 @@ pub struct InfoPackInner {
     ///
     ///
     instance_structure_change_notifier: Arc<Notify>,
+
+    ///
+    /// Last productions, the oldest first
+    ///
+    production_history: VecDeque<ProductionRecord>,
+
+    ///
+    /// Notified when a production is recorded
+    ///
+    production_history_change_notifier: Arc<Notify>,
 }
 
 impl InfoPackInner {
@@ -39,6 +55,8 @@ impl InfoPackInner {
             structure: Structure::default(),
             instance_status_change_notifier: Arc::new(Notify::new()),
             instance_structure_change_notifier: Arc::new(Notify::new()),
+            production_history: VecDeque::new(),
+            production_history_change_notifier: Arc::new(Notify::new()),
         }
     }
 
@@ -52,6 +70,83 @@ impl InfoPackInner {
         }
     }
 
+    ///
+    /// Forget an instance that has been destroyed
+    ///
+    pub fn remove_instance(&mut self, instance_name: &String) {
+        if self.structure.remove_instance(instance_name) {
+            self.instance_status_change_notifier.notify_waiters();
+            self.instance_structure_change_notifier.notify_waiters();
+        }
+    }
+
+    ///
+    /// Raise an alert from the platform on an instance
+    ///
+    pub fn raise_alert(&mut self, instance_name: &String, alert: Alert) {
+        self.create_instance_if_not_exists(instance_name);
+        if let Some(instance) = self.structure.get_mut_instance(instance_name) {
+            instance.add_alert(alert);
+        }
+        self.instance_status_change_notifier.notify_waiters();
+    }
+
+    ///
+    /// Raise an alert on an instance only if it is known, false otherwise
+    ///
+    pub fn raise_alert_if_known(&mut self, instance_name: &String, alert: Alert) -> bool {
+        match self.structure.get_mut_instance(instance_name) {
+            Some(instance) => instance.add_alert(alert),
+            None => return false,
+        }
+        self.instance_status_change_notifier.notify_waiters();
+        true
+    }
+
+    ///
+    /// Put an instance in error from the platform, the alert gives the reason
+    ///
+    pub fn set_instance_error(&mut self, instance_name: &String, alert: Alert) {
+        self.create_instance_if_not_exists(instance_name);
+        if let Some(instance) = self.structure.get_mut_instance(instance_name) {
+            instance.set_state(State::Error);
+            instance.add_alert(alert);
+        }
+        self.instance_status_change_notifier.notify_waiters();
+    }
+
+    ///
+    /// Record the result of a production order, a failed one puts the instance in error
+    ///
+    pub fn record_production(&mut self, record: ProductionRecord) {
+        self.create_instance_if_not_exists(&record.instance);
+        if let Some(instance) = self.structure.get_mut_instance(&record.instance) {
+            if !record.outcome.is_ok() {
+                instance.set_state(State::Error);
+            }
+            instance.set_production(record.clone());
+        }
+
+        self.production_history.push_back(record);
+        while self.production_history.len() > PRODUCTION_HISTORY_SIZE {
+            self.production_history.pop_front();
+        }
+
+        self.instance_status_change_notifier.notify_waiters();
+        self.production_history_change_notifier.notify_waiters();
+    }
+
+    ///
+    /// Remove an alert raised by the platform on an instance
+    ///
+    pub fn remove_alert(&mut self, instance_name: &String, alert: &Alert) {
+        if let Some(instance) = self.structure.get_mut_instance(instance_name) {
+            if instance.remove_alert(alert) {
+                self.instance_status_change_notifier.notify_waiters();
+            }
+        }
+    }
+
     ///
     ///
     ///
@@ -199,10 +294,20 @@ impl InfoPackInner {
 
     ///
     ///
-    pub fn pack_instance_status(&self) -> Vec<(String, State, Vec<Alert>)> {
+    pub fn pack_instance_status(
+        &self,
+    ) -> Vec<(String, State, Vec<Alert>, Option<ProductionRecord>)> {
         self.structure.pack_instance_status()
     }
 
+    ///
+    ///
+    ///
+    pub fn production_history_into_json_value(&self) -> Result<serde_json::Value, Error> {
+        serde_json::to_value(&self.production_history)
+            .map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+
     ///
     ///
     ///
@@ -222,4 +327,76 @@ impl InfoPackInner {
     pub fn instance_structure_change_notifier(&self) -> Arc<Notify> {
         self.instance_structure_change_notifier.clone()
     }
+
+    ///
+    ///
+    pub fn production_history_change_notifier(&self) -> Arc<Notify> {
+        self.production_history_change_notifier.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::underscore_device::structure::instance::ProductionOutcome;
+
+    #[test]
+    fn removed_instance_leaves_the_status_but_not_the_history() {
+        let mut pack = InfoPackInner::new();
+        let name = "psu".to_string();
+        pack.record_production(ProductionRecord::now(
+            name.clone(),
+            "manuf.model".to_string(),
+            ProductionOutcome::Ok,
+        ));
+        pack.raise_alert(&name, Alert::new("pza/psu", "alert"));
+        assert_eq!(pack.pack_instance_status().len(), 1);
+
+        pack.remove_instance(&name);
+        assert!(pack.pack_instance_status().is_empty());
+        assert_eq!(
+            pack.production_history_into_json_value().unwrap()[0]["instance"],
+            "psu"
+        );
+
+        //
+        // Removing an unknown instance is harmless
+        pack.remove_instance(&name);
+        assert!(pack.pack_instance_status().is_empty());
+    }
+
+    #[test]
+    fn failed_productions_put_the_instance_in_error() {
+        let mut pack = InfoPackInner::new();
+        pack.record_production(ProductionRecord::now(
+            "psu".to_string(),
+            "manuf.model".to_string(),
+            ProductionOutcome::DriverError("no device".to_string()),
+        ));
+
+        let status = pack.pack_instance_status();
+        assert_eq!(status[0].0, "psu");
+        assert!(matches!(status[0].1, State::Error));
+        assert_eq!(
+            status[0].3.as_ref().unwrap().outcome,
+            ProductionOutcome::DriverError("no device".to_string())
+        );
+    }
+
+    #[test]
+    fn production_history_keeps_the_last_records() {
+        let mut pack = InfoPackInner::new();
+        for i in 0..PRODUCTION_HISTORY_SIZE + 5 {
+            pack.record_production(ProductionRecord::now(
+                format!("psu_{}", i),
+                "manuf.model".to_string(),
+                ProductionOutcome::Ok,
+            ));
+        }
+
+        let history = pack.production_history_into_json_value().unwrap();
+        let history = history.as_array().unwrap();
+        assert_eq!(history.len(), PRODUCTION_HISTORY_SIZE);
+        assert_eq!(history[0]["instance"], "psu_5");
+    }
 }
@@ -0,0 +1,103 @@
+pub mod data;
+
+use data::{PluginCommand, PluginsDriver};
+use panduza_platform_core::{log_debug, log_warn, Container, JsonAttServer, Logger};
+use panduza_platform_core::{spawn_on_command, Error, Instance};
+
+///
+/// Mount the plugins attribute
+///
+/// json with the plugins loaded by the platform and the ones it refused
+/// {
+///     "plugins": [
+///         { "path", "directory", "metadata", "isolated", "producers": ["manuf.model"] }
+///     ],
+///     "errors": [ { "path", "error" } ],
+///     "built_in": ["manuf.model"],
+///     "conflicts": [ { "dref", "providers": ["plugin path", "built-in"], "selected" } ]
+/// }
+///
+/// commands manage the plugin files at runtime:
+/// { "load": "plugin path" }
+/// { "unload": "plugin path" }
+/// { "reload": "plugin path" }
+/// (unload and reload only apply to isolated plugins)
+///
+pub async fn mount(mut instance: Instance, driver: PluginsDriver) -> Result<(), Error> {
+    //
+    // Create the attribute
+    let att_plugins = instance
+        .create_attribute("plugins")
+        .with_rw()
+        .finish_as_json()
+        .await?;
+
+    //
+    //
+    let value = driver.into_json_value().await?;
+    att_plugins.set(value).await?;
+
+    //
+    //
+    let plugins_have_changed = driver.change_notifier.clone();
+
+    //
+    //
+    let driver_2 = driver.clone();
+    let att_plugins_2 = att_plugins.clone();
+    instance
+        .spawn("plugins_watcher", async move {
+            //
+            loop {
+                //
+                // Wait for plugins change
+                plugins_have_changed.notified().await;
+
+                let value = driver_2.into_json_value().await?;
+                att_plugins_2.set(value).await?;
+            }
+        })
+        .await;
+
+    //
+    // Execute action on each command received
+    let logger_2 = instance.logger.clone();
+    let att_plugins_3 = att_plugins.clone();
+    spawn_on_command!(
+        "on_command => _/plugins",
+        instance,
+        att_plugins_3,
+        on_command(logger_2.clone(), att_plugins_3.clone(), driver.clone())
+    );
+
+    //
+    //
+    Ok(())
+}
+
+///
+///
+///
+async fn on_command(
+    logger: Logger,
+    mut att_plugins: JsonAttServer,
+    mut driver: PluginsDriver,
+) -> Result<(), Error> {
+    while let Some(command) = att_plugins.pop_cmd().await {
+        //
+        // Log
+        log_debug!(logger, "Plugins command received '{:?}'", command);
+
+        //
+        // A bad command must not stop the attribute
+        match serde_json::from_value::<PluginCommand>(command) {
+            Ok(plugin_command) => {
+                driver.request_command(plugin_command).await;
+            }
+            Err(e) => {
+                log_warn!(logger, "Invalid plugins command: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
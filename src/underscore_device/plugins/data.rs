@@ -0,0 +1,262 @@
+use panduza_platform_core::Error;
+use panduza_platform_core::Store;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+
+use crate::plugins_manager::PluginMetadata;
+
+///
+/// Runtime management of the plugin files
+///
+/// { "load": "/path/to/plugin.so" }
+/// { "unload": "/path/to/plugin.so" }
+/// { "reload": "/path/to/plugin.so" }
+///
+/// Unload and reload are refused for plugins that are not isolated.
+///
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginCommand {
+    Load(PathBuf),
+    Unload(PathBuf),
+    Reload(PathBuf),
+}
+
+///
+/// Plugin loaded by the platform
+///
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PluginInfo {
+    /// File of the plugin
+    pub path: String,
+    /// Directory from which the plugin has been loaded
+    pub directory: String,
+    /// Informations exported by the plugin
+    pub metadata: PluginMetadata,
+    /// True if the plugin runs in its own process
+    pub isolated: bool,
+    /// References of the drivers that the plugin can produce
+    pub producers: Vec<String>,
+    /// True if the plugin can destroy its instances (destroy, tree reload, unload)
+    pub unproduce: bool,
+}
+
+///
+/// Plugin file that the platform refused to load
+///
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PluginLoadError {
+    /// File of the plugin
+    pub path: String,
+    /// Reason of the failure
+    pub error: String,
+}
+
+///
+/// Driver reference provided by several producers
+///
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ProducerConflict {
+    /// Driver reference
+    pub dref: String,
+    /// Plugin files or 'built-in' that provide it
+    pub providers: Vec<String>,
+    /// Provider used to produce it
+    pub selected: String,
+}
+
+///
+/// Content of the plugins attribute
+///
+#[derive(Serialize, Debug, Clone, Default)]
+struct PluginsInfo {
+    plugins: Vec<PluginInfo>,
+    errors: Vec<PluginLoadError>,
+    built_in: Vec<String>,
+    /// Always false, the local runtime of the core cannot stop its instances
+    built_in_unproduce: bool,
+    conflicts: Vec<ProducerConflict>,
+}
+
+#[derive(Clone)]
+///
+///
+///
+pub struct PluginsDriver {
+    ///
+    /// Notified when a data change
+    ///
+    pub change_notifier: Arc<Notify>,
+
+    ///
+    /// When user requested a plugin management
+    ///
+    pub request_notifier: Arc<Notify>,
+
+    ///
+    ///
+    ///
+    info: Arc<Mutex<PluginsInfo>>,
+
+    ///
+    /// Commands waiting to be processed by the platform
+    ///
+    requests: Arc<Mutex<Vec<PluginCommand>>>,
+}
+
+impl PluginsDriver {
+    ///
+    ///
+    ///
+    pub fn new() -> Self {
+        Self {
+            change_notifier: Arc::new(Notify::new()),
+            request_notifier: Arc::new(Notify::new()),
+            info: Arc::new(Mutex::new(PluginsInfo::default())),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    ///
+    /// Ask the platform to process this command
+    ///
+    pub async fn request_command(&mut self, command: PluginCommand) {
+        self.requests.lock().await.push(command);
+        //
+        // The processor may be busy, keep the request for it
+        self.request_notifier.notify_one();
+    }
+
+    ///
+    /// Commands requested since the last call
+    ///
+    pub async fn take_requests(&self) -> Vec<PluginCommand> {
+        std::mem::take(&mut *self.requests.lock().await)
+    }
+
+    ///
+    /// Replace the plugins after a load from the platform
+    ///
+    pub async fn set_plugins(&mut self, plugins: Vec<PluginInfo>, errors: Vec<PluginLoadError>) {
+        let mut info = self.info.lock().await;
+        info.plugins = plugins;
+        info.errors = errors;
+        drop(info);
+        self.change_notifier.notify_waiters();
+    }
+
+    ///
+    /// Replace the producers built in the platform
+    ///
+    pub async fn set_built_in(&mut self, store: &Store) -> Result<(), Error> {
+        self.info.lock().await.built_in = store_producers(store)?;
+        self.change_notifier.notify_waiters();
+        Ok(())
+    }
+
+    ///
+    /// Replace the drivers provided by several producers
+    ///
+    pub async fn set_conflicts(&mut self, conflicts: Vec<ProducerConflict>) {
+        self.info.lock().await.conflicts = conflicts;
+        self.change_notifier.notify_waiters();
+    }
+
+    ///
+    ///
+    ///
+    pub async fn into_json_value(&self) -> Result<JsonValue, Error> {
+        let info = self.info.lock().await;
+        serde_json::to_value(&*info).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+}
+
+///
+/// References of the drivers of a store
+///
+pub fn store_producers(store: &Store) -> Result<Vec<String>, Error> {
+    let mut producers: Vec<String> = match store.into_json_value()? {
+        JsonValue::Object(map) => map.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    producers.sort();
+    Ok(producers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn plugins_attribute_lists_plugins_errors_and_conflicts() {
+        let mut driver = PluginsDriver::new();
+        driver
+            .set_plugins(
+                vec![PluginInfo {
+                    path: "/plugins/libpza_a.so".to_string(),
+                    directory: "/plugins".to_string(),
+                    producers: vec!["manuf.model".to_string()],
+                    ..Default::default()
+                }],
+                vec![PluginLoadError {
+                    path: "/plugins/libpza_b.so".to_string(),
+                    error: "ABI".to_string(),
+                }],
+            )
+            .await;
+        driver
+            .set_conflicts(vec![ProducerConflict {
+                dref: "manuf.model".to_string(),
+                providers: vec!["/plugins/libpza_a.so".to_string(), "built-in".to_string()],
+                selected: "built-in".to_string(),
+            }])
+            .await;
+
+        let value = driver.into_json_value().await.unwrap();
+        assert_eq!(value["plugins"][0]["producers"][0], "manuf.model");
+        assert_eq!(value["plugins"][0]["unproduce"], false);
+        assert_eq!(value["errors"][0]["path"], "/plugins/libpza_b.so");
+        assert_eq!(value["conflicts"][0]["selected"], "built-in");
+        assert_eq!(value["built_in_unproduce"], false);
+    }
+
+    #[tokio::test]
+    async fn commands_are_kept_until_processed() {
+        let mut driver = PluginsDriver::new();
+        for command in [
+            json!({ "load": "/plugins/a.so" }),
+            json!({ "reload": "/plugins/a.so" }),
+            json!({ "unload": "/plugins/a.so" }),
+        ] {
+            let command: PluginCommand = serde_json::from_value(command).unwrap();
+            driver.request_command(command).await;
+        }
+        assert!(
+            serde_json::from_value::<PluginCommand>(json!({ "drop": "/plugins/a.so" })).is_err()
+        );
+
+        //
+        // Notified even if the processor was not waiting yet
+        tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            driver.request_notifier.notified(),
+        )
+        .await
+        .unwrap();
+        let commands = driver.take_requests().await;
+        assert!(matches!(
+            commands.as_slice(),
+            [
+                PluginCommand::Load(_),
+                PluginCommand::Reload(_),
+                PluginCommand::Unload(_)
+            ]
+        ));
+        assert!(driver.take_requests().await.is_empty());
+    }
+}
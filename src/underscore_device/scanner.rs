@@ -1,7 +1,8 @@
 pub mod data;
 
-use data::ScannerDriver;
-use panduza_platform_core::{log_debug, Container, Logger};
+use super::tree::data::{TreeCommand, TreeDriver};
+use data::{InstantiateCommand, ScannerDriver};
+use panduza_platform_core::{log_debug, log_warn, Container, JsonAttServer, Logger};
 use panduza_platform_core::{spawn_loop, spawn_on_command, BooleanAttServer, Error, Instance};
 use serde_json::json;
 
@@ -10,11 +11,17 @@ use serde_json::json;
 ///
 /// scanner -> interface to control a scan session
 ///      - running boolean
-///      - total_scan number
-///      - joined_scan number
-///      - instances json
+///      - cancel boolean command to stop the running session
+///      - progress json { total_scan, joined_scan, pending, cancelled }
+///      - result json { instances, errors }
+///      - instantiate json command to produce scanned instances
+///      - hotplug json { appeared, disappeared, not_produced, missing }
 ///
-pub async fn mount(mut instance: Instance, driver: ScannerDriver) -> Result<(), Error> {
+pub async fn mount(
+    mut instance: Instance,
+    driver: ScannerDriver,
+    tree_driver: TreeDriver,
+) -> Result<(), Error> {
     //
     // Create the attribute
     let mut class_scanner = instance.create_class("scanner").finish().await;
@@ -26,6 +33,22 @@ pub async fn mount(mut instance: Instance, driver: ScannerDriver) -> Result<(),
         .await?;
     att_running.set(false).await?;
 
+    let att_cancel = class_scanner
+        .create_attribute("cancel")
+        .with_rw()
+        .finish_as_boolean()
+        .await?;
+    att_cancel.set(false).await?;
+
+    let att_progress = class_scanner
+        .create_attribute("progress")
+        .with_ro()
+        .finish_as_json()
+        .await?;
+    att_progress
+        .set(driver.progress_into_json_value().await?)
+        .await?;
+
     let att_result = class_scanner
         .create_attribute("result")
         .with_ro()
@@ -33,6 +56,22 @@ pub async fn mount(mut instance: Instance, driver: ScannerDriver) -> Result<(),
         .await?;
     att_result.set(json!({})).await?;
 
+    let att_instantiate = class_scanner
+        .create_attribute("instantiate")
+        .with_rw()
+        .finish_as_json()
+        .await?;
+    att_instantiate.set(json!({})).await?;
+
+    let att_hotplug = class_scanner
+        .create_attribute("hotplug")
+        .with_ro()
+        .finish_as_json()
+        .await?;
+    att_hotplug
+        .set(driver.hotplug_into_json_value().await?)
+        .await?;
+
     //
     //
     let driver_2 = driver.clone();
@@ -44,6 +83,59 @@ pub async fn mount(mut instance: Instance, driver: ScannerDriver) -> Result<(),
         att_result.set(ppp).await?;
     });
 
+    //
+    //
+    let driver_6 = driver.clone();
+    spawn_loop!("loop => _/scanner/hotplug", instance, {
+        driver_6.hotplug_notifier.notified().await;
+        let hotplug = driver_6.hotplug_into_json_value().await?;
+
+        att_hotplug.set(hotplug).await?;
+    });
+
+    //
+    //
+    let driver_4 = driver.clone();
+    let att_running_3 = att_running.clone();
+    spawn_loop!("loop => _/scanner/progress", instance, {
+        driver_4.progress_notifier.notified().await;
+        let progress = driver_4.progress_into_json_value().await?;
+
+        att_progress.set(progress).await?;
+        att_running_3
+            .set(driver_4.is_already_running().await)
+            .await?;
+    });
+
+    //
+    //
+    let logger_4 = instance.logger.clone();
+    let att_cancel_2 = att_cancel.clone();
+    let driver_5 = driver.clone();
+    spawn_on_command!(
+        "on_command => _/scanner/cancel",
+        instance,
+        att_cancel_2,
+        on_cancel_command(logger_4.clone(), att_cancel_2.clone(), driver_5.clone())
+    );
+
+    //
+    //
+    let logger_3 = instance.logger.clone();
+    let att_instantiate_2 = att_instantiate.clone();
+    let driver_3 = driver.clone();
+    spawn_on_command!(
+        "on_command => _/scanner/instantiate",
+        instance,
+        att_instantiate_2,
+        on_instantiate_command(
+            logger_3.clone(),
+            att_instantiate_2.clone(),
+            driver_3.clone(),
+            tree_driver.clone()
+        )
+    );
+
     //
     // Execute action on each command received
     let logger_2 = instance.logger.clone();
@@ -86,3 +178,82 @@ async fn on_running_command(
     }
     Ok(())
 }
+
+///
+///
+///
+async fn on_cancel_command(
+    logger: Logger,
+    mut att_cancel: BooleanAttServer,
+    mut driver: ScannerDriver,
+) -> Result<(), Error> {
+    while let Some(command) = att_cancel.pop_cmd().await {
+        //
+        // Log
+        log_debug!(logger, "Scanner cancel command received '{:?}'", command);
+
+        if command {
+            driver.request_scanning_cancel().await;
+        }
+        att_cancel.set(false).await?;
+    }
+    Ok(())
+}
+
+///
+///
+///
+async fn on_instantiate_command(
+    logger: Logger,
+    mut att_instantiate: JsonAttServer,
+    mut driver: ScannerDriver,
+    mut tree_driver: TreeDriver,
+) -> Result<(), Error> {
+    while let Some(command) = att_instantiate.pop_cmd().await {
+        //
+        // Log
+        log_debug!(
+            logger,
+            "Scanner instantiate command received '{:?}'",
+            command
+        );
+
+        //
+        // A bad command must not stop the attribute
+        let command = match serde_json::from_value::<InstantiateCommand>(command) {
+            Ok(command) => command,
+            Err(e) => {
+                log_warn!(logger, "Invalid instantiate command: {:?}", e);
+                continue;
+            }
+        };
+        let orders = match driver.select(&command.select).await {
+            Ok(orders) => orders,
+            Err(e) => {
+                log_warn!(logger, "Instantiate command rejected: {:?}", e);
+                continue;
+            }
+        };
+        let names: Vec<String> = orders.iter().map(|po| po.name.clone()).collect();
+
+        //
+        // Persisted orders are produced by the reload of the tree
+        if command.persist {
+            for po in orders {
+                if let Err(e) = tree_driver.apply_command(TreeCommand::Add(po.into())).await {
+                    log_warn!(logger, "Unable to persist scanned instance: {:?}", e);
+                }
+            }
+        } else {
+            driver.request_instantiation(orders).await;
+        }
+
+        att_instantiate
+            .set(json!({
+                "instances": names,
+                "persist": command.persist
+            }))
+            .await?;
+    }
+    Ok(())
+}
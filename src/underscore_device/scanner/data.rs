@@ -1,11 +1,90 @@
+use crate::device_tree::DeviceIdentity;
 use panduza_platform_core::Error;
 use panduza_platform_core::ProductionOrder;
-// use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::sync::Notify;
 
+///
+/// Orders of the last scan selected by the user
+///
+/// "all", [0, 2] (indexes in the result) or ["name_a", "name_b"]
+///
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ScanSelection {
+    All(String),
+    Indexes(Vec<usize>),
+    Names(Vec<String>),
+}
+
+///
+/// Command to produce some orders of the last scan
+///
+/// { "select": "all", "persist": true }
+///
+#[derive(Deserialize, Debug)]
+pub struct InstantiateCommand {
+    /// Orders to produce
+    pub select: ScanSelection,
+    /// Also append them into the device tree file
+    #[serde(default)]
+    pub persist: bool,
+}
+
+///
+/// Progress of the current scan session
+///
+#[derive(Default, Serialize, Debug)]
+pub struct ScanProgress {
+    /// Number of scanners started
+    pub total_scan: usize,
+    /// Number of scanners that returned, failed or timed out
+    pub joined_scan: usize,
+    /// Scanners still running
+    pub pending: Vec<String>,
+    /// True if the user cancelled the session
+    pub cancelled: bool,
+}
+
+///
+/// Changes between two complete scan sessions
+///
+#[derive(Default, Serialize, Debug)]
+pub struct HotplugDiff {
+    /// Orders found now but not during the previous session
+    pub appeared: Vec<ProductionOrder>,
+    /// Orders found during the previous session but not now
+    pub disappeared: Vec<ProductionOrder>,
+    /// Orders found now that match no produced instance
+    pub not_produced: Vec<ProductionOrder>,
+    /// Produced instances that were found during the previous session but not now
+    pub missing: Vec<String>,
+}
+
+///
+/// Scanned orders do not have the names of the produced instances,
+/// they are the same device if they have the same dref and settings
+///
+fn same_device(a: &ProductionOrder, b: &ProductionOrder) -> bool {
+    a.dref == b.dref && a.settings == b.settings
+}
+
+///
+/// A produced instance is the scanned unit if it has the identity keys returned by the
+/// scanner (serial number, vid, pid), its other settings may have been changed by the user
+///
+fn same_unit(scanned: &ProductionOrder, produced: &ProductionOrder) -> bool {
+    match DeviceIdentity::of_scanned(scanned) {
+        Some(identity) => scanned.dref == produced.dref && identity.matches(produced),
+        None => same_device(scanned, produced),
+    }
+}
+
 #[derive(Clone)]
 ///
 ///
@@ -30,6 +109,56 @@ pub struct ScannerDriver {
     ///
     ///
     pub found_instances: Arc<Mutex<Vec<ProductionOrder>>>,
+
+    ///
+    /// Error of each scanner that failed during the last session
+    ///
+    pub scan_errors: Arc<Mutex<HashMap<String, String>>>,
+
+    ///
+    /// Progress of the current session
+    ///
+    progress: Arc<Mutex<ScanProgress>>,
+
+    ///
+    /// When the progress of the session changed
+    ///
+    pub progress_notifier: Arc<Notify>,
+
+    ///
+    /// When user request to cancel the session
+    ///
+    pub cancel_notifier: Arc<Notify>,
+
+    ///
+    /// When user request the production of scanned instances
+    ///
+    pub instantiate_notifier: Arc<Notify>,
+
+    ///
+    /// Orders waiting to be produced by the platform
+    ///
+    instantiate_requests: Arc<Mutex<Vec<ProductionOrder>>>,
+
+    ///
+    /// Orders found by each scanner during the current session
+    ///
+    session_instances: Arc<Mutex<HashMap<String, Vec<ProductionOrder>>>>,
+
+    ///
+    /// Orders found by each scanner during the last complete session
+    ///
+    previous_instances: Arc<Mutex<Option<HashMap<String, Vec<ProductionOrder>>>>>,
+
+    ///
+    /// Changes detected by the last complete session
+    ///
+    hotplug: Arc<Mutex<HotplugDiff>>,
+
+    ///
+    /// When a new hotplug diff is available
+    ///
+    pub hotplug_notifier: Arc<Notify>,
 }
 
 impl ScannerDriver {
@@ -42,6 +171,16 @@ impl ScannerDriver {
             update_notifier: Arc::new(Notify::new()),
             is_running: Arc::new(Mutex::new(false)),
             found_instances: Arc::new(Mutex::new(Vec::new())),
+            scan_errors: Arc::new(Mutex::new(HashMap::new())),
+            progress: Arc::new(Mutex::new(ScanProgress::default())),
+            progress_notifier: Arc::new(Notify::new()),
+            cancel_notifier: Arc::new(Notify::new()),
+            instantiate_notifier: Arc::new(Notify::new()),
+            instantiate_requests: Arc::new(Mutex::new(Vec::new())),
+            session_instances: Arc::new(Mutex::new(HashMap::new())),
+            previous_instances: Arc::new(Mutex::new(None)),
+            hotplug: Arc::new(Mutex::new(HotplugDiff::default())),
+            hotplug_notifier: Arc::new(Notify::new()),
         }
     }
 
@@ -49,22 +188,226 @@ impl ScannerDriver {
         *self.is_running.lock().await
     }
 
-    pub async fn stop_running(&self) {
-        *self.is_running.lock().await = false;
+    pub async fn request_scanning_start(&mut self) {
+        //
+        // The processor may be busy, keep the request for it
+        self.request_notifier.notify_one();
     }
 
-    pub async fn request_scanning_start(&mut self) {
-        self.request_notifier.notify_waiters();
+    pub async fn request_scanning_cancel(&mut self) {
+        self.cancel_notifier.notify_waiters();
+    }
+
+    ///
+    /// Reset the results and start a session with those scanners
+    ///
+    pub async fn start_session(&mut self, scanners: Vec<String>) {
+        *self.is_running.lock().await = true;
+        self.found_instances.lock().await.clear();
+        self.session_instances.lock().await.clear();
+        self.scan_errors.lock().await.clear();
+        *self.progress.lock().await = ScanProgress {
+            total_scan: scanners.len(),
+            joined_scan: 0,
+            pending: scanners,
+            cancelled: false,
+        };
+        self.progress_notifier.notify_waiters();
+        self.update_notifier.notify_waiters();
+    }
+
+    ///
+    /// Store the result of one scanner
+    ///
+    pub async fn join_scan(
+        &mut self,
+        scanner: String,
+        result: Result<Vec<ProductionOrder>, Error>,
+    ) {
+        match result {
+            Ok(found_instances) => {
+                self.found_instances
+                    .lock()
+                    .await
+                    .extend(found_instances.iter().cloned());
+                self.session_instances
+                    .lock()
+                    .await
+                    .insert(scanner.clone(), found_instances);
+            }
+            Err(e) => {
+                self.scan_errors
+                    .lock()
+                    .await
+                    .insert(scanner.clone(), format!("{:?}", e));
+            }
+        }
+        let mut progress = self.progress.lock().await;
+        progress.joined_scan += 1;
+        progress.pending.retain(|pending| pending != &scanner);
+        drop(progress);
+        self.progress_notifier.notify_waiters();
+        self.update_notifier.notify_waiters();
     }
 
-    pub async fn store_instances(&mut self, found_instances: Vec<ProductionOrder>) {
-        let mut p = self.found_instances.lock().await;
-        p.clear();
-        p.extend(found_instances);
-        // println!("{:?}", p);
+    ///
+    /// End the session, pending scanners are reported as cancelled
+    ///
+    /// A complete session is compared with the previous one and with the
+    /// orders of the produced instances
+    ///
+    pub async fn finish_session(&mut self, cancelled: bool, produced: Vec<ProductionOrder>) {
+        if !cancelled {
+            self.update_hotplug(produced).await;
+        }
+
+        let mut progress = self.progress.lock().await;
+        progress.cancelled = cancelled;
+        let mut errors = self.scan_errors.lock().await;
+        for scanner in progress.pending.drain(..) {
+            errors.insert(scanner, "Cancelled".to_string());
+        }
+        drop(errors);
+        drop(progress);
+        *self.is_running.lock().await = false;
+        self.progress_notifier.notify_waiters();
         self.update_notifier.notify_waiters();
     }
 
+    ///
+    /// A scanner that failed or timed out says nothing about its devices,
+    /// its results of the previous session are kept instead of reporting them as disappeared
+    ///
+    async fn update_hotplug(&mut self, produced: Vec<ProductionOrder>) {
+        let mut by_scanner = self.session_instances.lock().await.clone();
+        let mut previous = self.previous_instances.lock().await;
+        if let Some(previous_by_scanner) = previous.as_ref() {
+            for scanner in self.scan_errors.lock().await.keys() {
+                if let Some(orders) = previous_by_scanner.get(scanner) {
+                    by_scanner.insert(scanner.clone(), orders.clone());
+                }
+            }
+        }
+        let found: Vec<ProductionOrder> = by_scanner.values().flatten().cloned().collect();
+
+        //
+        // The first session is only the reference of the next ones
+        let previous_found: Vec<ProductionOrder> = match previous.as_ref() {
+            Some(previous_by_scanner) => previous_by_scanner.values().flatten().cloned().collect(),
+            None => found.clone(),
+        };
+        let disappeared: Vec<ProductionOrder> = previous_found
+            .iter()
+            .filter(|po| !found.iter().any(|f| same_device(f, po)))
+            .cloned()
+            .collect();
+
+        let diff = HotplugDiff {
+            appeared: found
+                .iter()
+                .filter(|po| !previous_found.iter().any(|p| same_device(p, po)))
+                .cloned()
+                .collect(),
+            not_produced: found
+                .iter()
+                .filter(|po| !produced.iter().any(|p| same_unit(po, p)))
+                .cloned()
+                .collect(),
+            missing: produced
+                .iter()
+                .filter(|p| {
+                    previous_found.iter().any(|d| same_unit(d, p))
+                        && !found.iter().any(|f| same_unit(f, p))
+                })
+                .map(|p| p.name.clone())
+                .collect(),
+            disappeared: disappeared,
+        };
+
+        *previous = Some(by_scanner);
+        *self.hotplug.lock().await = diff;
+        self.hotplug_notifier.notify_waiters();
+    }
+
+    ///
+    /// Orders found by the last complete session, None if no session completed
+    ///
+    pub async fn last_found_instances(&self) -> Option<Vec<ProductionOrder>> {
+        self.previous_instances
+            .lock()
+            .await
+            .as_ref()
+            .map(|by_scanner| by_scanner.values().flatten().cloned().collect())
+    }
+
+    ///
+    ///
+    ///
+    pub async fn hotplug_into_json_value(&self) -> Result<JsonValue, Error> {
+        let hotplug = self.hotplug.lock().await;
+        serde_json::to_value(&*hotplug).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+
+    ///
+    ///
+    ///
+    pub async fn progress_into_json_value(&self) -> Result<JsonValue, Error> {
+        let progress = self.progress.lock().await;
+        serde_json::to_value(&*progress).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+
+    ///
+    /// Get the orders of the last scan that match the selection
+    ///
+    pub async fn select(&self, selection: &ScanSelection) -> Result<Vec<ProductionOrder>, Error> {
+        let found = self.found_instances.lock().await;
+        match selection {
+            ScanSelection::All(all) => {
+                if all != "all" {
+                    return Err(Error::InvalidArgument(format!(
+                        "Unknown selection '{}'",
+                        all
+                    )));
+                }
+                Ok(found.clone())
+            }
+            ScanSelection::Indexes(indexes) => indexes
+                .iter()
+                .map(|i| {
+                    found.get(*i).cloned().ok_or(Error::InvalidArgument(format!(
+                        "No scanned instance at index {}",
+                        i
+                    )))
+                })
+                .collect(),
+            ScanSelection::Names(names) => {
+                names
+                    .iter()
+                    .map(|name| {
+                        found.iter().find(|po| &po.name == name).cloned().ok_or(
+                            Error::InvalidArgument(format!("No scanned instance named '{}'", name)),
+                        )
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    ///
+    /// Ask the platform to produce those orders
+    ///
+    pub async fn request_instantiation(&mut self, orders: Vec<ProductionOrder>) {
+        self.instantiate_requests.lock().await.extend(orders);
+        self.instantiate_notifier.notify_one();
+    }
+
+    ///
+    /// Take the orders waiting to be produced
+    ///
+    pub async fn take_instantiation_requests(&self) -> Vec<ProductionOrder> {
+        std::mem::take(&mut *self.instantiate_requests.lock().await)
+    }
+
     // ///
     // ///
     // ///
@@ -78,7 +421,148 @@ impl ScannerDriver {
     ///
     pub async fn into_json_value(&self) -> Result<JsonValue, Error> {
         let p = self.found_instances.lock().await;
-        let v = serde_json::to_value(&*p).unwrap();
-        Ok(v)
+        let e = self.scan_errors.lock().await;
+        Ok(json!({
+            "instances": &*p,
+            "errors": &*e
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(name: &str, port: &str) -> ProductionOrder {
+        serde_json::from_value(
+            json!({ "name": name, "dref": "manuf.model", "settings": { "port": port } }),
+        )
+        .unwrap()
+    }
+
+    async fn driver_with(found: Vec<ProductionOrder>) -> ScannerDriver {
+        let mut driver = ScannerDriver::new();
+        driver.start_session(vec!["scanner".to_string()]).await;
+        driver.join_scan("scanner".to_string(), Ok(found)).await;
+        driver
+    }
+
+    #[tokio::test]
+    async fn select_orders_of_the_last_scan() {
+        let driver = driver_with(vec![order("a", "1"), order("b", "2")]).await;
+
+        let all: ScanSelection = serde_json::from_value(json!("all")).unwrap();
+        assert_eq!(driver.select(&all).await.unwrap().len(), 2);
+
+        let indexes: ScanSelection = serde_json::from_value(json!([1])).unwrap();
+        assert_eq!(driver.select(&indexes).await.unwrap()[0].name, "b");
+
+        let names: ScanSelection = serde_json::from_value(json!(["a"])).unwrap();
+        assert_eq!(driver.select(&names).await.unwrap()[0].name, "a");
+    }
+
+    #[tokio::test]
+    async fn select_refuses_unknown_orders() {
+        let driver = driver_with(vec![order("a", "1")]).await;
+        for selection in [json!("some"), json!([3]), json!(["b"])] {
+            let selection: ScanSelection = serde_json::from_value(selection).unwrap();
+            assert!(driver.select(&selection).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn session_progress_and_cancelled_scanners() {
+        let mut driver = ScannerDriver::new();
+        driver
+            .start_session(vec!["fast".to_string(), "slow".to_string()])
+            .await;
+        assert!(driver.is_already_running().await);
+        driver
+            .join_scan("fast".to_string(), Ok(vec![order("a", "1")]))
+            .await;
+
+        let progress = driver.progress_into_json_value().await.unwrap();
+        assert_eq!(progress["total_scan"], 2);
+        assert_eq!(progress["joined_scan"], 1);
+        assert_eq!(progress["pending"], json!(["slow"]));
+
+        driver.finish_session(true, Vec::new()).await;
+        assert!(!driver.is_already_running().await);
+        let results = driver.into_json_value().await.unwrap();
+        assert_eq!(results["errors"]["slow"], "Cancelled");
+        assert_eq!(results["instances"][0]["name"], "a");
+
+        //
+        // A cancelled session is not a reference for the hotplug diff
+        assert!(driver.last_found_instances().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn hotplug_finds_produced_units_by_their_identity() {
+        let scanned = |port: &str| -> ProductionOrder {
+            serde_json::from_value(json!({
+                "name": "scanned",
+                "dref": "manuf.model",
+                "settings": { "port": port, "usb": { "serial_number": "A1" } }
+            }))
+            .unwrap()
+        };
+        //
+        // The user changed a setting of the produced instance
+        let produced: ProductionOrder = serde_json::from_value(json!({
+            "name": "psu",
+            "dref": "manuf.model",
+            "settings": { "port": "a", "usb": { "serial_number": "A1" }, "baudrate": 9600 }
+        }))
+        .unwrap();
+
+        let mut driver = ScannerDriver::new();
+        for found in [vec![scanned("a")], vec![scanned("b")]] {
+            driver.start_session(vec!["scanner".to_string()]).await;
+            driver.join_scan("scanner".to_string(), Ok(found)).await;
+            driver.finish_session(false, vec![produced.clone()]).await;
+        }
+        let hotplug = driver.hotplug_into_json_value().await.unwrap();
+        assert_eq!(hotplug["missing"], json!([]));
+        assert_eq!(hotplug["not_produced"], json!([]));
+
+        driver.start_session(vec!["scanner".to_string()]).await;
+        driver
+            .join_scan("scanner".to_string(), Ok(Vec::new()))
+            .await;
+        driver.finish_session(false, vec![produced.clone()]).await;
+        let hotplug = driver.hotplug_into_json_value().await.unwrap();
+        assert_eq!(hotplug["missing"], json!(["psu"]));
+    }
+
+    #[tokio::test]
+    async fn hotplug_keeps_the_results_of_failed_scanners() {
+        let mut driver = ScannerDriver::new();
+        driver.start_session(vec!["scanner".to_string()]).await;
+        driver
+            .join_scan("scanner".to_string(), Ok(vec![order("a", "1")]))
+            .await;
+        driver.finish_session(false, Vec::new()).await;
+
+        driver.start_session(vec!["scanner".to_string()]).await;
+        driver
+            .join_scan("scanner".to_string(), Err(Error::Generic("timeout".into())))
+            .await;
+        driver.finish_session(false, Vec::new()).await;
+        let hotplug = driver.hotplug_into_json_value().await.unwrap();
+        assert_eq!(hotplug["disappeared"], json!([]));
+        assert_eq!(driver.last_found_instances().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_made_while_the_processor_is_busy_is_kept() {
+        let mut driver = ScannerDriver::new();
+        driver.request_scanning_start().await;
+        tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            driver.request_notifier.notified(),
+        )
+        .await
+        .unwrap();
     }
 }
@@ -33,10 +33,10 @@ impl SharedStore {
     }
 
     ///
-    ///
+    /// Replace the store, plugins can be loaded and unloaded at runtime
     ///
     pub async fn set_stores(&mut self, store: Store) {
-        self.store.lock().await.extend_by_copy(&store);
+        *self.store.lock().await = store;
         self.change_notifier.notify_waiters();
     }
 
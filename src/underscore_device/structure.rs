@@ -2,7 +2,7 @@ pub mod attribute;
 pub mod class;
 pub mod instance;
 
-use instance::{Alert, InstanceElement};
+use instance::{Alert, InstanceElement, ProductionRecord};
 use panduza_platform_core::{instance::State, log_trace, Container, Error, Instance};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -33,6 +33,13 @@ impl Structure {
         self.driver_instances.insert(name, instance);
     }
 
+    ///
+    /// Remove an instance, return true if it was present
+    ///
+    pub fn remove_instance(&mut self, name: &String) -> bool {
+        self.driver_instances.remove(name).is_some()
+    }
+
     // pub fn get_
 
     ///
@@ -52,10 +59,17 @@ impl Structure {
     ///
     ///
     ///
-    pub fn pack_instance_status(&self) -> Vec<(String, State, Vec<Alert>)> {
+    pub fn pack_instance_status(
+        &self,
+    ) -> Vec<(String, State, Vec<Alert>, Option<ProductionRecord>)> {
         let mut r = Vec::new();
         for (_key, value) in (&self.driver_instances).into_iter() {
-            r.push((_key.clone(), value.state.clone(), value.alerts.clone()));
+            r.push((
+                _key.clone(),
+                value.state.clone(),
+                value.alerts.clone(),
+                value.production.clone(),
+            ));
         }
         r
     }
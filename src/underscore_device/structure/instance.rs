@@ -4,12 +4,29 @@ use std::collections::HashMap;
 
 use super::{attribute::AttributElement, class::ClassElement};
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+///
+/// Number of alerts kept on an instance
+///
+static MAX_ALERTS: usize = 32;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Alert {
     topic: String,
     message: String,
 }
 
+impl Alert {
+    ///
+    /// Alert raised by the platform itself
+    ///
+    pub fn new<T: Into<String>, M: Into<String>>(topic: T, message: M) -> Self {
+        Self {
+            topic: topic.into(),
+            message: message.into(),
+        }
+    }
+}
+
 impl From<AlertNotification> for Alert {
     fn from(value: AlertNotification) -> Self {
         Self {
@@ -19,6 +36,61 @@ impl From<AlertNotification> for Alert {
     }
 }
 
+///
+/// Result of a production order
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum ProductionOutcome {
+    /// The order has been given to its producer
+    Ok,
+    /// No producer provides the driver reference
+    UnknownDriver,
+    /// The settings of the order are refused
+    InvalidSettings(String),
+    /// The producer failed to build the instance
+    DriverError(String),
+    /// The emergency stop is engaged, nothing is produced until the re-arm
+    EmergencyStop,
+    /// A dependency of the device has been abandoned, it waits for its return
+    Blocked(String),
+}
+
+impl ProductionOutcome {
+    ///
+    ///
+    ///
+    pub fn is_ok(&self) -> bool {
+        *self == ProductionOutcome::Ok
+    }
+}
+
+///
+/// Production of an instance at a given time
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductionRecord {
+    pub time: String,
+    pub instance: String,
+    pub dref: String,
+    #[serde(flatten)]
+    pub outcome: ProductionOutcome,
+}
+
+impl ProductionRecord {
+    ///
+    /// Record of a production that just happened
+    ///
+    pub fn now(instance: String, dref: String, outcome: ProductionOutcome) -> Self {
+        Self {
+            time: chrono::Local::now().to_rfc3339(),
+            instance: instance,
+            dref: dref,
+            outcome: outcome,
+        }
+    }
+}
+
 ///
 /// Represent an instance in the structure
 ///
@@ -36,6 +108,12 @@ pub struct InstanceElement {
     #[serde(skip)]
     pub alerts: Vec<Alert>,
 
+    ///
+    /// Last production of the instance
+    ///
+    #[serde(skip)]
+    pub production: Option<ProductionRecord>,
+
     ///
     /// Sub classes
     ///
@@ -61,11 +139,34 @@ impl InstanceElement {
     }
 
     ///
+    /// Define the last production
     ///
+    pub fn set_production(&mut self, record: ProductionRecord) {
+        self.production = Some(record);
+    }
+
+    ///
+    /// Add an alert beside the others, an alert already raised is not repeated
+    ///
+    /// The oldest alerts are dropped beyond MAX_ALERTS.
     ///
     pub fn add_alert(&mut self, alert: Alert) {
-        self.alerts.clear();
+        if self.alerts.contains(&alert) {
+            return;
+        }
         self.alerts.push(alert);
+        if self.alerts.len() > MAX_ALERTS {
+            self.alerts.remove(0);
+        }
+    }
+
+    ///
+    /// Remove this alert only, the alerts of the driver are kept
+    ///
+    pub fn remove_alert(&mut self, alert: &Alert) -> bool {
+        let count = self.alerts.len();
+        self.alerts.retain(|a| a != alert);
+        count != self.alerts.len()
     }
 
     ///
@@ -105,3 +206,64 @@ impl InstanceElement {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_are_added_once_beside_the_others() {
+        let mut instance = InstanceElement::default();
+        instance.add_alert(Alert::new("pza/psu", "from the driver"));
+        instance.add_alert(Alert::new("pza/psu", "from the platform"));
+        instance.add_alert(Alert::new("pza/psu", "from the driver"));
+        assert_eq!(
+            instance.alerts,
+            vec![
+                Alert::new("pza/psu", "from the driver"),
+                Alert::new("pza/psu", "from the platform"),
+            ]
+        );
+
+        assert!(instance.remove_alert(&Alert::new("pza/psu", "from the platform")));
+        assert!(!instance.remove_alert(&Alert::new("pza/psu", "from the platform")));
+        assert_eq!(
+            instance.alerts,
+            vec![Alert::new("pza/psu", "from the driver")]
+        );
+    }
+
+    #[test]
+    fn oldest_alerts_are_dropped() {
+        let mut instance = InstanceElement::default();
+        for i in 0..MAX_ALERTS + 2 {
+            instance.add_alert(Alert::new("pza/psu", format!("alert {}", i)));
+        }
+        assert_eq!(instance.alerts.len(), MAX_ALERTS);
+        assert_eq!(instance.alerts[0], Alert::new("pza/psu", "alert 2"));
+    }
+
+    #[test]
+    fn production_records_carry_their_outcome_and_reason() {
+        let record = ProductionRecord::now(
+            "psu".to_string(),
+            "manuf.model".to_string(),
+            ProductionOutcome::InvalidSettings("missing 'port'".to_string()),
+        );
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["instance"], "psu");
+        assert_eq!(value["dref"], "manuf.model");
+        assert_eq!(value["outcome"], "invalid_settings");
+        assert_eq!(value["reason"], "missing 'port'");
+
+        let record = ProductionRecord::now(
+            "psu".to_string(),
+            "manuf.model".to_string(),
+            ProductionOutcome::UnknownDriver,
+        );
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["outcome"], "unknown_driver");
+        assert!(value.get("reason").is_none());
+        assert!(!record.outcome.is_ok());
+    }
+}
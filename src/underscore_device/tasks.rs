@@ -0,0 +1,111 @@
+pub mod data;
+
+use data::TasksDriver;
+use panduza_platform_core::{log_debug, log_warn, Container, JsonAttServer, Logger};
+use panduza_platform_core::{spawn_on_command, Error, Instance};
+
+///
+/// Mount the task table of the platform
+///
+/// platform -> tasks spawned by the platform and its instances
+///      - tasks json, by task name
+///        {
+///            "psu_1/fsm": {
+///                "instance": "psu_1",
+///                "state": "running" | "completed" | "failed" | "cancelled",
+///                "start_time": "...",
+///                "end_time": "..." | null,
+///                "policy": "never" | "on_failure" | "always", (always "never" for instance tasks)
+///                "failures": 0,
+///                "consecutive_failures": 0,
+///                "restarts": 0,
+///                "last_error": null
+///            }
+///        }
+///      - abort_task json command with the name of the task to abort, it is not restarted
+///
+/// A task that waits forever looks the same as a task that waits for its next event,
+/// both are "running": the platform only sees when a task ends, not its progress.
+/// Only the last 64 finished tasks that are not restarted stay in the table.
+///
+pub async fn mount(mut instance: Instance, driver: TasksDriver) -> Result<(), Error> {
+    //
+    // Create the attributes
+    let mut class_platform = instance.create_class("platform").finish().await;
+
+    let att_tasks = class_platform
+        .create_attribute("tasks")
+        .with_ro()
+        .finish_as_json()
+        .await?;
+    att_tasks.set(driver.into_json_value().await?).await?;
+
+    let att_abort = class_platform
+        .create_attribute("abort_task")
+        .with_wo()
+        .finish_as_json()
+        .await?;
+
+    //
+    //
+    let tasks_have_changed = driver.change_notifier.clone();
+
+    //
+    //
+    let driver_2 = driver.clone();
+    instance
+        .spawn("tasks_watcher", async move {
+            //
+            loop {
+                //
+                // Wait for table change
+                tasks_have_changed.notified().await;
+
+                let value = driver_2.into_json_value().await?;
+                att_tasks.set(value).await?;
+            }
+        })
+        .await;
+
+    //
+    // Execute action on each command received
+    let logger_2 = instance.logger.clone();
+    let att_abort_2 = att_abort.clone();
+    spawn_on_command!(
+        "on_command => _/platform/abort_task",
+        instance,
+        att_abort_2,
+        on_command(logger_2.clone(), att_abort_2.clone(), driver.clone())
+    );
+
+    //
+    //
+    Ok(())
+}
+
+///
+///
+///
+async fn on_command(
+    logger: Logger,
+    mut att_abort: JsonAttServer,
+    mut driver: TasksDriver,
+) -> Result<(), Error> {
+    while let Some(command) = att_abort.pop_cmd().await {
+        //
+        // Log
+        log_debug!(logger, "Abort task command received '{:?}'", command);
+
+        //
+        // A bad command must not stop the attribute
+        match serde_json::from_value::<String>(command) {
+            Ok(name) => {
+                driver.request_abort(name).await;
+            }
+            Err(e) => {
+                log_warn!(logger, "Invalid abort task command: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
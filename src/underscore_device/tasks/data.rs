@@ -0,0 +1,115 @@
+use crate::supervisor::TaskStats;
+use panduza_platform_core::Error;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+
+#[derive(Clone)]
+///
+///
+///
+pub struct TasksDriver {
+    ///
+    /// When user requested the abort of a task
+    ///
+    pub request_notifier: Arc<Notify>,
+
+    ///
+    /// When the task table changed
+    ///
+    pub change_notifier: Arc<Notify>,
+
+    ///
+    /// Names of the tasks to abort
+    ///
+    requests: Arc<Mutex<Vec<String>>>,
+
+    ///
+    /// Task table, by task name
+    ///
+    tasks: Arc<Mutex<BTreeMap<String, TaskStats>>>,
+}
+
+impl TasksDriver {
+    ///
+    ///
+    ///
+    pub fn new() -> Self {
+        Self {
+            request_notifier: Arc::new(Notify::new()),
+            change_notifier: Arc::new(Notify::new()),
+            requests: Arc::new(Mutex::new(Vec::new())),
+            tasks: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    ///
+    /// Queue the abort of a task for the platform
+    ///
+    pub async fn request_abort(&mut self, name: String) {
+        self.requests.lock().await.push(name);
+        //
+        // The processor may be busy, keep the request for it
+        self.request_notifier.notify_one();
+    }
+
+    ///
+    /// Names requested since the last call
+    ///
+    pub async fn take_requests(&self) -> Vec<String> {
+        std::mem::take(&mut *self.requests.lock().await)
+    }
+
+    ///
+    /// Publish the task table of the platform
+    ///
+    pub async fn set_tasks(&mut self, tasks: BTreeMap<String, TaskStats>) {
+        *self.tasks.lock().await = tasks;
+        self.change_notifier.notify_waiters();
+    }
+
+    ///
+    ///
+    ///
+    pub async fn into_json_value(&self) -> Result<JsonValue, Error> {
+        let tasks = self.tasks.lock().await;
+        serde_json::to_value(&*tasks).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supervisor::RestartPolicy;
+
+    #[tokio::test]
+    async fn task_table_and_abort_requests() {
+        let mut driver = TasksDriver::new();
+        let mut stats = TaskStats::default();
+        stats.record_start("psu/poll", &RestartPolicy::Never);
+        stats.record_end(Some("no device".to_string()));
+        driver
+            .set_tasks(BTreeMap::from([("psu/poll".to_string(), stats)]))
+            .await;
+
+        let value = driver.into_json_value().await.unwrap();
+        assert_eq!(value["psu/poll"]["instance"], "psu");
+        assert_eq!(value["psu/poll"]["state"], "failed");
+        assert_eq!(value["psu/poll"]["last_error"], "no device");
+        assert!(value["psu/poll"].get("started").is_none());
+
+        //
+        // Notified even if the processor was not waiting yet
+        driver.request_abort("psu/poll".to_string()).await;
+        tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            driver.request_notifier.notified(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(driver.take_requests().await, vec!["psu/poll".to_string()]);
+        assert!(driver.take_requests().await.is_empty());
+    }
+}
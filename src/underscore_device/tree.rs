@@ -0,0 +1,81 @@
+pub mod data;
+
+use data::{TreeCommand, TreeDriver};
+use panduza_platform_core::{log_debug, log_warn, Container, JsonAttServer, Logger};
+use panduza_platform_core::{spawn_loop, spawn_on_command, Error, Instance};
+
+///
+/// Mount the tree attribute
+///
+/// json with the device tree loaded by the platform, commands modify it:
+/// { "add": { production order } }
+/// { "update": { production order } }
+/// { "remove": "instance name" }
+///
+pub async fn mount(mut instance: Instance, driver: TreeDriver) -> Result<(), Error> {
+    //
+    // Create the attribute
+    let att_tree = instance
+        .create_attribute("tree")
+        .with_rw()
+        .finish_as_json()
+        .await?;
+    att_tree.set(driver.into_json_value().await?).await?;
+
+    //
+    //
+    let driver_2 = driver.clone();
+    let att_tree_2 = att_tree.clone();
+    spawn_loop!("loop => _/tree", instance, {
+        driver_2.update_notifier.notified().await;
+        let value = driver_2.into_json_value().await?;
+
+        att_tree_2.set(value).await?;
+    });
+
+    //
+    // Execute action on each command received
+    let logger_2 = instance.logger.clone();
+    let att_tree_3 = att_tree.clone();
+    spawn_on_command!(
+        "on_command => _/tree",
+        instance,
+        att_tree_3,
+        on_command(logger_2.clone(), att_tree_3.clone(), driver.clone())
+    );
+
+    //
+    //
+    Ok(())
+}
+
+///
+///
+///
+async fn on_command(
+    logger: Logger,
+    mut att_tree: JsonAttServer,
+    mut driver: TreeDriver,
+) -> Result<(), Error> {
+    while let Some(command) = att_tree.pop_cmd().await {
+        //
+        // Log
+        log_debug!(logger, "Tree command received '{:?}'", command);
+
+        //
+        // A bad command must not stop the attribute
+        let result = serde_json::from_value::<TreeCommand>(command)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid tree command: {:?}", e)));
+        match result {
+            Ok(tree_command) => {
+                if let Err(e) = driver.apply_command(tree_command).await {
+                    log_warn!(logger, "Tree command rejected: {:?}", e);
+                }
+            }
+            Err(e) => {
+                log_warn!(logger, "{:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use crate::device_tree::{default_tree_file, DeviceEntry, DeviceTree};
+use panduza_platform_core::Error;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+
+///
+/// Modifications of the device tree that the user can request
+///
+/// { "add": { "name": "psu", "dref": "manuf.model", "settings": {}, "identity": {} } }
+/// { "update": { "name": "psu", "dref": "manuf.model", "settings": {}, "identity": {} } }
+/// { "remove": "psu" }
+///
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeCommand {
+    Add(DeviceEntry),
+    Update(DeviceEntry),
+    Remove(String),
+}
+
+#[derive(Clone)]
+///
+///
+///
+pub struct TreeDriver {
+    ///
+    /// When user changed the tree
+    ///
+    pub request_notifier: Arc<Notify>,
+
+    ///
+    /// When the platform loaded a new tree
+    ///
+    pub update_notifier: Arc<Notify>,
+
+    ///
+    /// Last tree loaded by the platform
+    ///
+    tree: Arc<Mutex<DeviceTree>>,
+}
+
+impl TreeDriver {
+    ///
+    ///
+    ///
+    pub fn new() -> Self {
+        Self {
+            request_notifier: Arc::new(Notify::new()),
+            update_notifier: Arc::new(Notify::new()),
+            tree: Arc::new(Mutex::new(DeviceTree::default())),
+        }
+    }
+
+    ///
+    /// Replace the tree after a load from the platform
+    ///
+    pub async fn set_tree(&mut self, tree: DeviceTree) {
+        *self.tree.lock().await = tree;
+        self.update_notifier.notify_waiters();
+    }
+
+    ///
+    /// Apply the user command and persist the result in the tree file
+    ///
+    /// Only the devices declared in the main tree file can be modified, the ones from
+    /// included files and profiles must be edited in their own file. An update only
+    /// writes the fields it changes, templates and variables of the entry are kept.
+    /// A tree file with comments is refused, they would be lost.
+    ///
+    pub async fn apply_command(&mut self, command: TreeCommand) -> Result<(), Error> {
+        let tree = self.tree.lock().await;
+        let tree_path = default_tree_file()?;
+
+        //
+        // Name of the device targeted by the command
+        let name = match &command {
+            TreeCommand::Add(entry) | TreeCommand::Update(entry) => entry.order.name.clone(),
+            TreeCommand::Remove(name) => name.clone(),
+        };
+        let existing = tree.devices.iter().find(|d| d.order.name == name);
+        match (&command, existing) {
+            (TreeCommand::Add(_), Some(_)) => {
+                return Err(Error::InvalidArgument(format!(
+                    "Device '{}' already exists",
+                    name
+                )));
+            }
+            (TreeCommand::Update(_) | TreeCommand::Remove(_), None) => {
+                return Err(Error::InvalidArgument(format!(
+                    "Device '{}' does not exist",
+                    name
+                )));
+            }
+            (_, Some(entry)) if entry.source.as_ref() != Some(&tree_path) => {
+                return Err(Error::InvalidArgument(format!(
+                    "Device '{}' is declared in {:?}, edit it there",
+                    name,
+                    entry.source.clone().unwrap_or_default()
+                )));
+            }
+            _ => {}
+        }
+
+        //
+        // Resolved entry, to find the fields changed by an update
+        let resolved = existing.map(entry_to_json).transpose()?;
+
+        //
+        // The file is the reference, the platform will load it again. The loaded tree is
+        // not updated yet, so the file content is checked too.
+        DeviceTree::edit_file_devices(&tree_path, |devices| {
+            let position = devices
+                .iter()
+                .position(|d| d.get("name").and_then(|n| n.as_str()) == Some(name.as_str()));
+            match (command, position) {
+                (TreeCommand::Add(_), Some(_)) => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Device '{}' already exists in {:?}",
+                        name, tree_path
+                    )));
+                }
+                (TreeCommand::Add(entry), None) => devices.push(entry_to_json(&entry)?),
+                (TreeCommand::Update(entry), Some(index)) => write_changes(
+                    &mut devices[index],
+                    &resolved.unwrap_or_default(),
+                    &entry_to_json(&entry)?,
+                ),
+                (TreeCommand::Remove(_), Some(index)) => {
+                    devices.remove(index);
+                }
+                (_, None) => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Device '{}' is not declared in the devices of {:?}",
+                        name, tree_path
+                    )));
+                }
+            }
+            Ok(())
+        })?;
+        drop(tree);
+
+        self.request_notifier.notify_waiters();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    pub async fn into_json_value(&self) -> Result<JsonValue, Error> {
+        let tree = self.tree.lock().await;
+        serde_json::to_value(&*tree).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+    }
+}
+
+///
+/// Write in the entry of the file only what differs between the resolved entry and the
+/// new one
+///
+/// The fields given by a template or a variable stay as written when they are not changed.
+///
+fn write_changes(written: &mut JsonValue, resolved: &JsonValue, new: &JsonValue) {
+    if resolved == new {
+        return;
+    }
+    match (
+        written.as_object_mut(),
+        resolved.as_object(),
+        new.as_object(),
+    ) {
+        (Some(written), Some(resolved), Some(new)) => {
+            for (key, value) in new.iter() {
+                match (written.get_mut(key), resolved.get(key)) {
+                    (Some(written_value), Some(resolved_value)) => {
+                        write_changes(written_value, resolved_value, value)
+                    }
+                    _ if resolved.get(key) == Some(value) => {}
+                    _ => {
+                        written.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            for key in resolved.keys() {
+                if !new.contains_key(key) {
+                    written.remove(key);
+                }
+            }
+        }
+        _ => *written = new.clone(),
+    }
+}
+
+///
+/// Device entry as written in the tree file
+///
+fn entry_to_json(entry: &DeviceEntry) -> Result<JsonValue, Error> {
+    serde_json::to_value(entry).map_err(|e| Error::SerializeFailure(format!("{:?}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn commands_name_their_device() {
+        let command: TreeCommand = serde_json::from_value(
+            json!({ "add": { "name": "psu", "dref": "manuf.model", "settings": { "port": "a" } } }),
+        )
+        .unwrap();
+        match command {
+            TreeCommand::Add(entry) => assert_eq!(entry.order.name, "psu"),
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        let command: TreeCommand = serde_json::from_value(json!({ "remove": "psu" })).unwrap();
+        assert!(matches!(command, TreeCommand::Remove(name) if name == "psu"));
+
+        assert!(serde_json::from_value::<TreeCommand>(json!({ "rename": "psu" })).is_err());
+    }
+
+    #[test]
+    fn entries_are_written_without_empty_fields() {
+        let entry: DeviceEntry = serde_json::from_value(
+            json!({ "name": "psu", "dref": "manuf.model", "settings": { "port": "a" } }),
+        )
+        .unwrap();
+        let value = entry_to_json(&entry).unwrap();
+        assert_eq!(value["name"], "psu");
+        assert_eq!(value["settings"], json!({ "port": "a" }));
+        assert!(value.get("identity").is_none());
+        assert!(value.get("depends_on").is_none());
+    }
+}